@@ -1,27 +1,96 @@
 use core::str;
-use std::{collections::HashMap, fmt::Display};
+use std::{collections::HashMap, fmt::Display, net::IpAddr, str::FromStr};
 
-#[derive(Debug, PartialEq, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Clone, Copy)]
 pub enum Method {
     Get,
+    Head,
     Post,
+    Options,
     Uninitialized
 }
 
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Version {
     V1_1,
+    V2,
     Uninitialized
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct HttpRequest {
     pub version: Version,
     pub method: Method,
     pub resource: String,
     pub header: HashMap<String, String>,    
     pub path_params: HashMap<String, String>,
-    pub body: Vec<u8>
+    pub body: Vec<u8>,
+    /// The certificate the client authenticated with over mutual TLS
+    pub peer_certificate: Option<PeerCertificate>,
+}
+
+/// A client certificate that was verified against the CA bundle of the server
+#[derive(Debug, PartialEq, Clone)]
+pub struct PeerCertificate {
+    /// The distinguished name, e.g. `CN=billing, O=Example`
+    pub subject: String,
+    pub subject_alt_names: Vec<SubjectAltName>,
+    /// The SHA-256 of the DER certificate, in lowercase hex
+    pub fingerprint: String,
+    pub der: Vec<u8>,
+}
+
+/// The names of a certificate that are used to authorize a client, the other
+/// kinds are left out.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum SubjectAltName {
+    Dns(String),
+    Uri(String),
+    Email(String),
+    Ip(IpAddr),
+}
+
+/// Error returned by [`HttpRequest::param`] when a path parameter can't be
+/// read as the requested type.
+#[derive(Debug, PartialEq)]
+pub enum ParamError {
+    Missing(String),
+    Invalid {
+        name: String,
+        value: String,
+        expected: &'static str,
+        reason: String,
+    },
+}
+
+impl Display for ParamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing(name) => write!(f, "missing path parameter `{}`", name),
+            Self::Invalid { name, value, expected, reason } => write!(
+                f,
+                "path parameter `{}` has value `{}` which is not a valid {}: {}",
+                name, value, expected, reason
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParamError {}
+
+/// An empty request, e.g. to stand in for one that couldn't be parsed.
+impl Default for HttpRequest {
+    fn default() -> Self {
+        HttpRequest {
+            version: Version::Uninitialized,
+            method: Method::Uninitialized,
+            resource: String::new(),
+            header: HashMap::new(),
+            path_params: HashMap::new(),
+            body: Vec::new(),
+            peer_certificate: None,
+        }
+    }
 }
 
 impl HttpRequest {
@@ -29,11 +98,56 @@ impl HttpRequest {
         self.path_params = path_params.clone();
     }
 
+    /// The path of the resource, without the query string.
+    pub fn path(&self) -> &str {
+        match self.resource.split_once('?') {
+            Some((path, _)) => path,
+            None => &self.resource,
+        }
+    }
+
+    /// The query string of the resource, without the leading `?`.
+    pub fn query(&self) -> Option<&str> {
+        self.resource.split_once('?').map(|(_, query)| query)
+    }
+
+    /// Looks up a header by name, ignoring case.
+    pub fn header_value(&self, name: &str) -> Option<&str> {
+        self.header.iter()
+                   .find(|(key, _)| key.eq_ignore_ascii_case(name))
+                   .map(|(_, value)| value.as_str())
+    }
+
+    /// Reads the path parameter `name` and parses it as `T`.
+    pub fn param<T>(&self, name: &str) -> Result<T, ParamError>
+    where
+        T: FromStr,
+        T::Err: Display,
+    {
+        let value = self.path_params
+                        .get(name)
+                        .ok_or_else(|| ParamError::Missing(name.to_owned()))?;
+        value.parse::<T>().map_err(|e| ParamError::Invalid {
+            name: name.to_owned(),
+            value: value.to_owned(),
+            expected: std::any::type_name::<T>(),
+            reason: e.to_string(),
+        })
+    }
+
+    /// Whether the client wants to send more requests on the same connection:
+    /// the default of HTTP/1.1 unless it sends `Connection: close`.
+    pub fn keep_alive(&self) -> bool {
+        let close = self.header_value("Connection")
+                        .is_some_and(|value| value.eq_ignore_ascii_case("close"));
+        self.version == Version::V1_1 && !close
+    }
+
     pub fn parse(raw_request: Vec<u8>) -> Option<HttpRequest> {
         let end_of_header = raw_request.windows(4)
                                               .position(|window| window == b"\r\n\r\n")?;
         let (header_part, body_part) = raw_request.split_at(end_of_header + 4);
-        let header_part = str::from_utf8(&header_part).ok()?;
+        let header_part = str::from_utf8(header_part).ok()?;
         let mut lines = header_part.split("\r\n");
         
         // first line
@@ -62,6 +176,7 @@ impl HttpRequest {
             header: headers,
             path_params: HashMap::new(),
             body: body_part.to_vec(),
+            peer_certificate: None,
         })
     }        
 }
@@ -70,6 +185,7 @@ impl From<&str> for Version {
     fn from(s: &str) -> Self {
         match s {
             "HTTP/1.1" => Version::V1_1,
+            "HTTP/2.0" => Version::V2,
             _ => Version::Uninitialized
         }
     }
@@ -79,7 +195,9 @@ impl From<&str> for Method {
     fn from(s: &str) -> Self {
         match s {
             "GET" => Method::Get,
+            "HEAD" => Method::Head,
             "POST" => Method::Post,
+            "OPTIONS" => Method::Options,
             _ => Method::Uninitialized
         }
     }
@@ -89,7 +207,9 @@ impl Display for Method {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let repr = match self {
             Self::Get => "GET",
+            Self::Head => "HEAD",
             Self::Post => "POST",
+            Self::Options => "OPTIONS",
             Self::Uninitialized => "Unknown",
         };
         write!(f, "{}", repr)
@@ -104,7 +224,9 @@ mod tests {
     fn test_method_into_for_method() {
         let cases = vec![
             ("GET", Method::Get),
+            ("HEAD", Method::Head),
             ("POST", Method::Post),
+            ("OPTIONS", Method::Options),
             ("Unkown", Method::Uninitialized),
         ];
 
@@ -164,4 +286,38 @@ mod tests {
         assert_eq!(result.header, expected_headers);
         assert_eq!(result.body, body);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_typed_path_param() {
+        let mut request = HttpRequest::parse(b"GET /users/42 HTTP/1.1\r\n\r\n".to_vec()).unwrap();
+        let mut path_params = HashMap::new();
+        path_params.insert(String::from("id"), String::from("42"));
+        path_params.insert(String::from("name"), String::from("bob"));
+        request.with_path_params(&path_params);
+
+        assert_eq!(request.param::<u64>("id"), Ok(42));
+        assert_eq!(request.param::<String>("name"), Ok(String::from("bob")));
+        assert_eq!(request.param::<u64>("order_id"), Err(ParamError::Missing(String::from("order_id"))));
+
+        let err = request.param::<u64>("name").unwrap_err();
+        assert_eq!(err.to_string(),
+                   "path parameter `name` has value `bob` which is not a valid u64: invalid digit found in string");
+    }
+
+    #[test]
+    fn test_path_query_and_header() {
+        let mut raw_request = Vec::new();
+        raw_request.extend_from_slice(b"GET /search?q=rust&page=2 HTTP/1.1\r\n");
+        raw_request.extend_from_slice(b"Content-Type: application/json\r\n\r\n");
+        let request = HttpRequest::parse(raw_request).unwrap();
+
+        assert_eq!(request.path(), "/search");
+        assert_eq!(request.query(), Some("q=rust&page=2"));
+        assert_eq!(request.header_value("content-type"), Some("application/json"));
+        assert_eq!(request.header_value("Accept"), None);
+
+        let request = HttpRequest::parse(b"GET /search HTTP/1.1\r\n\r\n".to_vec()).unwrap();
+        assert_eq!(request.path(), "/search");
+        assert_eq!(request.query(), None);
+    }
+}
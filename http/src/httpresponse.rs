@@ -1,54 +1,165 @@
 use std::collections::HashMap;
-use std::io::{Write, Result};
+use std::fmt::Display;
+use std::io::{self, Write};
+
+/// Status code of a response, e.g. `StatusCode::NOT_FOUND`.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub struct StatusCode(pub u16);
+
+impl StatusCode {
+    pub const SWITCHING_PROTOCOLS: StatusCode = StatusCode(101);
+    pub const OK: StatusCode = StatusCode(200);
+    pub const CREATED: StatusCode = StatusCode(201);
+    pub const ACCEPTED: StatusCode = StatusCode(202);
+    pub const NO_CONTENT: StatusCode = StatusCode(204);
+    pub const MOVED_PERMANENTLY: StatusCode = StatusCode(301);
+    pub const FOUND: StatusCode = StatusCode(302);
+    pub const SEE_OTHER: StatusCode = StatusCode(303);
+    pub const NOT_MODIFIED: StatusCode = StatusCode(304);
+    pub const BAD_REQUEST: StatusCode = StatusCode(400);
+    pub const UNAUTHORIZED: StatusCode = StatusCode(401);
+    pub const FORBIDDEN: StatusCode = StatusCode(403);
+    pub const NOT_FOUND: StatusCode = StatusCode(404);
+    pub const METHOD_NOT_ALLOWED: StatusCode = StatusCode(405);
+    pub const CONFLICT: StatusCode = StatusCode(409);
+    pub const PAYLOAD_TOO_LARGE: StatusCode = StatusCode(413);
+    pub const UNSUPPORTED_MEDIA_TYPE: StatusCode = StatusCode(415);
+    pub const UNPROCESSABLE_ENTITY: StatusCode = StatusCode(422);
+    pub const UPGRADE_REQUIRED: StatusCode = StatusCode(426);
+    pub const TOO_MANY_REQUESTS: StatusCode = StatusCode(429);
+    pub const REQUEST_HEADER_FIELDS_TOO_LARGE: StatusCode = StatusCode(431);
+    pub const INTERNAL_SERVER_ERROR: StatusCode = StatusCode(500);
+    pub const NOT_IMPLEMENTED: StatusCode = StatusCode(501);
+    pub const BAD_GATEWAY: StatusCode = StatusCode(502);
+    pub const SERVICE_UNAVAILABLE: StatusCode = StatusCode(503);
+
+    pub fn as_u16(&self) -> u16 {
+        self.0
+    }
+
+    /// The reason phrase of the status line, empty for unknown codes
+    pub fn reason(&self) -> &'static str {
+        match self.0 {
+            101 => "Switching Protocols",
+            200 => "OK",
+            201 => "Created",
+            202 => "Accepted",
+            204 => "No Content",
+            301 => "Moved Permanently",
+            302 => "Found",
+            303 => "See Other",
+            304 => "Not Modified",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            403 => "Forbidden",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            409 => "Conflict",
+            413 => "Payload Too Large",
+            415 => "Unsupported Media Type",
+            422 => "Unprocessable Entity",
+            426 => "Upgrade Required",
+            429 => "Too Many Requests",
+            431 => "Request Header Fields Too Large",
+            500 => "Internal Server Error",
+            501 => "Not Implemented",
+            502 => "Bad Gateway",
+            503 => "Service Unavailable",
+            _ => ""
+        }
+    }
+}
+
+impl Display for StatusCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 #[derive(Debug, PartialEq, Clone)]
-pub struct HttpResponse<'a> {
-    pub version: &'a str,
-    pub status_code: &'a str,
-    pub status_text: &'a str,
-    pub headers: Option<HashMap<&'a str, &'a str>>,
+pub struct HttpResponse {
+    pub version: String,
+    pub status_code: String,
+    pub status_text: String,
+    pub headers: Option<HashMap<String, String>>,
     pub body: Option<Vec<u8>>,
 }
 
-impl <'a> Default for HttpResponse<'a> {
+impl Default for HttpResponse {
     fn default() -> Self {
         HttpResponse {
-            version: "HTTP/1.1",
-            status_code: "200",
-            status_text: "OK",
+            version: String::from("HTTP/1.1"),
+            status_code: String::from("200"),
+            status_text: String::from("OK"),
             headers: None,
             body: None
         }
     }
 }
 
-impl<'a> HttpResponse<'a> {
-    pub fn new(status_code: &'a str, 
-               headers: Option<HashMap<&'a str, &'a str>>,
+impl HttpResponse {
+    pub fn new(status_code: &str, 
+               headers: Option<HashMap<&str, &str>>,
                body: Option<Vec<u8>>) -> Self {
-        let mut response = HttpResponse::default();
-        response.status_code = status_code;
-        response.status_text = match response.status_code {
-            "200" => "OK",
-            "400" => "Bad Request",
-            "404" => "Not Found",
-            "500" => "Internal Server Error",
-            _ => ""
+        let status_text = match status_code.parse::<u16>() {
+            Ok(code) => StatusCode(code).reason(),
+            Err(_) => ""
         };
-        response.headers = match headers {
-            Some(_) => headers,
+        let headers = match headers {
+            Some(headers) => Some(headers.into_iter()
+                                         .map(|(key, value)| (key.to_owned(), value.to_owned()))
+                                         .collect()),
             None => {
                 let mut headers = HashMap::new();
-                headers.insert("Content-Type", "text/html");
+                headers.insert(String::from("Content-Type"), String::from("text/html"));
                 Some(headers)
             }
         };
-        response.body = body;
 
-        response
+        HttpResponse {
+            status_code: status_code.to_owned(),
+            status_text: status_text.to_owned(),
+            headers,
+            body,
+            ..HttpResponse::default()
+        }
+    }
+
+    /// A response with the given status and body, without any headers.
+    pub fn with_status(status: StatusCode, body: Option<Vec<u8>>) -> Self {
+        HttpResponse {
+            status_code: status.to_string(),
+            status_text: status.reason().to_owned(),
+            headers: None,
+            body,
+            ..HttpResponse::default()
+        }
+    }
+
+    /// Replaces the status code and the status text.
+    pub fn set_status(&mut self, status: StatusCode) {
+        self.status_code = status.to_string();
+        self.status_text = status.reason().to_owned();
+    }
+
+    /// Sets a header, replacing any previous value.
+    pub fn with_header(mut self, key: &str, value: &str) -> Self {
+        self.headers.get_or_insert_with(HashMap::new)
+                    .insert(key.to_owned(), value.to_owned());
+        self
+    }
+
+    pub fn send_response(&self, write_stream: &mut impl Write) -> io::Result<()> {
+        self.write_response(write_stream, true)
+    }
+
+    /// Sends the response to a HEAD request: the same status line and headers,
+    /// including the `Content-Length` of the body, but not the body itself.
+    pub fn send_head_response(&self, write_stream: &mut impl Write) -> io::Result<()> {
+        self.write_response(write_stream, false)
     }
 
-    pub fn send_response(&self, write_stream: &mut impl Write) -> Result<()> {
+    fn write_response(&self, write_stream: &mut impl Write, include_body: bool) -> io::Result<()> {
         write!(write_stream, 
                "{} {} {}\r\n", 
                self.version, self.status_code, self.status_text)?;
@@ -59,20 +170,164 @@ impl<'a> HttpResponse<'a> {
             }
         }
         
-        if let Some(headers) = &self.headers {
-            if let Some(body) = &self.body {
-                if !headers.contains_key("Content-Length") {
-                    write!(write_stream, "{}: {}\r\n", "Content-Length", body.len())?
-                }    
-            }
+        // the length lets the client find the end of the response on a kept-alive
+        // connection, responses that can't have a body don't get one and
+        // chunked ones say where they end in the body
+        let has_content_length = self.headers.iter().flatten().any(|(key, _)| {
+            key.eq_ignore_ascii_case("Content-Length") || key.eq_ignore_ascii_case("Transfer-Encoding")
+        });
+        let bodiless = self.status_code.starts_with('1') || self.status_code == "204" || self.status_code == "304";
+        if !has_content_length && !bodiless {
+            let content_length = self.body.as_ref().map_or(0, |body| body.len());
+            write!(write_stream, "Content-Length: {}\r\n", content_length)?
         }
         write!(write_stream, "\r\n")?;
 
-        if let Some(body) = &self.body {            
-            write_stream.write_all(body)?;
+        if let Some(body) = &self.body {
+            if include_body && !bodiless {
+                write_stream.write_all(body)?;
+            }
         }
         write_stream.flush()?;
 
         Ok(())
     }
 }
+
+/// Converts the return value of a handler into a response, so handlers can
+/// return e.g. a `String`, `(StatusCode, T)` or `Result<T, E>` and use `?`
+/// with an error type that maps itself to a status code.
+pub trait IntoResponse {
+    fn into_response(self) -> HttpResponse;
+}
+
+impl IntoResponse for HttpResponse {
+    fn into_response(self) -> HttpResponse {
+        self
+    }
+}
+
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::with_status(self, None)
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::with_status(StatusCode::OK, Some(self.into_bytes()))
+            .with_header("Content-Type", "text/plain; charset=utf-8")
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> HttpResponse {
+        self.to_owned().into_response()
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> HttpResponse {
+        HttpResponse::with_status(StatusCode::OK, Some(self))
+            .with_header("Content-Type", "application/octet-stream")
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self) -> HttpResponse {
+        let mut response = self.1.into_response();
+        response.set_status(self.0);
+        response
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> HttpResponse {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(e) => e.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_send_head_response() {
+        let response = HttpResponse::new("200", None, Some(b"Hello World!".to_vec()));
+
+        let mut full = Vec::new();
+        response.send_response(&mut full).unwrap();
+        let mut head = Vec::new();
+        response.send_head_response(&mut head).unwrap();
+
+        let full = String::from_utf8(full).unwrap();
+        let head = String::from_utf8(head).unwrap();
+        assert!(full.ends_with("\r\n\r\nHello World!"));
+        assert!(head.ends_with("\r\n\r\n"));
+        assert!(head.contains("Content-Length: 12\r\n"));
+        assert_eq!(full, format!("{}Hello World!", head));
+    }
+
+    #[test]
+    fn test_content_length() {
+        let mut written = Vec::new();
+        HttpResponse::with_status(StatusCode::NOT_FOUND, None).send_response(&mut written).unwrap();
+        assert_eq!(written, b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n");
+
+        let mut written = Vec::new();
+        HttpResponse::with_status(StatusCode::NO_CONTENT, None).send_response(&mut written).unwrap();
+        assert_eq!(written, b"HTTP/1.1 204 No Content\r\n\r\n");
+
+        let mut written = Vec::new();
+        HttpResponse::with_status(StatusCode::OK, None).with_header("Transfer-Encoding", "chunked")
+                                                       .send_response(&mut written)
+                                                       .unwrap();
+        assert_eq!(written, b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+
+        // header names are case-insensitive
+        let mut written = Vec::new();
+        HttpResponse::with_status(StatusCode::OK, Some(b"abc".to_vec())).with_header("content-length", "3")
+                                                                       .send_response(&mut written)
+                                                                       .unwrap();
+        assert_eq!(written, b"HTTP/1.1 200 OK\r\ncontent-length: 3\r\n\r\nabc");
+
+        // the body of a response that can't have one isn't sent
+        let mut written = Vec::new();
+        HttpResponse::with_status(StatusCode::NOT_MODIFIED, Some(b"stale".to_vec())).send_response(&mut written)
+                                                                                   .unwrap();
+        assert_eq!(written, b"HTTP/1.1 304 Not Modified\r\n\r\n");
+    }
+
+    #[test]
+    fn test_status_text() {
+        assert_eq!(HttpResponse::new("404", None, None).status_text, "Not Found");
+        assert_eq!(HttpResponse::new("418", None, None).status_text, "");
+        assert_eq!(StatusCode::UNPROCESSABLE_ENTITY.reason(), "Unprocessable Entity");
+    }
+
+    #[test]
+    fn test_into_response() {
+        let response = "Hello".into_response();
+        assert_eq!(response.status_code, "200");
+        assert_eq!(response.body, Some(b"Hello".to_vec()));
+        assert_eq!(response.headers.unwrap().get("Content-Type").unwrap(), "text/plain; charset=utf-8");
+
+        let response = (StatusCode::CREATED, String::from("created")).into_response();
+        assert_eq!(response.status_code, "201");
+        assert_eq!(response.status_text, "Created");
+        assert_eq!(response.body, Some(b"created".to_vec()));
+
+        let response = vec![1u8, 2, 3].into_response();
+        assert_eq!(response.headers.unwrap().get("Content-Type").unwrap(), "application/octet-stream");
+
+        let ok: Result<&'static str, StatusCode> = Ok("found");
+        assert_eq!(ok.into_response().status_code, "200");
+        let err: Result<&'static str, (StatusCode, &'static str)> = Err((StatusCode::NOT_FOUND, "no such user"));
+        let response = err.into_response();
+        assert_eq!(response.status_code, "404");
+        assert_eq!(response.body, Some(b"no such user".to_vec()));
+    }
+}
@@ -0,0 +1,427 @@
+use std::error::Error;
+use std::fmt::Display;
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use http::httpresponse::StatusCode;
+
+/// The longest head, request line and headers, that the server reads
+pub const DEFAULT_MAX_HEAD_SIZE: usize = 16 * 1024;
+/// The longest body that the server reads
+pub const DEFAULT_MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+/// Error on a connection with a client, reported to the connection error hook
+/// of the server instead of panicking the thread that serves the connection.
+#[derive(Debug)]
+pub enum ConnectionError {
+    /// The client went away, e.g. it closed the connection before sending a
+    /// request or before reading the response. Expected from time to time.
+    Disconnected(io::Error),
+    /// Any other I/O failure, e.g. accepting the connection or a timeout.
+    Io(io::Error),
+}
+
+impl ConnectionError {
+    pub fn is_disconnect(&self) -> bool {
+        matches!(self, ConnectionError::Disconnected(_))
+    }
+
+    pub fn io_error(&self) -> &io::Error {
+        match self {
+            ConnectionError::Disconnected(e) | ConnectionError::Io(e) => e,
+        }
+    }
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::UnexpectedEof => ConnectionError::Disconnected(e),
+            _ => ConnectionError::Io(e),
+        }
+    }
+}
+
+impl Display for ConnectionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConnectionError::Disconnected(e) => write!(f, "client disconnected: {}", e),
+            ConnectionError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for ConnectionError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(self.io_error())
+    }
+}
+
+/// Why the bytes of a connection can't be split into requests. The server
+/// answers with the status of the error and closes the connection, since it
+/// can't tell where the next request would start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FramingError {
+    /// The head is longer than the limit, or doesn't end before it
+    HeadTooLarge,
+    /// The `Content-Length` is over the limit
+    BodyTooLarge,
+    /// The `Content-Length` isn't a number, there are different ones, or it
+    /// comes along with a `Transfer-Encoding`
+    InvalidLength,
+    /// The body has a `Transfer-Encoding`, e.g. chunked, which the server doesn't decode
+    UnsupportedEncoding,
+}
+
+impl FramingError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            FramingError::HeadTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            FramingError::BodyTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            FramingError::InvalidLength => StatusCode::BAD_REQUEST,
+            FramingError::UnsupportedEncoding => StatusCode::NOT_IMPLEMENTED,
+        }
+    }
+}
+
+impl Display for FramingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FramingError::HeadTooLarge => write!(f, "the head of the request is too large"),
+            FramingError::BodyTooLarge => write!(f, "the body of the request is too large"),
+            FramingError::InvalidLength => write!(f, "invalid Content-Length"),
+            FramingError::UnsupportedEncoding => write!(f, "unsupported Transfer-Encoding"),
+        }
+    }
+}
+
+impl Error for FramingError {}
+
+/// The largest request the server reads, see [`crate::server::Server::with_max_head_size`]
+/// and [`crate::server::Server::with_max_body_size`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Limits {
+    pub(crate) max_head_size: usize,
+    pub(crate) max_body_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_head_size: DEFAULT_MAX_HEAD_SIZE,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+        }
+    }
+}
+
+/// A connection that a handler takes over once the response is written, e.g.
+/// for a WebSocket, plain or encrypted
+pub(crate) trait Transport: Read + Write {}
+
+impl<T: Read + Write> Transport for T {}
+
+/// A blocking connection of the Threads backend, plain or encrypted, whose
+/// socket can be read from another thread while responses are written
+pub(crate) trait Socket: Read + Write {
+    /// The socket under the connection, streams that aren't sockets have none
+    fn try_clone_socket(&self) -> io::Result<TcpStream> {
+        Err(ErrorKind::Unsupported.into())
+    }
+
+    /// What the client sent in the bytes read from the socket, e.g. decrypted
+    fn decode(&mut self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(bytes.to_vec())
+    }
+}
+
+impl Socket for TcpStream {
+    fn try_clone_socket(&self) -> io::Result<TcpStream> {
+        self.try_clone()
+    }
+}
+
+#[cfg(feature = "tls")]
+impl Socket for rustls::StreamOwned<rustls::ServerConnection, TcpStream> {
+    fn try_clone_socket(&self) -> io::Result<TcpStream> {
+        self.sock.try_clone()
+    }
+
+    fn decode(&mut self, mut bytes: &[u8]) -> io::Result<Vec<u8>> {
+        let mut decoded = Vec::new();
+        while !bytes.is_empty() {
+            self.conn.read_tls(&mut bytes)?;
+            let state = self.conn.process_new_packets().map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+            let start = decoded.len();
+            decoded.resize(start + state.plaintext_bytes_to_read(), 0);
+            self.conn.reader().read_exact(&mut decoded[start..])?;
+        }
+        // e.g. the answer to a key update
+        while self.conn.wants_write() {
+            self.conn.write_tls(&mut self.sock)?;
+        }
+        Ok(decoded)
+    }
+}
+
+/// Splits the bytes received on a connection into requests, whatever the
+/// way they were cut by the reads: a request is complete once its head and as
+/// many bytes of body as its `Content-Length` says have arrived. Bytes past
+/// the end of a request are kept for the next one.
+#[derive(Debug, Default)]
+pub struct RequestParser {
+    buffer: Vec<u8>,
+    limits: Limits,
+}
+
+impl RequestParser {
+    pub(crate) fn new(limits: Limits) -> Self {
+        RequestParser {
+            buffer: Vec::new(),
+            limits,
+        }
+    }
+
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Takes the first complete request out of the buffer. Fails as soon as
+    /// the head shows that the request can't be read, before its body arrives.
+    pub fn next_request(&mut self) -> Result<Option<Vec<u8>>, FramingError> {
+        let Some(request_len) = request_len(&self.buffer, self.limits)? else {
+            return Ok(None);
+        };
+        if self.buffer.len() < request_len {
+            return Ok(None);
+        }
+        let rest = self.buffer.split_off(request_len);
+        Ok(Some(std::mem::replace(&mut self.buffer, rest)))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Whether the buffer holds more than the largest request, so that reading
+    /// more can wait until the requests it holds are answered
+    pub fn is_full(&self) -> bool {
+        self.buffer.len() >= self.limits.max_head_size + self.limits.max_body_size
+    }
+
+    /// Whatever was received of a request that never completed
+    pub fn take_incomplete(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.buffer)
+    }
+
+    /// Like [`read_request`], the bytes the client sent past the request are
+    /// kept in the parser
+    pub fn read_request(&mut self, stream: &mut impl Read) -> Result<Result<Vec<u8>, FramingError>, ConnectionError> {
+        let mut temp_buff = [0u8; 1024];
+        loop {
+            match self.next_request() {
+                Ok(Some(raw_request)) => return Ok(Ok(raw_request)),
+                Ok(None) => (),
+                Err(e) => return Ok(Err(e)),
+            }
+            let n = match stream.read(&mut temp_buff) {
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            if n == 0 {
+                if self.is_empty() {
+                    return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed before the request").into());
+                }
+                return Ok(Ok(self.take_incomplete()));
+            }
+            self.feed(&temp_buff[..n]);
+        }
+    }
+}
+
+/// Reads a request with blocking reads. A request cut short by the client is
+/// returned as it is, so that it's answered with a 400, unless nothing was sent
+/// at all. A request that can't be framed fails with the [`FramingError`] to
+/// answer, the connection fails with the [`ConnectionError`].
+pub fn read_request(stream: &mut impl Read) -> Result<Result<Vec<u8>, FramingError>, ConnectionError> {
+    RequestParser::default().read_request(stream)
+}
+
+/// The length of the head plus the body, once the whole head has been read
+fn request_len(raw_request: &[u8], limits: Limits) -> Result<Option<usize>, FramingError> {
+    let Some(end_of_header) = raw_request.windows(4).position(|window| window == b"\r\n\r\n") else {
+        // the end of the head may be cut between two reads
+        return match raw_request.len() > limits.max_head_size + 3 {
+            true => Err(FramingError::HeadTooLarge),
+            false => Ok(None),
+        };
+    };
+    let end_of_header = end_of_header + 4;
+    if end_of_header > limits.max_head_size {
+        return Err(FramingError::HeadTooLarge);
+    }
+
+    let head = String::from_utf8_lossy(&raw_request[..end_of_header]);
+    let mut content_length = None;
+    let mut transfer_encoding = false;
+    for (key, value) in head.split("\r\n").skip(1).filter_map(|line| line.split_once(':')) {
+        if key.trim().eq_ignore_ascii_case("Transfer-Encoding") {
+            transfer_encoding = true;
+        } else if key.trim().eq_ignore_ascii_case("Content-Length") {
+            // repeated in one header, e.g. `5, 5`, or in several, the lengths must agree
+            for value in value.split(',').map(str::trim) {
+                if value.is_empty() || !value.bytes().all(|byte| byte.is_ascii_digit()) {
+                    return Err(FramingError::InvalidLength);
+                }
+                // too many digits for usize is too large anyway
+                let length = value.parse::<usize>().map_err(|_| FramingError::BodyTooLarge)?;
+                if content_length.is_some_and(|content_length| content_length != length) {
+                    return Err(FramingError::InvalidLength);
+                }
+                content_length = Some(length);
+            }
+        }
+    }
+    // both would let the client and the server disagree on where the request ends
+    match (transfer_encoding, content_length) {
+        (true, Some(_)) => Err(FramingError::InvalidLength),
+        (true, None) => Err(FramingError::UnsupportedEncoding),
+        (false, Some(length)) if length > limits.max_body_size => Err(FramingError::BodyTooLarge),
+        (false, length) => Ok(Some(end_of_header + length.unwrap_or(0))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Returns the chunks one read at a time, then fails with the error, if any
+    struct ChunkedStream {
+        chunks: Vec<&'static [u8]>,
+        error: Option<ErrorKind>,
+    }
+
+    impl Read for ChunkedStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.chunks.is_empty() {
+                return match self.error.take() {
+                    Some(kind) => Err(io::Error::from(kind)),
+                    None => Ok(0),
+                };
+            }
+            let chunk = self.chunks.remove(0);
+            buf[..chunk.len()].copy_from_slice(chunk);
+            Ok(chunk.len())
+        }
+    }
+
+    #[test]
+    fn test_read_request() {
+        // the body arrives in a later read than the head
+        let mut stream = ChunkedStream {
+            chunks: vec![b"POST /echo HTTP/1.1\r\ncontent-length: 5\r\n\r\n", b"hel", b"lo"],
+            error: Some(ErrorKind::TimedOut),
+        };
+        assert_eq!(read_request(&mut stream).unwrap().unwrap(), b"POST /echo HTTP/1.1\r\ncontent-length: 5\r\n\r\nhello");
+
+        // doesn't wait for more once the request is complete
+        let mut stream = ChunkedStream { chunks: vec![b"GET / HTTP/1.1\r\n\r\n"], error: Some(ErrorKind::TimedOut) };
+        assert_eq!(read_request(&mut stream).unwrap().unwrap(), b"GET / HTTP/1.1\r\n\r\n");
+
+        let mut stream = ChunkedStream { chunks: vec![b"GET / HT"], error: None };
+        assert_eq!(read_request(&mut stream).unwrap().unwrap(), b"GET / HT");
+
+        // the framing error is returned, for the server to answer it
+        let mut stream = ChunkedStream {
+            chunks: vec![b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n0\r\n\r\n"],
+            error: None,
+        };
+        assert_eq!(read_request(&mut stream).unwrap(), Err(FramingError::UnsupportedEncoding));
+    }
+
+    #[test]
+    fn test_request_parser() {
+        let mut parser = RequestParser::default();
+        parser.feed(b"POST /echo HTTP/1.1\r\nContent-Le");
+        assert_eq!(parser.next_request(), Ok(None));
+        parser.feed(b"ngth: 2\r\n\r\nhiGET / HTTP/1.1\r\n\r\nGET /n");
+
+        // pipelined requests come out one at a time
+        assert_eq!(parser.next_request().unwrap().unwrap(), b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\n\r\nhi");
+        assert_eq!(parser.next_request().unwrap().unwrap(), b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(parser.next_request(), Ok(None));
+        assert!(!parser.is_empty());
+        assert_eq!(parser.take_incomplete(), b"GET /n");
+        assert!(parser.is_empty());
+    }
+
+    fn frame(raw_request: &[u8]) -> Result<Option<Vec<u8>>, FramingError> {
+        let mut parser = RequestParser::new(Limits { max_head_size: 128, max_body_size: 10 });
+        parser.feed(raw_request);
+        parser.next_request()
+    }
+
+    #[test]
+    fn test_framing() {
+        assert_eq!(frame(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789").unwrap().unwrap().len(), 49);
+        // the same length repeated is fine
+        assert!(frame(b"POST / HTTP/1.1\r\nContent-Length: 2, 2\r\ncontent-length: 2\r\n\r\nhi").unwrap().is_some());
+
+        // a chunked body would be read as the next request
+        for raw_request in [&b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n"[..],
+                            b"POST / HTTP/1.1\r\ntransfer-encoding: gzip, chunked\r\n\r\n"] {
+            assert_eq!(frame(raw_request), Err(FramingError::UnsupportedEncoding));
+        }
+        for raw_request in [&b"POST / HTTP/1.1\r\nContent-Length: 2\r\nTransfer-Encoding: chunked\r\n\r\n"[..],
+                            b"POST / HTTP/1.1\r\nContent-Length: abc\r\n\r\n",
+                            b"POST / HTTP/1.1\r\nContent-Length: +2\r\n\r\nhi",
+                            b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n",
+                            b"POST / HTTP/1.1\r\nContent-Length:\r\n\r\n",
+                            b"POST / HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 3\r\n\r\nhi!",
+                            b"POST / HTTP/1.1\r\nContent-Length: 2, 3\r\n\r\nhi!"] {
+            assert_eq!(frame(raw_request), Err(FramingError::InvalidLength), "{}", String::from_utf8_lossy(raw_request));
+        }
+
+        // refused before the body arrives
+        assert_eq!(frame(b"POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\n"), Err(FramingError::BodyTooLarge));
+        assert_eq!(frame(b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n"),
+                   Err(FramingError::BodyTooLarge));
+        // and the head before it ends
+        assert_eq!(frame(&[b'a'; 128]), Ok(None));
+        assert_eq!(frame(&[b'a'; 132]), Err(FramingError::HeadTooLarge));
+        let mut long_head = b"GET / HTTP/1.1\r\nCookie: ".to_vec();
+        long_head.extend([b'a'; 110]);
+        long_head.extend(b"\r\n\r\n");
+        assert_eq!(frame(&long_head), Err(FramingError::HeadTooLarge));
+        assert_eq!(FramingError::HeadTooLarge.status(), StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+        assert_eq!(FramingError::BodyTooLarge.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(FramingError::InvalidLength.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(FramingError::UnsupportedEncoding.status(), StatusCode::NOT_IMPLEMENTED);
+
+        // a full parser holds more than a request
+        let mut parser = RequestParser::new(Limits { max_head_size: 128, max_body_size: 10 });
+        parser.feed(&[b'a'; 137]);
+        assert!(!parser.is_full());
+        parser.feed(b"a");
+        assert!(parser.is_full());
+    }
+
+    #[test]
+    fn test_classify_errors() {
+        let mut stream = ChunkedStream { chunks: vec![], error: None };
+        assert!(read_request(&mut stream).unwrap_err().is_disconnect());
+
+        let mut stream = ChunkedStream { chunks: vec![b"GET / HTTP/1.1\r\n"], error: Some(ErrorKind::ConnectionReset) };
+        assert!(read_request(&mut stream).unwrap_err().is_disconnect());
+
+        let mut stream = ChunkedStream { chunks: vec![], error: Some(ErrorKind::TimedOut) };
+        let error = read_request(&mut stream).unwrap_err();
+        assert!(!error.is_disconnect());
+        assert_eq!(error.io_error().kind(), ErrorKind::TimedOut);
+
+        assert!(ConnectionError::from(io::Error::from(ErrorKind::BrokenPipe)).is_disconnect());
+    }
+}
@@ -0,0 +1,450 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::sync::Arc;
+
+use serde::Serialize;
+use serde::de::{self, DeserializeOwned, IntoDeserializer, Visitor};
+use serde::de::value::{Error as DeError, MapDeserializer};
+use http::httprequest::HttpRequest;
+use http::httpresponse::{HttpResponse, IntoResponse, StatusCode};
+
+/// Builds a handler argument from the request, e.g. `Path<T>` or `Json<T>`.
+/// Handlers whose arguments all implement it can be registered on a router:
+///
+/// ```text
+/// fn create_order(Path(user_id): Path<u64>, Json(order): Json<Order>, State(db): State<Db>) -> HttpResponse
+/// ```
+pub trait FromRequest<S>: Sized {
+    fn from_request(request: &HttpRequest, state: &Arc<S>) -> Result<Self, Rejection>;
+}
+
+/// Why an extractor couldn't be built from the request. The handler isn't
+/// called and the client gets `400 Bad Request` with the message as the body.
+#[derive(Debug, PartialEq)]
+pub struct Rejection {
+    message: String,
+}
+
+impl Rejection {
+    pub fn new(message: impl Into<String>) -> Self {
+        Rejection { message: message.into() }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> HttpResponse {
+        (StatusCode::BAD_REQUEST, self.message).into_response()
+    }
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Path parameters, percent-decoded, deserialized into `T`: a struct with a
+/// field per parameter, or a single value when the route has one parameter.
+#[derive(Debug, PartialEq)]
+pub struct Path<T>(pub T);
+
+/// The query string deserialized into `T`.
+#[derive(Debug, PartialEq)]
+pub struct Query<T>(pub T);
+
+/// An `application/x-www-form-urlencoded` body deserialized into `T`.
+#[derive(Debug, PartialEq)]
+pub struct Form<T>(pub T);
+
+/// An `application/json` body deserialized into `T`. Handlers can also
+/// return it to respond with `T` serialized as JSON.
+#[derive(Debug, PartialEq)]
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> HttpResponse {
+        match serde_json::to_vec(&self.0) {
+            Ok(body) => HttpResponse::with_status(StatusCode::OK, Some(body))
+                            .with_header("Content-Type", "application/json"),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Couldn't serialize the response: {}", e))
+                          .into_response(),
+        }
+    }
+}
+
+/// The raw body of the request.
+#[derive(Debug, PartialEq)]
+pub struct Bytes(pub Vec<u8>);
+
+/// The state of the router, see [`crate::router::Router::with_state`].
+#[derive(Debug, PartialEq)]
+pub struct State<S>(pub Arc<S>);
+
+/// A header decoded into `T`, see [`TypedHeader`]. Use `Option<Header<T>>`
+/// when the header is optional.
+#[derive(Debug, PartialEq)]
+pub struct Header<T>(pub T);
+
+/// A header that can be extracted with [`Header`].
+pub trait TypedHeader: Sized {
+    const NAME: &'static str;
+
+    fn decode(value: &str) -> Result<Self, String>;
+}
+
+macro_rules! string_header {
+    ($($header:ident => $name:literal),*) => {
+        $(
+            #[derive(Debug, PartialEq)]
+            pub struct $header(pub String);
+
+            impl TypedHeader for $header {
+                const NAME: &'static str = $name;
+
+                fn decode(value: &str) -> Result<Self, String> {
+                    Ok($header(value.to_string()))
+                }
+            }
+        )*
+    };
+}
+
+string_header!(
+    Host => "Host",
+    UserAgent => "User-Agent",
+    ContentType => "Content-Type",
+    Authorization => "Authorization"
+);
+
+impl<S, T: DeserializeOwned> FromRequest<S> for Path<T> {
+    fn from_request(request: &HttpRequest, _state: &Arc<S>) -> Result<Self, Rejection> {
+        T::deserialize(PathDeserializer { params: &request.path_params })
+            .map(Path)
+            .map_err(|e| Rejection::new(format!("Invalid path parameters: {}", e)))
+    }
+}
+
+impl<S, T: DeserializeOwned> FromRequest<S> for Query<T> {
+    fn from_request(request: &HttpRequest, _state: &Arc<S>) -> Result<Self, Rejection> {
+        serde_urlencoded::from_str(request.query().unwrap_or(""))
+            .map(Query)
+            .map_err(|e| Rejection::new(format!("Invalid query string: {}", e)))
+    }
+}
+
+impl<S, T: DeserializeOwned> FromRequest<S> for Form<T> {
+    fn from_request(request: &HttpRequest, _state: &Arc<S>) -> Result<Self, Rejection> {
+        expect_content_type(request, "application/x-www-form-urlencoded")?;
+        serde_urlencoded::from_bytes(&request.body)
+            .map(Form)
+            .map_err(|e| Rejection::new(format!("Invalid form body: {}", e)))
+    }
+}
+
+impl<S, T: DeserializeOwned> FromRequest<S> for Json<T> {
+    fn from_request(request: &HttpRequest, _state: &Arc<S>) -> Result<Self, Rejection> {
+        expect_content_type(request, "application/json")?;
+        serde_json::from_slice(&request.body)
+            .map(Json)
+            .map_err(|e| Rejection::new(format!("Invalid JSON body: {}", e)))
+    }
+}
+
+impl<S> FromRequest<S> for Bytes {
+    fn from_request(request: &HttpRequest, _state: &Arc<S>) -> Result<Self, Rejection> {
+        Ok(Bytes(request.body.clone()))
+    }
+}
+
+impl<S> FromRequest<S> for State<S> {
+    fn from_request(_request: &HttpRequest, state: &Arc<S>) -> Result<Self, Rejection> {
+        Ok(State(state.clone()))
+    }
+}
+
+impl<S, T: TypedHeader> FromRequest<S> for Header<T> {
+    fn from_request(request: &HttpRequest, _state: &Arc<S>) -> Result<Self, Rejection> {
+        let value = request.header_value(T::NAME)
+                           .ok_or_else(|| Rejection::new(format!("Missing header `{}`", T::NAME)))?;
+        T::decode(value)
+            .map(Header)
+            .map_err(|e| Rejection::new(format!("Invalid header `{}`: {}", T::NAME, e)))
+    }
+}
+
+impl<S, T: FromRequest<S>> FromRequest<S> for Option<T> {
+    fn from_request(request: &HttpRequest, state: &Arc<S>) -> Result<Self, Rejection> {
+        Ok(T::from_request(request, state).ok())
+    }
+}
+
+fn expect_content_type(request: &HttpRequest, expected: &str) -> Result<(), Rejection> {
+    let content_type = request.header_value("Content-Type").unwrap_or_default();
+    let mime_type = content_type.split(';').next().unwrap_or_default().trim();
+    if mime_type.eq_ignore_ascii_case(expected) {
+        Ok(())
+    } else {
+        Err(Rejection::new(format!("Expected Content-Type `{}`, found `{}`", expected, content_type)))
+    }
+}
+
+/// Deserializes the path parameters as a map, or as a single value when
+/// there is only one of them
+struct PathDeserializer<'a> {
+    params: &'a HashMap<String, String>,
+}
+
+impl<'a> PathDeserializer<'a> {
+    fn single_value(&self) -> Result<ParamValue<'a>, DeError> {
+        let mut values = self.params.values();
+        match (values.next(), values.next()) {
+            (Some(value), None) => Ok(ParamValue(value)),
+            _ => Err(de::Error::custom(format!("expected 1 path parameter, found {}", self.params.len()))),
+        }
+    }
+}
+
+macro_rules! single_value {
+    ($($method:ident),*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.single_value()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for PathDeserializer<'de> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let params = self.params.iter().map(|(name, value)| (name.as_str(), ParamValue(value)));
+        visitor.visit_map(MapDeserializer::new(params))
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(self, _name: &'static str, _fields: &'static [&'static str],
+                                          visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str],
+                                        visitor: V) -> Result<V::Value, Self::Error> {
+        self.single_value()?.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, Self::Error> {
+        Err(de::Error::custom("path parameters can't be read as a sequence, use a struct"))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(self, _name: &'static str, _len: usize,
+                                                visitor: V) -> Result<V::Value, Self::Error> {
+        self.deserialize_seq(visitor)
+    }
+
+    single_value!(deserialize_bool, deserialize_i8, deserialize_i16, deserialize_i32, deserialize_i64,
+                  deserialize_u8, deserialize_u16, deserialize_u32, deserialize_u64, deserialize_f32,
+                  deserialize_f64, deserialize_char, deserialize_str, deserialize_string, deserialize_option,
+                  deserialize_bytes, deserialize_byte_buf, deserialize_unit, deserialize_identifier,
+                  deserialize_ignored_any);
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(self, name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        self.single_value()?.deserialize_unit_struct(name, visitor)
+    }
+}
+
+/// The value of a single path parameter, parsed into the type the visitor asks for
+struct ParamValue<'a>(&'a str);
+
+macro_rules! parse_value {
+    ($($method:ident => $visit:ident: $type:ty),*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                let value: $type = self.0.parse().map_err(|_| {
+                    de::Error::custom(format!("`{}` is not a valid {}", self.0, stringify!($type)))
+                })?;
+                visitor.$visit(value)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ParamValue<'de> {
+    type Error = DeError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_borrowed_str(self.0)
+    }
+
+    parse_value!(deserialize_bool => visit_bool: bool,
+                 deserialize_i8 => visit_i8: i8,
+                 deserialize_i16 => visit_i16: i16,
+                 deserialize_i32 => visit_i32: i32,
+                 deserialize_i64 => visit_i64: i64,
+                 deserialize_u8 => visit_u8: u8,
+                 deserialize_u16 => visit_u16: u16,
+                 deserialize_u32 => visit_u32: u32,
+                 deserialize_u64 => visit_u64: u64,
+                 deserialize_f32 => visit_f32: f32,
+                 deserialize_f64 => visit_f64: f64,
+                 deserialize_char => visit_char: char);
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(self, _name: &'static str, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(self, name: &'static str, variants: &'static [&'static str],
+                                        visitor: V) -> Result<V::Value, Self::Error> {
+        self.0.into_deserializer().deserialize_enum(name, variants, visitor)
+    }
+
+    serde::forward_to_deserialize_any! {
+        str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de> IntoDeserializer<'de, DeError> for ParamValue<'de> {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    fn request(raw_request: &str, path_params: &[(&str, &str)]) -> HttpRequest {
+        let mut request = HttpRequest::parse(raw_request.as_bytes().to_vec()).unwrap();
+        let path_params: HashMap<String, String> = path_params.iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        request.with_path_params(&path_params);
+        request
+    }
+
+    fn extract<T: FromRequest<()>>(request: &HttpRequest) -> Result<T, Rejection> {
+        T::from_request(request, &Arc::new(()))
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct OrderPath {
+        user_id: u64,
+        order_id: String,
+    }
+
+    #[derive(Debug, PartialEq, Deserialize)]
+    struct Search {
+        q: String,
+        page: Option<u32>,
+    }
+
+    #[test]
+    fn test_path_extractor() {
+        let req = request("GET /users/1/orders/A1 HTTP/1.1\r\n\r\n", &[("user_id", "1"), ("order_id", "A1")]);
+        let Path(order) = extract::<Path<OrderPath>>(&req).unwrap();
+        assert_eq!(order, OrderPath { user_id: 1, order_id: String::from("A1") });
+
+        let req = request("GET /users/42 HTTP/1.1\r\n\r\n", &[("user_id", "42")]);
+        assert_eq!(extract::<Path<u64>>(&req), Ok(Path(42)));
+        assert_eq!(extract::<Path<String>>(&req), Ok(Path(String::from("42"))));
+
+        let req = request("GET /users/bob HTTP/1.1\r\n\r\n", &[("user_id", "bob")]);
+        assert_eq!(extract::<Path<u64>>(&req),
+                   Err(Rejection::new("Invalid path parameters: `bob` is not a valid u64")));
+
+        let req = request("GET /users/1/orders/A1 HTTP/1.1\r\n\r\n", &[("user_id", "1"), ("order_id", "A1")]);
+        assert_eq!(extract::<Path<u64>>(&req),
+                   Err(Rejection::new("Invalid path parameters: expected 1 path parameter, found 2")));
+    }
+
+    #[test]
+    fn test_path_round_trip() {
+        use http::httprequest::Method;
+        use crate::router::Router;
+
+        let mut router = Router::default();
+        router.get("/users/{user_id:u64}/orders/{order_id}", |_: &HttpRequest| "order").name("user_order");
+        let url = router.url_for("user_order", &[("user_id", "7"), ("order_id", "A 1/2?ü%")]).unwrap();
+        let route_info = router.find_handler(Method::Get, &url).unwrap();
+        let mut req = request(&format!("GET {} HTTP/1.1\r\n\r\n", url), &[]);
+        req.with_path_params(&route_info.extract_path_params(req.path()));
+        let Path(order) = extract::<Path<OrderPath>>(&req).unwrap();
+        assert_eq!(order, OrderPath { user_id: 7, order_id: String::from("A 1/2?ü%") });
+    }
+
+    #[test]
+    fn test_query_extractor() {
+        let req = request("GET /search?q=rust&page=2 HTTP/1.1\r\n\r\n", &[]);
+        assert_eq!(extract::<Query<Search>>(&req), Ok(Query(Search { q: String::from("rust"), page: Some(2) })));
+
+        let req = request("GET /search?q=rust HTTP/1.1\r\n\r\n", &[]);
+        assert_eq!(extract::<Query<Search>>(&req), Ok(Query(Search { q: String::from("rust"), page: None })));
+
+        let req = request("GET /search HTTP/1.1\r\n\r\n", &[]);
+        assert_eq!(extract::<Query<Search>>(&req), Err(Rejection::new("Invalid query string: missing field `q`")));
+    }
+
+    #[test]
+    fn test_body_extractors() {
+        let req = request("POST /search HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"q\":\"rust\",\"page\":3}", &[]);
+        assert_eq!(extract::<Json<Search>>(&req), Ok(Json(Search { q: String::from("rust"), page: Some(3) })));
+        assert_eq!(extract::<Bytes>(&req), Ok(Bytes(b"{\"q\":\"rust\",\"page\":3}".to_vec())));
+        assert!(extract::<Form<Search>>(&req).unwrap_err().message().starts_with("Expected Content-Type"));
+
+        let req = request("POST /search HTTP/1.1\r\nContent-Type: application/json; charset=utf-8\r\n\r\n{\"page\":3}", &[]);
+        assert_eq!(extract::<Json<Search>>(&req),
+                   Err(Rejection::new("Invalid JSON body: missing field `q` at line 1 column 10")));
+
+        let req = request("POST /search HTTP/1.1\r\nContent-Type: application/x-www-form-urlencoded\r\n\r\nq=rust+lang", &[]);
+        assert_eq!(extract::<Form<Search>>(&req), Ok(Form(Search { q: String::from("rust lang"), page: None })));
+    }
+
+    #[test]
+    fn test_header_and_state_extractors() {
+        let req = request("GET / HTTP/1.1\r\nuser-agent: curl/8.0\r\n\r\n", &[]);
+        assert_eq!(extract::<Header<UserAgent>>(&req), Ok(Header(UserAgent(String::from("curl/8.0")))));
+        assert_eq!(extract::<Header<Authorization>>(&req), Err(Rejection::new("Missing header `Authorization`")));
+        assert_eq!(extract::<Option<Header<Authorization>>>(&req), Ok(None));
+
+        let state = Arc::new(String::from("state"));
+        let State(extracted) = State::from_request(&req, &state).unwrap();
+        assert!(Arc::ptr_eq(&extracted, &state));
+    }
+
+    #[test]
+    fn test_json_response() {
+        let response = Json(serde_json::json!({"id": 1})).into_response();
+        assert_eq!(response.status_code, "200");
+        assert_eq!(response.body, Some(b"{\"id\":1}".to_vec()));
+        assert_eq!(response.headers.unwrap().get("Content-Type").unwrap(), "application/json");
+    }
+
+    #[test]
+    fn test_rejection_response() {
+        let response = Rejection::new("Missing header `Authorization`").into_response();
+        assert_eq!(response.status_code, "400");
+        assert_eq!(response.body, Some(b"Missing header `Authorization`".to_vec()));
+    }
+}
@@ -0,0 +1,248 @@
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+use http::{httprequest::HttpRequest, httpresponse::IntoResponse};
+use crate::extract::FromRequest;
+use crate::router::RouteHandler;
+#[cfg(feature = "tokio")]
+use crate::router::AsyncRouteHandler;
+
+/// Anything that can handle the requests of a route of a `Router<S>`.
+///
+/// It's implemented for functions and closures that return anything that
+/// implements [`IntoResponse`] and take the request and, optionally, the state
+/// of the router either as `&S` or as `Arc<S>`:
+///
+/// ```text
+/// fn hello(req: &HttpRequest) -> HttpResponse
+/// fn visits(req: &HttpRequest, state: &AppState) -> HttpResponse
+/// fn visits(req: &HttpRequest, state: Arc<AppState>) -> HttpResponse
+/// ```
+///
+/// and for functions and closures that take up to 8 extractors, see [`FromRequest`]:
+///
+/// ```text
+/// fn order(Path(order): Path<OrderPath>, State(state): State<AppState>) -> Result<Json<Order>, AppError>
+/// ```
+///
+/// With the `tokio` feature, it's also implemented for async functions and
+/// closures that take the request by value or up to 8 extractors:
+///
+/// ```text
+/// async fn hello(req: HttpRequest) -> String
+/// async fn order(Path(id): Path<u64>, State(db): State<Db>) -> Result<Json<Order>, AppError>
+/// ```
+///
+/// `M` only distinguishes the implementations, it's inferred by the compiler.
+pub trait Handler<S, M>: Send + Sync + 'static {
+    /// Binds the state of the router to the handler
+    fn into_route_handler(self, state: Arc<S>) -> RouteHandler;
+
+    /// Binds the state of the router to the handler, along with the async
+    /// version of the handler for the backends that can await it
+    #[cfg(feature = "tokio")]
+    fn into_endpoint(self, state: Arc<S>) -> (RouteHandler, Option<AsyncRouteHandler>)
+    where
+        Self: Sized,
+    {
+        (self.into_route_handler(state), None)
+    }
+}
+
+/// Marker of handlers that only take the request, `R` is their return type
+pub struct RequestOnly<R>(PhantomData<R>);
+
+/// Marker of handlers that take the request and `&S`
+pub struct WithStateRef<R>(PhantomData<R>);
+
+/// Marker of handlers that take the request and `Arc<S>`
+pub struct WithState<R>(PhantomData<R>);
+
+/// Marker of handlers whose arguments are extractors, `T` is the tuple of their types
+pub struct WithExtractors<T, R>(PhantomData<(T, R)>);
+
+impl<F, S, R> Handler<S, RequestOnly<R>> for F
+where
+    F: Fn(&HttpRequest) -> R + Send + Sync + 'static,
+    R: IntoResponse,
+{
+    fn into_route_handler(self, _state: Arc<S>) -> RouteHandler {
+        Arc::new(move |request: &HttpRequest| self(request).into_response())
+    }
+}
+
+impl<F, S, R> Handler<S, WithStateRef<R>> for F
+where
+    F: Fn(&HttpRequest, &S) -> R + Send + Sync + 'static,
+    S: Send + Sync + 'static,
+    R: IntoResponse,
+{
+    fn into_route_handler(self, state: Arc<S>) -> RouteHandler {
+        Arc::new(move |request: &HttpRequest| self(request, &state).into_response())
+    }
+}
+
+impl<F, S, R> Handler<S, WithState<R>> for F
+where
+    F: Fn(&HttpRequest, Arc<S>) -> R + Send + Sync + 'static,
+    S: Send + Sync + 'static,
+    R: IntoResponse,
+{
+    fn into_route_handler(self, state: Arc<S>) -> RouteHandler {
+        Arc::new(move |request: &HttpRequest| self(request, state.clone()).into_response())
+    }
+}
+
+macro_rules! extractor_handler {
+    ($($extractor:ident),*) => {
+        impl<F, S, R, $($extractor,)*> Handler<S, WithExtractors<($($extractor,)*), R>> for F
+        where
+            F: Fn($($extractor,)*) -> R + Send + Sync + 'static,
+            S: Send + Sync + 'static,
+            R: IntoResponse,
+            $($extractor: FromRequest<S>,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn into_route_handler(self, state: Arc<S>) -> RouteHandler {
+                Arc::new(move |request: &HttpRequest| {
+                    $(
+                        let $extractor = match $extractor::from_request(request, &state) {
+                            Ok(value) => value,
+                            Err(rejection) => return rejection.into_response(),
+                        };
+                    )*
+                    self($($extractor,)*).into_response()
+                })
+            }
+        }
+    };
+}
+
+extractor_handler!();
+extractor_handler!(T1);
+extractor_handler!(T1, T2);
+extractor_handler!(T1, T2, T3);
+extractor_handler!(T1, T2, T3, T4);
+extractor_handler!(T1, T2, T3, T4, T5);
+extractor_handler!(T1, T2, T3, T4, T5, T6);
+extractor_handler!(T1, T2, T3, T4, T5, T6, T7);
+extractor_handler!(T1, T2, T3, T4, T5, T6, T7, T8);
+
+#[cfg(feature = "tokio")]
+pub use self::asynchronous::{AsyncRequest, AsyncWithExtractors};
+
+#[cfg(feature = "tokio")]
+mod asynchronous {
+    use std::future::{self, Future};
+    use std::marker::PhantomData;
+    use std::sync::{Arc, OnceLock};
+    use std::{panic, thread};
+
+    use tokio::runtime::{Builder, Handle, Runtime, RuntimeFlavor};
+    use tokio::task;
+    use http::{httprequest::HttpRequest, httpresponse::IntoResponse};
+    use crate::extract::FromRequest;
+    use crate::router::{AsyncRouteHandler, RouteHandler};
+    use super::Handler;
+
+    /// Marker of async handlers that take the request, `R` is the output of their future
+    pub struct AsyncRequest<R>(PhantomData<R>);
+
+    /// Marker of async handlers whose arguments are extractors
+    pub struct AsyncWithExtractors<T, R>(PhantomData<(T, R)>);
+
+    /// Runs the async handler to completion for the backends that call handlers
+    /// synchronously, and for the middleware chain of the tokio backend
+    fn blocking(async_handler: AsyncRouteHandler) -> RouteHandler {
+        Arc::new(move |request: &HttpRequest| {
+            let future = async_handler(request.clone());
+            match Handle::try_current() {
+                // a blocking thread of the tokio backend, or a worker of a
+                // runtime that lets it block
+                Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                    task::block_in_place(|| handle.block_on(future))
+                },
+                // a current-thread runtime can't be blocked in place, nor be
+                // entered again from its own thread
+                Ok(_) => thread::scope(|scope| scope.spawn(|| runtime().block_on(future)).join())
+                             .unwrap_or_else(|panic| panic::resume_unwind(panic)),
+                Err(_) => runtime().block_on(future),
+            }
+        })
+    }
+
+    /// The runtime that drives async handlers outside of the tokio backend, so
+    /// they can still use tokio's I/O and timers
+    fn runtime() -> &'static Runtime {
+        static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+        RUNTIME.get_or_init(|| {
+            Builder::new_multi_thread().worker_threads(1)
+                                       .enable_all()
+                                       .build()
+                                       .expect("Couldn't start the runtime of the async handlers")
+        })
+    }
+
+    impl<F, Fut, S, R> Handler<S, AsyncRequest<R>> for F
+    where
+        F: Fn(HttpRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = R> + Send + 'static,
+        S: Send + Sync + 'static,
+        R: IntoResponse,
+    {
+        fn into_route_handler(self, state: Arc<S>) -> RouteHandler {
+            self.into_endpoint(state).0
+        }
+
+        fn into_endpoint(self, _state: Arc<S>) -> (RouteHandler, Option<AsyncRouteHandler>) {
+            let async_handler: AsyncRouteHandler = Arc::new(move |request: HttpRequest| {
+                let future = self(request);
+                Box::pin(async move { future.await.into_response() })
+            });
+            (blocking(async_handler.clone()), Some(async_handler))
+        }
+    }
+
+    macro_rules! async_extractor_handler {
+        ($($extractor:ident),*) => {
+            impl<F, Fut, S, R, $($extractor,)*> Handler<S, AsyncWithExtractors<($($extractor,)*), R>> for F
+            where
+                F: Fn($($extractor,)*) -> Fut + Send + Sync + 'static,
+                Fut: Future<Output = R> + Send + 'static,
+                S: Send + Sync + 'static,
+                R: IntoResponse,
+                $($extractor: FromRequest<S>,)*
+            {
+                fn into_route_handler(self, state: Arc<S>) -> RouteHandler {
+                    self.into_endpoint(state).0
+                }
+
+                #[allow(non_snake_case, unused_variables)]
+                fn into_endpoint(self, state: Arc<S>) -> (RouteHandler, Option<AsyncRouteHandler>) {
+                    let async_handler: AsyncRouteHandler = Arc::new(move |request: HttpRequest| {
+                        // the extractors run before the future, which only owns their values
+                        $(
+                            let $extractor = match $extractor::from_request(&request, &state) {
+                                Ok(value) => value,
+                                Err(rejection) => return Box::pin(future::ready(rejection.into_response())),
+                            };
+                        )*
+                        let future = self($($extractor,)*);
+                        Box::pin(async move { future.await.into_response() })
+                    });
+                    (blocking(async_handler.clone()), Some(async_handler))
+                }
+            }
+        };
+    }
+
+    async_extractor_handler!();
+    async_extractor_handler!(T1);
+    async_extractor_handler!(T1, T2);
+    async_extractor_handler!(T1, T2, T3);
+    async_extractor_handler!(T1, T2, T3, T4);
+    async_extractor_handler!(T1, T2, T3, T4, T5);
+    async_extractor_handler!(T1, T2, T3, T4, T5, T6);
+    async_extractor_handler!(T1, T2, T3, T4, T5, T6, T7);
+    async_extractor_handler!(T1, T2, T3, T4, T5, T6, T7, T8);
+}
@@ -0,0 +1,1212 @@
+//! HTTP/2 (RFC 9113) on a connection, without the I/O: the backends feed it
+//! what they read, route the requests that come out the same way as the
+//! HTTP/1.1 ones and hand the responses back, then write the frames it
+//! produced. Clients get it with ALPN `h2` over TLS, and in cleartext when they
+//! start the connection with the preface of HTTP/2 ("prior knowledge", there
+//! is no `Upgrade: h2c`).
+
+mod hpack;
+
+use std::collections::{BTreeMap, HashMap};
+
+use http::httprequest::{HttpRequest, Method, Version};
+use http::httpresponse::{HttpResponse, StatusCode};
+
+/// What the client sends first, before its SETTINGS
+const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+const FRAME_HEADER_LEN: usize = 9;
+/// The largest frame the server accepts, the default of SETTINGS_MAX_FRAME_SIZE
+const MAX_FRAME_SIZE: usize = 16_384;
+const MAX_CONCURRENT_STREAMS: usize = 100;
+/// Larger header blocks end the connection, they are buffered until complete
+const MAX_HEADER_BLOCK_SIZE: usize = 65_536;
+const DEFAULT_WINDOW_SIZE: i64 = 65_535;
+const MAX_WINDOW_SIZE: i64 = (1 << 31) - 1;
+
+// frame types
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// settings
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+
+// error codes
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+pub(crate) const REFUSED_STREAM: u32 = 0x7;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+pub(crate) const HTTP_1_1_REQUIRED: u32 = 0xd;
+
+/// Headers that only mean something to an HTTP/1.1 connection, requests with
+/// them are malformed and responses don't send them
+const CONNECTION_HEADERS: [&str; 5] = ["connection", "keep-alive", "proxy-connection", "transfer-encoding", "upgrade"];
+
+/// Whether the "request" that the HTTP/1.1 parser split off is the start of
+/// the preface, the rest of it is still in the parser
+pub(crate) fn is_preface(raw_request: &[u8]) -> bool {
+    raw_request == &PREFACE[..PREFACE.len() - 6]
+}
+
+/// Why a frame can't be processed
+enum Error {
+    /// Ends the connection with GOAWAY
+    Connection(u32),
+    /// Resets the stream, the others go on
+    Stream(u32, u32),
+}
+
+/// A request that is being received, handled or answered
+struct Stream {
+    method: Method,
+    request: Option<HttpRequest>, // until the client ended the stream
+    send_window: i64,
+    body: Vec<u8>, // of the response, sent as flow control allows
+    sent: usize,
+    received: usize, // of the request, given back to the connection window once handed over
+}
+
+/// A header block that waits for its CONTINUATION frames
+struct HeaderBlock {
+    stream_id: u32,
+    end_stream: bool,
+    block: Vec<u8>,
+}
+
+pub(crate) struct Connection {
+    input: Vec<u8>,
+    output: Vec<u8>,
+    preface_received: bool,
+    settings_received: bool,
+    decoder: hpack::Decoder,
+    streams: BTreeMap<u32, Stream>,
+    last_stream_id: u32, // the highest one the client opened
+    header_block: Option<HeaderBlock>,
+    send_window: i64,
+    receive_window: i64,   // what the client may still send on the connection
+    max_body_size: usize,  // of the requests
+    initial_window: i64,   // of the streams, as set by the client
+    max_frame_size: usize, // of the frames sent to the client
+    going_away: bool,      // no new streams are accepted
+    closed: bool,          // after a connection error
+}
+
+impl Connection {
+    /// A connection whose SETTINGS are ready to be sent. Larger request
+    /// bodies than `max_body_size` are answered with 413.
+    pub(crate) fn new(max_body_size: usize) -> Self {
+        let mut connection = Connection {
+            input: Vec::new(),
+            output: Vec::new(),
+            preface_received: false,
+            settings_received: false,
+            decoder: hpack::Decoder::default(),
+            streams: BTreeMap::new(),
+            last_stream_id: 0,
+            header_block: None,
+            send_window: DEFAULT_WINDOW_SIZE,
+            receive_window: DEFAULT_WINDOW_SIZE,
+            max_body_size,
+            initial_window: DEFAULT_WINDOW_SIZE,
+            max_frame_size: MAX_FRAME_SIZE,
+            going_away: false,
+            closed: false,
+        };
+        let mut settings = SETTINGS_MAX_CONCURRENT_STREAMS.to_be_bytes().to_vec();
+        settings.extend_from_slice(&(MAX_CONCURRENT_STREAMS as u32).to_be_bytes());
+        connection.frame(SETTINGS, 0, 0, &settings);
+        // the connection window only gets its room back once the bodies are
+        // handed over, so that what is buffered is bounded, but a body past
+        // the limit must fit to be answered
+        connection.release(max_body_size.min((MAX_WINDOW_SIZE - DEFAULT_WINDOW_SIZE) as usize));
+        connection
+    }
+
+    /// Processes the bytes the client sent, from the preface on, and returns
+    /// the requests that are complete with their stream
+    pub(crate) fn receive(&mut self, bytes: &[u8]) -> Vec<(u32, HttpRequest)> {
+        let mut requests = Vec::new();
+        if self.closed {
+            return requests;
+        }
+        self.input.extend_from_slice(bytes);
+        if !self.preface_received {
+            let len = self.input.len().min(PREFACE.len());
+            if self.input[..len] != PREFACE[..len] {
+                self.connection_error(PROTOCOL_ERROR);
+                return requests;
+            }
+            if len < PREFACE.len() {
+                return requests;
+            }
+            self.input.drain(..len);
+            self.preface_received = true;
+        }
+
+        let input = std::mem::take(&mut self.input);
+        let mut rest = input.as_slice();
+        while rest.len() >= FRAME_HEADER_LEN {
+            let len = u32::from_be_bytes([0, rest[0], rest[1], rest[2]]) as usize;
+            if len > MAX_FRAME_SIZE {
+                self.connection_error(FRAME_SIZE_ERROR);
+                return Vec::new();
+            }
+            if rest.len() < FRAME_HEADER_LEN + len {
+                break;
+            }
+            let (kind, flags) = (rest[3], rest[4]);
+            let stream_id = u32::from_be_bytes([rest[5], rest[6], rest[7], rest[8]]) & 0x7fff_ffff;
+            let payload = &rest[FRAME_HEADER_LEN..FRAME_HEADER_LEN + len];
+            rest = &rest[FRAME_HEADER_LEN + len..];
+            match self.process_frame(kind, flags, stream_id, payload, &mut requests) {
+                Ok(()) => (),
+                Err(Error::Stream(stream_id, code)) => self.reset(stream_id, code),
+                Err(Error::Connection(code)) => {
+                    self.connection_error(code);
+                    return Vec::new();
+                },
+            }
+        }
+        self.input = rest.to_vec();
+        // the client may have given more room to the responses
+        self.send_data();
+        requests
+    }
+
+    /// Answers the request of the stream, unless the client reset it in the meantime
+    pub(crate) fn respond(&mut self, stream_id: u32, response: HttpResponse) {
+        let Some(stream) = self.streams.get_mut(&stream_id) else { return };
+        let bodiless = response.status_code.starts_with('1') || response.status_code == "204" || response.status_code == "304";
+
+        let mut fields = vec![(String::from(":status"), response.status_code.as_str())];
+        let mut has_content_length = false;
+        for (name, value) in response.headers.iter().flatten() {
+            let name = name.to_ascii_lowercase();
+            has_content_length |= name == "content-length";
+            if !CONNECTION_HEADERS.contains(&name.as_str()) {
+                fields.push((name, value.as_str()));
+            }
+        }
+        // a HEAD response has the length of the body it would have, like with HTTP/1.1
+        let content_length = response.body.as_ref().map_or(0, |body| body.len()).to_string();
+        if !has_content_length && !bodiless {
+            fields.push((String::from("content-length"), content_length.as_str()));
+        }
+        let fields: Vec<(&str, &str)> = fields.iter().map(|(name, value)| (name.as_str(), *value)).collect();
+        let block = hpack::encode(&fields);
+
+        if stream.method != Method::Head && !bodiless {
+            stream.body = response.body.unwrap_or_default();
+        }
+        let end_stream = stream.body.is_empty();
+        if end_stream {
+            self.streams.remove(&stream_id);
+        }
+        self.headers(stream_id, &block, end_stream);
+        self.send_data();
+    }
+
+    /// Refuses a request, e.g. with `REFUSED_STREAM` when the server is
+    /// overloaded, so that the client can retry it
+    pub(crate) fn reset(&mut self, stream_id: u32, code: u32) {
+        self.close_stream(stream_id);
+        self.frame(RST_STREAM, 0, stream_id, &code.to_be_bytes());
+    }
+
+    /// Tells the client not to open more streams, e.g. when the server shuts
+    /// down. The streams in progress are answered before the connection closes.
+    pub(crate) fn go_away(&mut self) {
+        if !self.going_away && !self.closed {
+            self.going_away = true;
+            self.go_away_frame(NO_ERROR);
+        }
+    }
+
+    /// The frames to write to the client
+    pub(crate) fn take_output(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.output)
+    }
+
+    /// Whether the connection can be closed once the output is written
+    pub(crate) fn is_closed(&self) -> bool {
+        self.closed || (self.going_away && self.streams.is_empty())
+    }
+
+    /// Whether requests are being received, handled or answered
+    pub(crate) fn has_streams(&self) -> bool {
+        !self.streams.is_empty()
+    }
+
+    fn process_frame(&mut self,
+                     kind: u8,
+                     flags: u8,
+                     stream_id: u32,
+                     payload: &[u8],
+                     requests: &mut Vec<(u32, HttpRequest)>) -> Result<(), Error> {
+        // a header block can't be interleaved with other frames
+        if let Some(header_block) = &self.header_block {
+            if kind != CONTINUATION || stream_id != header_block.stream_id {
+                return Err(Error::Connection(PROTOCOL_ERROR));
+            }
+        }
+        if !self.settings_received && kind != SETTINGS {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        match kind {
+            DATA => self.receive_data(flags, stream_id, payload, requests),
+            HEADERS => {
+                if stream_id == 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                let mut fragment = strip_padding(flags, payload)?;
+                if flags & PRIORITY_FLAG != 0 {
+                    // priorities are deprecated, and the streams are answered as soon as possible anyway
+                    fragment = fragment.get(5..).ok_or(Error::Connection(FRAME_SIZE_ERROR))?;
+                }
+                self.header_block = Some(HeaderBlock {
+                    stream_id,
+                    end_stream: flags & END_STREAM != 0,
+                    block: fragment.to_vec(),
+                });
+                match flags & END_HEADERS {
+                    0 => Ok(()),
+                    _ => self.end_headers(requests),
+                }
+            },
+            CONTINUATION => {
+                let Some(header_block) = &mut self.header_block else {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                };
+                header_block.block.extend_from_slice(payload);
+                if header_block.block.len() > MAX_HEADER_BLOCK_SIZE {
+                    return Err(Error::Connection(ENHANCE_YOUR_CALM));
+                }
+                match flags & END_HEADERS {
+                    0 => Ok(()),
+                    _ => self.end_headers(requests),
+                }
+            },
+            PRIORITY => match (stream_id, payload.len()) {
+                (0, _) => Err(Error::Connection(PROTOCOL_ERROR)),
+                (_, 5) => Ok(()),
+                (stream_id, _) => Err(Error::Stream(stream_id, FRAME_SIZE_ERROR)),
+            },
+            RST_STREAM => {
+                if stream_id == 0 || stream_id > self.last_stream_id {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                if payload.len() != 4 {
+                    return Err(Error::Connection(FRAME_SIZE_ERROR));
+                }
+                // the response, if it comes later, is dropped
+                self.close_stream(stream_id);
+                Ok(())
+            },
+            SETTINGS => self.receive_settings(flags, stream_id, payload),
+            // clients can't push
+            PUSH_PROMISE => Err(Error::Connection(PROTOCOL_ERROR)),
+            PING => {
+                if stream_id != 0 {
+                    return Err(Error::Connection(PROTOCOL_ERROR));
+                }
+                if payload.len() != 8 {
+                    return Err(Error::Connection(FRAME_SIZE_ERROR));
+                }
+                if flags & ACK == 0 {
+                    self.frame(PING, ACK, 0, payload);
+                }
+                Ok(())
+            },
+            // the client won't open more streams, the ones in progress are still answered
+            GOAWAY => match stream_id {
+                0 => Ok(()),
+                _ => Err(Error::Connection(PROTOCOL_ERROR)),
+            },
+            WINDOW_UPDATE => self.receive_window_update(stream_id, payload),
+            // unknown frames are ignored
+            _ => Ok(()),
+        }
+    }
+
+    /// Decodes a complete header block: a new request, or the trailers of one
+    fn end_headers(&mut self, requests: &mut Vec<(u32, HttpRequest)>) -> Result<(), Error> {
+        let Some(HeaderBlock { stream_id, end_stream, block }) = self.header_block.take() else { return Ok(()) };
+        // even the blocks of refused streams are decoded, the table of the decoder must stay in sync
+        let fields = self.decoder.decode(&block).map_err(|_| Error::Connection(COMPRESSION_ERROR))?;
+
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            // trailers end the request, they are left out of it
+            let request = stream.request.take().ok_or(Error::Stream(stream_id, STREAM_CLOSED))?;
+            if !end_stream {
+                return Err(Error::Stream(stream_id, PROTOCOL_ERROR));
+            }
+            let received = std::mem::take(&mut stream.received);
+            self.release(received);
+            return end_request(stream_id, request, requests);
+        }
+        if stream_id % 2 == 0 {
+            // the ids of the streams of the server, which never opens any
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        if stream_id <= self.last_stream_id {
+            // the stream is closed, e.g. it was reset
+            return Ok(());
+        }
+        self.last_stream_id = stream_id;
+        if self.going_away {
+            // past the last stream of GOAWAY, the client can retry it on a new connection
+            return Ok(());
+        }
+        if self.streams.len() >= MAX_CONCURRENT_STREAMS {
+            return Err(Error::Stream(stream_id, REFUSED_STREAM));
+        }
+        let request = build_request(fields).ok_or(Error::Stream(stream_id, PROTOCOL_ERROR))?;
+        let mut stream = Stream {
+            method: request.method,
+            request: None,
+            send_window: self.initial_window,
+            body: Vec::new(),
+            sent: 0,
+            received: 0,
+        };
+        if end_stream {
+            self.streams.insert(stream_id, stream);
+            return end_request(stream_id, request, requests);
+        }
+        let content_length = request.header_value("content-length").and_then(|value| value.parse::<usize>().ok());
+        stream.request = Some(request);
+        self.streams.insert(stream_id, stream);
+        if content_length.is_some_and(|len| len > self.max_body_size) {
+            self.body_too_large(stream_id);
+        }
+        Ok(())
+    }
+
+    fn receive_data(&mut self,
+                    flags: u8,
+                    stream_id: u32,
+                    payload: &[u8],
+                    requests: &mut Vec<(u32, HttpRequest)>) -> Result<(), Error> {
+        if stream_id == 0 || stream_id > self.last_stream_id {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        let data = strip_padding(flags, payload)?;
+        // the whole frame counts against flow control
+        self.receive_window -= payload.len() as i64;
+        if self.receive_window < 0 {
+            return Err(Error::Connection(FLOW_CONTROL_ERROR));
+        }
+        // ignored on a closed stream, e.g. the rest of a body after a reset,
+        // its room is given back right away
+        let Some(stream) = self.streams.get_mut(&stream_id) else {
+            self.release(payload.len());
+            return Ok(());
+        };
+        stream.received += payload.len();
+        let request = stream.request.as_mut().ok_or(Error::Stream(stream_id, STREAM_CLOSED))?;
+        if request.body.len() + data.len() > self.max_body_size {
+            self.body_too_large(stream_id);
+            return Ok(());
+        }
+        request.body.extend_from_slice(data);
+        if flags & END_STREAM != 0 {
+            let request = stream.request.take().unwrap();
+            let received = std::mem::take(&mut stream.received);
+            self.release(received);
+            return end_request(stream_id, request, requests);
+        }
+        // the body of a stream is bounded by its size limit, its window is given back right away
+        if !payload.is_empty() {
+            self.window_update(stream_id, payload.len());
+        }
+        Ok(())
+    }
+
+    fn receive_settings(&mut self, flags: u8, stream_id: u32, payload: &[u8]) -> Result<(), Error> {
+        if stream_id != 0 {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        if flags & ACK != 0 {
+            return match payload.len() {
+                0 => Ok(()),
+                _ => Err(Error::Connection(FRAME_SIZE_ERROR)),
+            };
+        }
+        if !payload.len().is_multiple_of(6) {
+            return Err(Error::Connection(FRAME_SIZE_ERROR));
+        }
+        for setting in payload.chunks(6) {
+            let id = u16::from_be_bytes([setting[0], setting[1]]);
+            let value = u32::from_be_bytes([setting[2], setting[3], setting[4], setting[5]]);
+            match id {
+                SETTINGS_ENABLE_PUSH if value > 1 => return Err(Error::Connection(PROTOCOL_ERROR)),
+                SETTINGS_INITIAL_WINDOW_SIZE => {
+                    let initial_window = value as i64;
+                    if initial_window > MAX_WINDOW_SIZE {
+                        return Err(Error::Connection(FLOW_CONTROL_ERROR));
+                    }
+                    // the windows of the open streams change by the difference
+                    for stream in self.streams.values_mut() {
+                        stream.send_window += initial_window - self.initial_window;
+                        if stream.send_window > MAX_WINDOW_SIZE {
+                            return Err(Error::Connection(FLOW_CONTROL_ERROR));
+                        }
+                    }
+                    self.initial_window = initial_window;
+                },
+                SETTINGS_MAX_FRAME_SIZE => {
+                    if !(MAX_FRAME_SIZE..=0xff_ffff).contains(&(value as usize)) {
+                        return Err(Error::Connection(PROTOCOL_ERROR));
+                    }
+                    self.max_frame_size = value as usize;
+                },
+                // the others don't change what the server sends, e.g. its
+                // encoder doesn't use the dynamic table
+                _ => (),
+            }
+        }
+        self.settings_received = true;
+        self.frame(SETTINGS, ACK, 0, &[]);
+        Ok(())
+    }
+
+    fn receive_window_update(&mut self, stream_id: u32, payload: &[u8]) -> Result<(), Error> {
+        if payload.len() != 4 {
+            return Err(Error::Connection(FRAME_SIZE_ERROR));
+        }
+        let increment = (u32::from_be_bytes([payload[0], payload[1], payload[2], payload[3]]) & 0x7fff_ffff) as i64;
+        if stream_id == 0 {
+            self.send_window += increment;
+            return match increment {
+                0 => Err(Error::Connection(PROTOCOL_ERROR)),
+                _ if self.send_window > MAX_WINDOW_SIZE => Err(Error::Connection(FLOW_CONTROL_ERROR)),
+                _ => Ok(()),
+            };
+        }
+        if stream_id > self.last_stream_id {
+            return Err(Error::Connection(PROTOCOL_ERROR));
+        }
+        let Some(stream) = self.streams.get_mut(&stream_id) else { return Ok(()) };
+        stream.send_window += increment;
+        match increment {
+            0 => Err(Error::Stream(stream_id, PROTOCOL_ERROR)),
+            _ if stream.send_window > MAX_WINDOW_SIZE => Err(Error::Stream(stream_id, FLOW_CONTROL_ERROR)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Sends as much of the bodies of the responses as the windows allow, the
+    /// streams are done once their whole body is sent
+    fn send_data(&mut self) {
+        let mut finished = Vec::new();
+        for (&stream_id, stream) in &mut self.streams {
+            while stream.sent < stream.body.len() {
+                let window = self.send_window.min(stream.send_window).max(0) as usize;
+                let len = (stream.body.len() - stream.sent).min(self.max_frame_size).min(window);
+                if len == 0 {
+                    break;
+                }
+                let end = stream.sent + len;
+                let flags = if end == stream.body.len() { END_STREAM } else { 0 };
+                write_frame(&mut self.output, DATA, flags, stream_id, &stream.body[stream.sent..end]);
+                stream.sent = end;
+                self.send_window -= len as i64;
+                stream.send_window -= len as i64;
+            }
+            if !stream.body.is_empty() && stream.sent == stream.body.len() {
+                finished.push(stream_id);
+            }
+        }
+        for stream_id in finished {
+            self.streams.remove(&stream_id);
+        }
+    }
+
+    /// Sends a header block, split into CONTINUATION frames if it's larger than a frame
+    fn headers(&mut self, stream_id: u32, block: &[u8], end_stream: bool) {
+        let mut fragments = block.chunks(self.max_frame_size).peekable();
+        let mut kind = HEADERS;
+        let mut flags = if end_stream { END_STREAM } else { 0 };
+        // an empty block still needs its HEADERS frame
+        let first = fragments.next().unwrap_or_default();
+        let mut fragment = Some(first);
+        while let Some(current) = fragment {
+            fragment = fragments.next();
+            if fragment.is_none() {
+                flags |= END_HEADERS;
+            }
+            write_frame(&mut self.output, kind, flags, stream_id, current);
+            kind = CONTINUATION;
+            flags = 0;
+        }
+    }
+
+    /// Answers a request whose body is too large without waiting for the rest
+    /// of it, and stops the client from sending it
+    fn body_too_large(&mut self, stream_id: u32) {
+        if let Some(stream) = self.streams.get_mut(&stream_id) {
+            stream.request = None;
+            let received = std::mem::take(&mut stream.received);
+            self.release(received);
+        }
+        self.respond(stream_id, HttpResponse::with_status(StatusCode::PAYLOAD_TOO_LARGE, None));
+        // with NO_ERROR since the client has its response (RFC 9113 8.1)
+        self.frame(RST_STREAM, 0, stream_id, &NO_ERROR.to_be_bytes());
+    }
+
+    /// Forgets a stream, what its request body took of the connection window is given back
+    fn close_stream(&mut self, stream_id: u32) {
+        if let Some(stream) = self.streams.remove(&stream_id) {
+            self.release(stream.received);
+        }
+    }
+
+    /// Gives room back to the client in the connection window
+    fn release(&mut self, len: usize) {
+        if len > 0 {
+            self.receive_window += len as i64;
+            self.window_update(0, len);
+        }
+    }
+
+    fn window_update(&mut self, stream_id: u32, increment: usize) {
+        self.frame(WINDOW_UPDATE, 0, stream_id, &(increment as u32).to_be_bytes());
+    }
+
+    fn connection_error(&mut self, code: u32) {
+        if !self.closed {
+            self.go_away_frame(code);
+            self.closed = true;
+            self.streams.clear();
+            self.header_block = None;
+        }
+    }
+
+    fn go_away_frame(&mut self, code: u32) {
+        let mut payload = self.last_stream_id.to_be_bytes().to_vec();
+        payload.extend_from_slice(&code.to_be_bytes());
+        self.frame(GOAWAY, 0, 0, &payload);
+    }
+
+    fn frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+        write_frame(&mut self.output, kind, flags, stream_id, payload);
+    }
+}
+
+fn write_frame(output: &mut Vec<u8>, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) {
+    output.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    output.push(kind);
+    output.push(flags);
+    output.extend_from_slice(&stream_id.to_be_bytes());
+    output.extend_from_slice(payload);
+}
+
+/// The payload of a DATA or HEADERS frame without its padding
+fn strip_padding(flags: u8, payload: &[u8]) -> Result<&[u8], Error> {
+    if flags & PADDED == 0 {
+        return Ok(payload);
+    }
+    let (&padding, rest) = payload.split_first().ok_or(Error::Connection(FRAME_SIZE_ERROR))?;
+    let len = rest.len().checked_sub(padding as usize).ok_or(Error::Connection(PROTOCOL_ERROR))?;
+    Ok(&rest[..len])
+}
+
+/// Checks the body against the `content-length` of the request, if any
+fn end_request(stream_id: u32, request: HttpRequest, requests: &mut Vec<(u32, HttpRequest)>) -> Result<(), Error> {
+    if let Some(content_length) = request.header_value("content-length") {
+        if content_length.parse::<usize>().ok() != Some(request.body.len()) {
+            return Err(Error::Stream(stream_id, PROTOCOL_ERROR));
+        }
+    }
+    requests.push((stream_id, request));
+    Ok(())
+}
+
+/// The request of the header fields, None if it's malformed. The header names
+/// are lowercase, `:authority` becomes the `host` header.
+fn build_request(fields: Vec<hpack::Field>) -> Option<HttpRequest> {
+    let (mut method, mut scheme, mut path, mut authority) = (None, None, None, None);
+    let mut headers: HashMap<String, String> = HashMap::new();
+    for (name, value) in fields {
+        let name = String::from_utf8(name).ok()?;
+        let value = String::from_utf8(value).ok()?;
+        if let Some(pseudo) = name.strip_prefix(':') {
+            // the pseudo-headers come first, once each
+            if !headers.is_empty() {
+                return None;
+            }
+            let slot = match pseudo {
+                "method" => &mut method,
+                "scheme" => &mut scheme,
+                "path" => &mut path,
+                "authority" => &mut authority,
+                _ => return None,
+            };
+            if slot.replace(value).is_some() {
+                return None;
+            }
+            continue;
+        }
+        if name.bytes().any(|byte| byte.is_ascii_uppercase())
+           || CONNECTION_HEADERS.contains(&name.as_str())
+           || (name == "te" && value != "trailers") {
+            return None;
+        }
+        // repeated fields are joined, the cookies may have been split to compress better
+        match headers.get_mut(&name) {
+            Some(joined) => {
+                joined.push_str(if name == "cookie" { "; " } else { ", " });
+                joined.push_str(&value);
+            },
+            None => {
+                headers.insert(name, value);
+            },
+        }
+    }
+    let (method, path) = (method?, path.filter(|path| !path.is_empty())?);
+    scheme?;
+    if let Some(authority) = authority {
+        headers.entry(String::from("host")).or_insert(authority);
+    }
+    Some(HttpRequest {
+        version: Version::V2,
+        method: method.as_str().into(),
+        resource: path,
+        header: headers,
+        ..HttpRequest::default()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bytes::Bytes;
+    use http::httpresponse::StatusCode;
+
+    /// A client that encodes its frames by hand
+    struct Client {
+        server: Connection,
+        received: Vec<u8>,
+    }
+
+    /// A frame the server sent
+    #[derive(Debug, PartialEq)]
+    struct Frame {
+        kind: u8,
+        flags: u8,
+        stream_id: u32,
+        payload: Vec<u8>,
+    }
+
+    impl Client {
+        fn new() -> Self {
+            Client::with_max_body_size(1_000)
+        }
+
+        fn with_max_body_size(max_body_size: usize) -> Self {
+            let mut client = Client { server: Connection::new(max_body_size), received: Vec::new() };
+            assert!(client.send(PREFACE).is_empty());
+            client
+        }
+
+        fn send(&mut self, bytes: &[u8]) -> Vec<(u32, HttpRequest)> {
+            let requests = self.server.receive(bytes);
+            self.received.extend(self.server.take_output());
+            requests
+        }
+
+        fn send_frame(&mut self, kind: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<(u32, HttpRequest)> {
+            let mut frame = Vec::new();
+            write_frame(&mut frame, kind, flags, stream_id, payload);
+            self.send(&frame)
+        }
+
+        fn settings(&mut self, settings: &[(u16, u32)]) {
+            let payload: Vec<u8> = settings.iter()
+                                           .flat_map(|(id, value)| [&id.to_be_bytes()[..], &value.to_be_bytes()].concat())
+                                           .collect();
+            self.send_frame(SETTINGS, 0, 0, &payload);
+        }
+
+        fn request(&mut self, stream_id: u32, fields: &[(&str, &str)], end_stream: bool) -> Vec<(u32, HttpRequest)> {
+            let flags = END_HEADERS | if end_stream { END_STREAM } else { 0 };
+            self.send_frame(HEADERS, flags, stream_id, &hpack::encode(fields))
+        }
+
+        fn respond(&mut self, stream_id: u32, response: HttpResponse) {
+            self.server.respond(stream_id, response);
+            self.received.extend(self.server.take_output());
+        }
+
+        /// The frames the server sent since the last call
+        fn frames(&mut self) -> Vec<Frame> {
+            let mut frames = Vec::new();
+            let mut rest = std::mem::take(&mut self.received);
+            while !rest.is_empty() {
+                let len = u32::from_be_bytes([0, rest[0], rest[1], rest[2]]) as usize;
+                let frame = rest.drain(..FRAME_HEADER_LEN + len).collect::<Vec<u8>>();
+                frames.push(Frame {
+                    kind: frame[3],
+                    flags: frame[4],
+                    stream_id: u32::from_be_bytes([frame[5], frame[6], frame[7], frame[8]]),
+                    payload: frame[FRAME_HEADER_LEN..].to_vec(),
+                });
+            }
+            frames
+        }
+    }
+
+    fn get(path: &str) -> Vec<(&str, &str)> {
+        vec![(":method", "GET"), (":scheme", "https"), (":path", path), (":authority", "example.com")]
+    }
+
+    fn decode(block: &[u8]) -> Vec<(String, String)> {
+        hpack::Decoder::default().decode(block)
+                                 .unwrap()
+                                 .into_iter()
+                                 .map(|(name, value)| (String::from_utf8(name).unwrap(), String::from_utf8(value).unwrap()))
+                                 .collect()
+    }
+
+    #[test]
+    fn test_settings_and_ping() {
+        let mut client = Client::new();
+        let settings = client.frames();
+        assert_eq!(settings, [
+            Frame { kind: SETTINGS, flags: 0, stream_id: 0, payload: vec![0, 3, 0, 0, 0, 100] },
+            // room for a body past the limit of 1000 bytes
+            Frame { kind: WINDOW_UPDATE, flags: 0, stream_id: 0, payload: 1000u32.to_be_bytes().to_vec() },
+        ]);
+
+        client.settings(&[(SETTINGS_ENABLE_PUSH, 0), (SETTINGS_MAX_FRAME_SIZE, 32_768)]);
+        assert_eq!(client.frames(), [Frame { kind: SETTINGS, flags: ACK, stream_id: 0, payload: Vec::new() }]);
+        assert_eq!(client.server.max_frame_size, 32_768);
+
+        client.send_frame(PING, 0, 0, b"12345678");
+        assert_eq!(client.frames(), [Frame { kind: PING, flags: ACK, stream_id: 0, payload: b"12345678".to_vec() }]);
+        // acknowledgments aren't answered, unknown frames are ignored
+        client.send_frame(PING, ACK, 0, b"12345678");
+        client.send_frame(0xfa, 0, 0, b"?");
+        assert!(client.frames().is_empty());
+
+        // the first frame must be SETTINGS
+        let mut client = Client::new();
+        client.send_frame(PING, 0, 0, b"12345678");
+        assert_eq!(client.frames().last().unwrap().payload, [0, 0, 0, 0, 0, 0, 0, PROTOCOL_ERROR as u8]);
+        assert!(client.server.is_closed());
+
+        let mut client = Client::new();
+        client.send(b"GET / HTTP/1.1\r\n\r\n");
+        assert!(client.server.is_closed());
+    }
+
+    #[test]
+    fn test_requests() {
+        let mut client = Client::new();
+        client.settings(&[]);
+        client.frames();
+
+        let mut fields = get("/users?page=2");
+        fields.extend([("cookie", "a=1"), ("accept", "text/html"), ("cookie", "b=2")]);
+        let requests = client.request(1, &fields, true);
+        assert_eq!(requests.len(), 1);
+        let (stream_id, request) = &requests[0];
+        assert_eq!(*stream_id, 1);
+        assert_eq!(request.version, Version::V2);
+        assert_eq!(request.method, Method::Get);
+        assert_eq!(request.path(), "/users");
+        assert_eq!(request.query(), Some("page=2"));
+        assert_eq!(request.header_value("Host"), Some("example.com"));
+        assert_eq!(request.header_value("cookie"), Some("a=1; b=2"));
+
+        // a body in two DATA frames, the second one padded
+        let mut fields = get("/echo");
+        fields[0] = (":method", "POST");
+        fields.push(("content-length", "11"));
+        assert!(client.request(3, &fields, false).is_empty());
+        assert!(client.send_frame(DATA, 0, 3, b"hello ").is_empty());
+        let requests = client.send_frame(DATA, END_STREAM | PADDED, 3, b"\x02world\0\0");
+        assert_eq!(requests[0].1.body, b"hello world");
+        // the stream window gets its room back right away, the connection
+        // window once the body is handed over
+        let updates: Vec<(u32, Vec<u8>)> = client.frames().into_iter()
+                                                 .map(|frame| (frame.stream_id, frame.payload))
+                                                 .collect();
+        assert_eq!(updates, [(3, vec![0, 0, 0, 6]), (0, vec![0, 0, 0, 14])]);
+
+        // a header block split into a CONTINUATION
+        let block = hpack::encode(&get("/split"));
+        client.send_frame(HEADERS, END_STREAM, 5, &block[..3]);
+        let requests = client.send_frame(CONTINUATION, END_HEADERS, 5, &block[3..]);
+        assert_eq!(requests[0].1.path(), "/split");
+        assert!(client.server.has_streams());
+    }
+
+    #[test]
+    fn test_malformed_requests() {
+        let mut client = Client::new();
+        client.settings(&[]);
+        client.frames();
+
+        let malformed: [&[(&str, &str)]; 5] = [
+            &[(":method", "GET"), (":path", "/")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/"), ("Accept", "*/*")],
+            &[(":method", "GET"), (":scheme", "https"), ("accept", "*/*"), (":path", "/")],
+            &[(":method", "GET"), (":scheme", "https"), (":path", "/"), ("connection", "keep-alive")],
+            &[(":method", "POST"), (":scheme", "https"), (":path", "/"), ("content-length", "3")],
+        ];
+        for (i, fields) in malformed.iter().enumerate() {
+            let stream_id = 2 * i as u32 + 1;
+            assert!(client.request(stream_id, fields, true).is_empty());
+            let reset = Frame { kind: RST_STREAM, flags: 0, stream_id, payload: PROTOCOL_ERROR.to_be_bytes().to_vec() };
+            assert_eq!(client.frames(), [reset]);
+        }
+        // the connection goes on
+        assert_eq!(client.request(11, &get("/"), true).len(), 1);
+
+        // an even stream is a connection error
+        client.request(12, &get("/"), true);
+        assert_eq!(client.frames()[0].kind, GOAWAY);
+        assert!(client.server.is_closed());
+    }
+
+    #[test]
+    fn test_responses() {
+        let mut client = Client::new();
+        client.settings(&[]);
+        client.request(1, &get("/"), true);
+        let mut head = get("/");
+        head[0] = (":method", "HEAD");
+        client.request(3, &head, true);
+        client.frames();
+
+        let response = HttpResponse::with_status(StatusCode::OK, Some(b"hello".to_vec())).with_header("Connection", "close")
+                                                                     .with_header("X-Id", "7");
+        client.respond(3, response.clone());
+        client.respond(1, response);
+        let frames = client.frames();
+        // the HEAD response has no body but its length
+        assert_eq!((frames[0].kind, frames[0].flags, frames[0].stream_id), (HEADERS, END_HEADERS | END_STREAM, 3));
+        let mut fields = decode(&frames[0].payload);
+        assert_eq!(fields.remove(0), (String::from(":status"), String::from("200")));
+        fields.sort();
+        assert_eq!(fields, [(String::from("content-length"), String::from("5")), (String::from("x-id"), String::from("7"))]);
+        assert_eq!((frames[1].kind, frames[1].flags, frames[1].stream_id), (HEADERS, END_HEADERS, 1));
+        assert_eq!(frames[2], Frame { kind: DATA, flags: END_STREAM, stream_id: 1, payload: b"hello".to_vec() });
+        assert!(!client.server.has_streams());
+
+        // a response to a stream that was reset is dropped
+        client.request(5, &get("/"), true);
+        client.send_frame(RST_STREAM, 0, 5, &8u32.to_be_bytes());
+        client.respond(5, HttpResponse::with_status(StatusCode::OK, Some(b"late".to_vec())));
+        assert!(client.frames().is_empty());
+    }
+
+    #[test]
+    fn test_flow_control() {
+        let mut client = Client::new();
+        client.settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 10)]);
+        client.request(1, &get("/"), true);
+        client.request(3, &get("/"), true);
+        client.frames();
+
+        let body = b"0123456789abcdefghij";
+        client.respond(1, HttpResponse::with_status(StatusCode::OK, Some(body.to_vec())));
+        client.respond(3, HttpResponse::with_status(StatusCode::OK, Some(body.to_vec())));
+        let data: Vec<(u32, Vec<u8>)> = client.frames().into_iter()
+                                              .filter(|frame| frame.kind == DATA)
+                                              .map(|frame| (frame.stream_id, frame.payload))
+                                              .collect();
+        assert_eq!(data, [(1, b"0123456789".to_vec()), (3, b"0123456789".to_vec())]);
+
+        // a larger initial window applies to the open streams
+        client.settings(&[(SETTINGS_INITIAL_WINDOW_SIZE, 15)]);
+        let data: Vec<(u32, u8, Vec<u8>)> = client.frames().into_iter()
+                                                  .filter(|frame| frame.kind == DATA)
+                                                  .map(|frame| (frame.stream_id, frame.flags, frame.payload))
+                                                  .collect();
+        assert_eq!(data, [(1, 0, b"abcde".to_vec()), (3, 0, b"abcde".to_vec())]);
+
+        client.send_frame(WINDOW_UPDATE, 0, 3, &100u32.to_be_bytes());
+        assert_eq!(client.frames(), [Frame { kind: DATA, flags: END_STREAM, stream_id: 3, payload: b"fghij".to_vec() }]);
+        assert!(client.server.has_streams());
+
+        // the connection window is shared by the streams
+        client.send_frame(WINDOW_UPDATE, 0, 1, &100u32.to_be_bytes());
+        client.request(5, &get("/"), true);
+        client.send_frame(WINDOW_UPDATE, 0, 5, &100_000u32.to_be_bytes());
+        client.frames();
+        client.respond(5, HttpResponse::with_status(StatusCode::OK, Some(vec![b'x'; 70_000])));
+        let sent: usize = client.frames().iter().filter(|frame| frame.kind == DATA).map(|frame| frame.payload.len()).sum();
+        assert_eq!(sent, 65_535 - 40);
+
+        // a window past 2^31 - 1 resets the stream, or ends the connection
+        client.send_frame(WINDOW_UPDATE, 0, 5, &0x7fff_ffffu32.to_be_bytes());
+        assert_eq!(client.frames(), [Frame { kind: RST_STREAM, flags: 0, stream_id: 5, payload: FLOW_CONTROL_ERROR.to_be_bytes().to_vec() }]);
+        client.send_frame(WINDOW_UPDATE, 0, 0, &0x7fff_ffffu32.to_be_bytes());
+        assert!(!client.server.is_closed());
+        client.send_frame(WINDOW_UPDATE, 0, 0, &1u32.to_be_bytes());
+        assert_eq!(client.frames()[0].payload[4..], FLOW_CONTROL_ERROR.to_be_bytes());
+        assert!(client.server.is_closed());
+    }
+
+    #[test]
+    fn test_body_size() {
+        let mut client = Client::new();
+        client.settings(&[]);
+        client.frames();
+        let mut fields = get("/upload");
+        fields[0] = (":method", "POST");
+
+        // a declared length past the limit is answered right away
+        let mut declared = fields.clone();
+        declared.push(("content-length", "2000"));
+        assert!(client.request(1, &declared, false).is_empty());
+        let frames = client.frames();
+        assert_eq!((frames[0].kind, frames[0].flags), (HEADERS, END_HEADERS | END_STREAM));
+        assert_eq!(decode(&frames[0].payload)[0], (String::from(":status"), String::from("413")));
+        assert_eq!(frames[1], Frame { kind: RST_STREAM, flags: 0, stream_id: 1, payload: NO_ERROR.to_be_bytes().to_vec() });
+        // what the client sent before it saw the reset is dropped
+        client.send_frame(DATA, 0, 1, &[b'x'; 600]);
+        assert_eq!(client.frames(), [Frame { kind: WINDOW_UPDATE, flags: 0, stream_id: 0, payload: vec![0, 0, 2, 88] }]);
+
+        // so is a body that turns out too large
+        client.request(3, &fields, false);
+        client.send_frame(DATA, 0, 3, &[b'x'; 600]);
+        client.frames();
+        assert!(client.send_frame(DATA, END_STREAM, 3, &[b'x'; 600]).is_empty());
+        let frames = client.frames();
+        let kinds: Vec<(u8, u32)> = frames.iter().map(|frame| (frame.kind, frame.stream_id)).collect();
+        assert_eq!(kinds, [(WINDOW_UPDATE, 0), (HEADERS, 3), (RST_STREAM, 3)]);
+        assert_eq!(frames[0].payload, 1200u32.to_be_bytes());
+        assert!(!client.server.has_streams());
+    }
+
+    #[test]
+    fn test_receive_window() {
+        let mut client = Client::with_max_body_size(2 * DEFAULT_WINDOW_SIZE as usize);
+        // the connection window grows to fit a whole body
+        let frames = client.frames();
+        assert_eq!(frames[1], Frame { kind: WINDOW_UPDATE, flags: 0, stream_id: 0, payload: 131_070u32.to_be_bytes().to_vec() });
+        client.settings(&[]);
+        client.frames();
+        let mut fields = get("/upload");
+        fields[0] = (":method", "POST");
+
+        // the bodies being received hold on to their room in the connection window
+        client.request(1, &fields, false);
+        for _ in 0..7 {
+            client.send_frame(DATA, 0, 1, &[b'x'; MAX_FRAME_SIZE]);
+        }
+        assert!(client.frames().iter().all(|frame| frame.stream_id == 1));
+        let requests = client.send_frame(DATA, END_STREAM, 1, &[b'x'; 10]);
+        assert_eq!(requests[0].1.body.len(), 7 * MAX_FRAME_SIZE + 10);
+        let released = (7 * MAX_FRAME_SIZE as u32 + 10).to_be_bytes().to_vec();
+        assert_eq!(client.frames(), [Frame { kind: WINDOW_UPDATE, flags: 0, stream_id: 0, payload: released }]);
+
+        // a client that sends past the window ends the connection, the
+        // bodies are smaller than the limit on their own
+        client.request(3, &fields, false);
+        client.request(5, &fields, false);
+        for stream_id in [3, 3, 3, 3, 3, 3, 3, 5, 5, 5, 5, 5] {
+            assert!(!client.server.is_closed());
+            client.send_frame(DATA, 0, stream_id, &[b'x'; MAX_FRAME_SIZE]);
+        }
+        let frames = client.frames();
+        assert_eq!(frames.last().unwrap().kind, GOAWAY);
+        assert_eq!(frames.last().unwrap().payload[4..], FLOW_CONTROL_ERROR.to_be_bytes());
+        assert!(client.server.is_closed());
+    }
+
+    #[test]
+    fn test_go_away() {
+        let mut client = Client::new();
+        client.settings(&[]);
+        client.request(1, &get("/"), true);
+        client.frames();
+
+        client.server.go_away();
+        assert!(!client.server.is_closed());
+        // streams opened after GOAWAY are ignored, the one in progress is still answered
+        assert!(client.request(3, &get("/"), true).is_empty());
+        client.respond(1, HttpResponse::with_status(StatusCode::NO_CONTENT, None));
+        let frames = client.frames();
+        assert_eq!(frames[0], Frame { kind: GOAWAY, flags: 0, stream_id: 0, payload: vec![0, 0, 0, 1, 0, 0, 0, 0] });
+        assert_eq!((frames[1].kind, frames[1].flags), (HEADERS, END_HEADERS | END_STREAM));
+        assert!(client.server.is_closed());
+    }
+
+    #[test]
+    fn test_concurrent_streams() {
+        let mut client = Client::new();
+        client.settings(&[]);
+        for i in 0..MAX_CONCURRENT_STREAMS as u32 {
+            assert_eq!(client.request(2 * i + 1, &get("/"), true).len(), 1);
+        }
+        client.frames();
+        assert!(client.request(201, &get("/"), true).is_empty());
+        assert_eq!(client.frames()[0].payload, REFUSED_STREAM.to_be_bytes());
+    }
+
+    /// Sends a request with the h2 client and reads the whole response
+    async fn send(client: &mut h2::client::SendRequest<Bytes>,
+                  method: &str,
+                  path: &str,
+                  body: &[u8]) -> (u16, http1::HeaderMap, Vec<u8>) {
+        let request = http1::Request::builder().method(method)
+                                               .uri(format!("http://localhost{}", path))
+                                               .body(())
+                                               .unwrap();
+        let (response, mut request_body) = client.send_request(request, body.is_empty()).unwrap();
+        if !body.is_empty() {
+            // h2 holds the data back until the server's window allows it
+            request_body.send_data(Bytes::copy_from_slice(body), true).unwrap();
+        }
+        let (parts, mut response_body) = response.await.unwrap().into_parts();
+        let mut received = Vec::new();
+        while let Some(data) = response_body.data().await {
+            let data = data.unwrap();
+            response_body.flow_control().release_capacity(data.len()).unwrap();
+            received.extend_from_slice(&data);
+        }
+        (parts.status.as_u16(), parts.headers, received)
+    }
+
+    #[test]
+    fn test_h2_overload() {
+        use std::sync::{mpsc, Mutex};
+        use std::time::Duration;
+        use crate::pool::OverloadPolicy;
+        use crate::server::{Server, HTTP2_STREAM_WORKERS};
+        use crate::testing;
+
+        // the handlers of a connection of the Threads backend are bounded
+        let server = Server::new("127.0.0.1:0").with_overload_policy(OverloadPolicy::Reject);
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        server.get("/wait", move |_: &HttpRequest| {
+            let _ = released.lock().unwrap().recv_timeout(Duration::from_secs(5));
+            "released"
+        });
+        let (handle, addr, running) = testing::start(server);
+
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        runtime.block_on(async {
+            let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let (client, connection) = h2::client::handshake(stream).await.unwrap();
+            tokio::spawn(connection);
+            let client = client.ready().await.unwrap();
+
+            // as many streams as there are workers of the connection and room in their queue
+            let mut waiting = Vec::new();
+            for _ in 0..2 * HTTP2_STREAM_WORKERS {
+                let mut client = client.clone();
+                waiting.push(tokio::spawn(async move { send(&mut client, "GET", "/wait", b"").await.0 }));
+                tokio::time::sleep(Duration::from_millis(20)).await;
+            }
+            // the next ones are refused right away
+            for _ in 0..2 {
+                assert_eq!(send(&mut client.clone(), "GET", "/wait", b"").await.0, 503);
+            }
+            drop(release);
+            for waiting in waiting {
+                assert_eq!(waiting.await.unwrap(), 200);
+            }
+        });
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn test_h2_client() {
+        use std::sync::{mpsc, Mutex};
+        use std::time::Duration;
+        use crate::server::Server;
+        use crate::testing::{self, BACKENDS};
+
+        for &backend in BACKENDS {
+            let server = Server::new("127.0.0.1:0").with_backend(backend).with_workers(4).with_max_body_size(300_000);
+            server.get("/hello/{name}", |req: &HttpRequest| format!("Hello {}!", req.path_params["name"]));
+            server.post("/echo", |req: &HttpRequest| req.body.clone());
+            server.sse("/events", |_: &HttpRequest, _: crate::sse::EventStream| {});
+            // a request that waits for the next one, which only works if they're multiplexed
+            let (release, released) = mpsc::channel();
+            let released = Mutex::new(released);
+            server.get("/wait", move |_: &HttpRequest| {
+                released.lock().unwrap().recv_timeout(Duration::from_secs(5)).map_or("timed out", |_| "released")
+            });
+            let release = Mutex::new(release);
+            server.get("/release", move |_: &HttpRequest| {
+                release.lock().unwrap().send(()).unwrap();
+                "done"
+            });
+            let (handle, addr, running) = testing::start(server);
+
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            runtime.block_on(async {
+                let stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let (client, connection) = h2::client::handshake(stream).await.unwrap();
+                let connection = tokio::spawn(connection);
+                let mut client = client.ready().await.unwrap();
+
+                let (status, headers, body) = send(&mut client, "GET", "/hello/ann", b"").await;
+                assert_eq!(status, 200, "{:?}", backend);
+                assert_eq!(headers["content-length"], "10");
+                assert_eq!(body, b"Hello ann!");
+
+                let (status, headers, body) = send(&mut client, "HEAD", "/hello/ann", b"").await;
+                assert_eq!(status, 200);
+                assert_eq!(headers["content-length"], "10");
+                assert!(body.is_empty());
+                assert_eq!(send(&mut client, "GET", "/missing", b"").await.0, 404);
+
+                // larger than the windows in both directions
+                let large: Vec<u8> = (0..200_000u32).map(|i| i as u8).collect();
+                let (status, _, body) = send(&mut client, "POST", "/echo", &large).await;
+                assert_eq!(status, 200);
+                assert_eq!(body, large);
+                let too_large = vec![b'x'; 400_000];
+                assert_eq!(send(&mut client, "POST", "/echo", &too_large).await.0, 413);
+
+                // routes that take over the connection need HTTP/1.1
+                let request = http1::Request::builder().uri("http://localhost/events").body(()).unwrap();
+                let (response, _) = client.send_request(request, true).unwrap();
+                assert_eq!(response.await.unwrap_err().reason(), Some(h2::Reason::HTTP_1_1_REQUIRED));
+
+                let mut waiting_client = client.clone();
+                let waiting = tokio::spawn(async move { send(&mut waiting_client, "GET", "/wait", b"").await });
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                assert_eq!(send(&mut client, "GET", "/release", b"").await.2, b"done");
+                assert_eq!(waiting.await.unwrap().2, b"released");
+
+                // the server tells the client to go away once it shuts down
+                handle.shutdown();
+                drop(client);
+                tokio::time::timeout(Duration::from_secs(5), connection).await.unwrap().unwrap().unwrap();
+            });
+            running.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,447 @@
+//! HPACK (RFC 7541), the compression of the header fields of HTTP/2. The
+//! decoder supports the whole format, the encoder only sends literals and the
+//! entries of the static table, so it never needs a dynamic table.
+
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+
+/// A header field as it is sent, names and values aren't necessarily UTF-8
+pub(crate) type Field = (Vec<u8>, Vec<u8>);
+
+/// The header block can't be decoded, a connection error
+#[derive(Debug, PartialEq)]
+pub(crate) struct DecodeError;
+
+/// The size of the dynamic table the decoder allows, the default of `SETTINGS_HEADER_TABLE_SIZE`
+const MAX_TABLE_SIZE: usize = 4096;
+
+/// Every entry of the dynamic table costs that much on top of its name and value
+const ENTRY_OVERHEAD: usize = 32;
+
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+/// The Huffman code of every byte and of the end of string, as (code, length in bits)
+const HUFFMAN_CODES: [(u32, u8); 257] = [
+    (0x1ff8, 13), (0x7fffd8, 23), (0xfffffe2, 28), (0xfffffe3, 28), (0xfffffe4, 28), (0xfffffe5, 28),
+    (0xfffffe6, 28), (0xfffffe7, 28), (0xfffffe8, 28), (0xffffea, 24), (0x3ffffffc, 30), (0xfffffe9, 28),
+    (0xfffffea, 28), (0x3ffffffd, 30), (0xfffffeb, 28), (0xfffffec, 28), (0xfffffed, 28), (0xfffffee, 28),
+    (0xfffffef, 28), (0xffffff0, 28), (0xffffff1, 28), (0xffffff2, 28), (0x3ffffffe, 30), (0xffffff3, 28),
+    (0xffffff4, 28), (0xffffff5, 28), (0xffffff6, 28), (0xffffff7, 28), (0xffffff8, 28), (0xffffff9, 28),
+    (0xffffffa, 28), (0xffffffb, 28), (0x14, 6), (0x3f8, 10), (0x3f9, 10), (0xffa, 12),
+    (0x1ff9, 13), (0x15, 6), (0xf8, 8), (0x7fa, 11), (0x3fa, 10), (0x3fb, 10),
+    (0xf9, 8), (0x7fb, 11), (0xfa, 8), (0x16, 6), (0x17, 6), (0x18, 6),
+    (0x0, 5), (0x1, 5), (0x2, 5), (0x19, 6), (0x1a, 6), (0x1b, 6),
+    (0x1c, 6), (0x1d, 6), (0x1e, 6), (0x1f, 6), (0x5c, 7), (0xfb, 8),
+    (0x7ffc, 15), (0x20, 6), (0xffb, 12), (0x3fc, 10), (0x1ffa, 13), (0x21, 6),
+    (0x5d, 7), (0x5e, 7), (0x5f, 7), (0x60, 7), (0x61, 7), (0x62, 7),
+    (0x63, 7), (0x64, 7), (0x65, 7), (0x66, 7), (0x67, 7), (0x68, 7),
+    (0x69, 7), (0x6a, 7), (0x6b, 7), (0x6c, 7), (0x6d, 7), (0x6e, 7),
+    (0x6f, 7), (0x70, 7), (0x71, 7), (0x72, 7), (0xfc, 8), (0x73, 7),
+    (0xfd, 8), (0x1ffb, 13), (0x7fff0, 19), (0x1ffc, 13), (0x3ffc, 14), (0x22, 6),
+    (0x7ffd, 15), (0x3, 5), (0x23, 6), (0x4, 5), (0x24, 6), (0x5, 5),
+    (0x25, 6), (0x26, 6), (0x27, 6), (0x6, 5), (0x74, 7), (0x75, 7),
+    (0x28, 6), (0x29, 6), (0x2a, 6), (0x7, 5), (0x2b, 6), (0x76, 7),
+    (0x2c, 6), (0x8, 5), (0x9, 5), (0x2d, 6), (0x77, 7), (0x78, 7),
+    (0x79, 7), (0x7a, 7), (0x7b, 7), (0x7ffe, 15), (0x7fc, 11), (0x3ffd, 14),
+    (0x1ffd, 13), (0xffffffc, 28), (0xfffe6, 20), (0x3fffd2, 22), (0xfffe7, 20), (0xfffe8, 20),
+    (0x3fffd3, 22), (0x3fffd4, 22), (0x3fffd5, 22), (0x7fffd9, 23), (0x3fffd6, 22), (0x7fffda, 23),
+    (0x7fffdb, 23), (0x7fffdc, 23), (0x7fffdd, 23), (0x7fffde, 23), (0xffffeb, 24), (0x7fffdf, 23),
+    (0xffffec, 24), (0xffffed, 24), (0x3fffd7, 22), (0x7fffe0, 23), (0xffffee, 24), (0x7fffe1, 23),
+    (0x7fffe2, 23), (0x7fffe3, 23), (0x7fffe4, 23), (0x1fffdc, 21), (0x3fffd8, 22), (0x7fffe5, 23),
+    (0x3fffd9, 22), (0x7fffe6, 23), (0x7fffe7, 23), (0xffffef, 24), (0x3fffda, 22), (0x1fffdd, 21),
+    (0xfffe9, 20), (0x3fffdb, 22), (0x3fffdc, 22), (0x7fffe8, 23), (0x7fffe9, 23), (0x1fffde, 21),
+    (0x7fffea, 23), (0x3fffdd, 22), (0x3fffde, 22), (0xfffff0, 24), (0x1fffdf, 21), (0x3fffdf, 22),
+    (0x7fffeb, 23), (0x7fffec, 23), (0x1fffe0, 21), (0x1fffe1, 21), (0x3fffe0, 22), (0x1fffe2, 21),
+    (0x7fffed, 23), (0x3fffe1, 22), (0x7fffee, 23), (0x7fffef, 23), (0xfffea, 20), (0x3fffe2, 22),
+    (0x3fffe3, 22), (0x3fffe4, 22), (0x7ffff0, 23), (0x3fffe5, 22), (0x3fffe6, 22), (0x7ffff1, 23),
+    (0x3ffffe0, 26), (0x3ffffe1, 26), (0xfffeb, 20), (0x7fff1, 19), (0x3fffe7, 22), (0x7ffff2, 23),
+    (0x3fffe8, 22), (0x1ffffec, 25), (0x3ffffe2, 26), (0x3ffffe3, 26), (0x3ffffe4, 26), (0x7ffffde, 27),
+    (0x7ffffdf, 27), (0x3ffffe5, 26), (0xfffff1, 24), (0x1ffffed, 25), (0x7fff2, 19), (0x1fffe3, 21),
+    (0x3ffffe6, 26), (0x7ffffe0, 27), (0x7ffffe1, 27), (0x3ffffe7, 26), (0x7ffffe2, 27), (0xfffff2, 24),
+    (0x1fffe4, 21), (0x1fffe5, 21), (0x3ffffe8, 26), (0x3ffffe9, 26), (0xffffffd, 28), (0x7ffffe3, 27),
+    (0x7ffffe4, 27), (0x7ffffe5, 27), (0xfffec, 20), (0xfffff3, 24), (0xfffed, 20), (0x1fffe6, 21),
+    (0x3fffe9, 22), (0x1fffe7, 21), (0x1fffe8, 21), (0x7ffff3, 23), (0x3fffea, 22), (0x3fffeb, 22),
+    (0x1ffffee, 25), (0x1ffffef, 25), (0xfffff4, 24), (0xfffff5, 24), (0x3ffffea, 26), (0x7ffff4, 23),
+    (0x3ffffeb, 26), (0x7ffffe6, 27), (0x3ffffec, 26), (0x3ffffed, 26), (0x7ffffe7, 27), (0x7ffffe8, 27),
+    (0x7ffffe9, 27), (0x7ffffea, 27), (0x7ffffeb, 27), (0xffffffe, 28), (0x7ffffec, 27), (0x7ffffed, 27),
+    (0x7ffffee, 27), (0x7ffffef, 27), (0x7fffff0, 27), (0x3ffffee, 26), (0x3fffffff, 30),
+];
+
+const EOS: u16 = 256;
+
+/// Decodes the header blocks of a connection, which share the dynamic table
+#[derive(Debug)]
+pub(crate) struct Decoder {
+    table: VecDeque<Field>, // the newest entry first
+    table_size: usize,
+    max_table_size: usize, // as last set by the encoder, up to MAX_TABLE_SIZE
+}
+
+impl Default for Decoder {
+    fn default() -> Self {
+        Decoder {
+            table: VecDeque::new(),
+            table_size: 0,
+            max_table_size: MAX_TABLE_SIZE,
+        }
+    }
+}
+
+impl Decoder {
+    pub(crate) fn decode(&mut self, mut block: &[u8]) -> Result<Vec<Field>, DecodeError> {
+        let mut fields = Vec::new();
+        while let Some(&first) = block.first() {
+            if first & 0x80 != 0 {
+                // indexed field
+                let index = decode_integer(&mut block, 7)?;
+                fields.push(self.entry(index)?.clone());
+            } else if first & 0xc0 == 0x40 {
+                // literal with incremental indexing
+                let field = self.decode_literal(&mut block, 6)?;
+                self.insert(field.clone());
+                fields.push(field);
+            } else if first & 0xe0 == 0x20 {
+                // dynamic table size update, only allowed before the first field
+                if !fields.is_empty() {
+                    return Err(DecodeError);
+                }
+                let max_table_size = decode_integer(&mut block, 5)?;
+                if max_table_size > MAX_TABLE_SIZE {
+                    return Err(DecodeError);
+                }
+                self.max_table_size = max_table_size;
+                self.evict(0);
+            } else {
+                // literal without indexing or never indexed
+                fields.push(self.decode_literal(&mut block, 4)?);
+            }
+        }
+        Ok(fields)
+    }
+
+    fn decode_literal(&self, block: &mut &[u8], prefix: u8) -> Result<Field, DecodeError> {
+        let name = match decode_integer(block, prefix)? {
+            0 => decode_string(block)?,
+            index => self.entry(index)?.0.clone(),
+        };
+        Ok((name, decode_string(block)?))
+    }
+
+    /// Index 1 is the first entry of the static table, the dynamic table follows
+    fn entry(&self, index: usize) -> Result<&Field, DecodeError> {
+        static STATIC_FIELDS: OnceLock<Vec<Field>> = OnceLock::new();
+        let static_fields = STATIC_FIELDS.get_or_init(|| {
+            STATIC_TABLE.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec())).collect()
+        });
+        match index {
+            0 => Err(DecodeError),
+            index if index <= static_fields.len() => Ok(&static_fields[index - 1]),
+            index => self.table.get(index - static_fields.len() - 1).ok_or(DecodeError),
+        }
+    }
+
+    fn insert(&mut self, field: Field) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // an entry larger than the table empties it and isn't added
+        if size <= self.max_table_size {
+            self.table_size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    /// Drops the oldest entries until there is room for `size` more bytes
+    fn evict(&mut self, size: usize) {
+        while self.table_size + size > self.max_table_size {
+            let Some((name, value)) = self.table.pop_back() else { break };
+            self.table_size -= name.len() + value.len() + ENTRY_OVERHEAD;
+        }
+    }
+}
+
+/// Encodes the header fields of a response. The fields of the static table are
+/// sent as their index, the others as literals that aren't indexed.
+pub(crate) fn encode(fields: &[(&str, &str)]) -> Vec<u8> {
+    let mut block = Vec::new();
+    for (name, value) in fields {
+        if let Some(index) = STATIC_TABLE.iter().position(|field| field == &(*name, *value)) {
+            encode_integer(&mut block, 0x80, 7, index + 1);
+            continue;
+        }
+        match STATIC_TABLE.iter().position(|(static_name, _)| static_name == name) {
+            Some(index) => encode_integer(&mut block, 0x00, 4, index + 1),
+            None => {
+                block.push(0x00);
+                encode_string(&mut block, name.as_bytes());
+            },
+        }
+        encode_string(&mut block, value.as_bytes());
+    }
+    block
+}
+
+/// Reads an integer whose first byte keeps `prefix` bits for it
+fn decode_integer(block: &mut &[u8], prefix: u8) -> Result<usize, DecodeError> {
+    let (&first, rest) = block.split_first().ok_or(DecodeError)?;
+    *block = rest;
+    let max_prefix = (1usize << prefix) - 1;
+    let mut value = first as usize & max_prefix;
+    if value < max_prefix {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or(DecodeError)?;
+        *block = rest;
+        // more is a lot larger than any table or string
+        if shift > 21 {
+            return Err(DecodeError);
+        }
+        value += (byte as usize & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn encode_integer(block: &mut Vec<u8>, flags: u8, prefix: u8, mut value: usize) {
+    let max_prefix = (1usize << prefix) - 1;
+    if value < max_prefix {
+        block.push(flags | value as u8);
+        return;
+    }
+    block.push(flags | max_prefix as u8);
+    value -= max_prefix;
+    while value >= 0x80 {
+        block.push((value & 0x7f) as u8 | 0x80);
+        value >>= 7;
+    }
+    block.push(value as u8);
+}
+
+fn decode_string(block: &mut &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let huffman = block.first().ok_or(DecodeError)? & 0x80 != 0;
+    let len = decode_integer(block, 7)?;
+    if block.len() < len {
+        return Err(DecodeError);
+    }
+    let (string, rest) = block.split_at(len);
+    *block = rest;
+    match huffman {
+        true => decode_huffman(string),
+        false => Ok(string.to_vec()),
+    }
+}
+
+/// Strings are always sent as they are, without Huffman coding
+fn encode_string(block: &mut Vec<u8>, string: &[u8]) {
+    encode_integer(block, 0x00, 7, string.len());
+    block.extend_from_slice(string);
+}
+
+/// A node of the tree of the Huffman codes, a leaf holds a symbol
+#[derive(Clone, Copy, Default)]
+struct Node {
+    children: [Option<u16>; 2],
+    symbol: Option<u16>,
+}
+
+fn huffman_tree() -> &'static [Node] {
+    static TREE: OnceLock<Vec<Node>> = OnceLock::new();
+    TREE.get_or_init(|| {
+        let mut tree = vec![Node::default()];
+        for (symbol, &(code, len)) in HUFFMAN_CODES.iter().enumerate() {
+            let mut node = 0;
+            for bit in (0..len).rev() {
+                let bit = (code >> bit) as usize & 1;
+                node = match tree[node].children[bit] {
+                    Some(child) => child as usize,
+                    None => {
+                        tree.push(Node::default());
+                        let child = tree.len() - 1;
+                        tree[node].children[bit] = Some(child as u16);
+                        child
+                    },
+                };
+            }
+            tree[node].symbol = Some(symbol as u16);
+        }
+        tree
+    })
+}
+
+fn decode_huffman(string: &[u8]) -> Result<Vec<u8>, DecodeError> {
+    let tree = huffman_tree();
+    let mut decoded = Vec::with_capacity(string.len() * 2);
+    let mut node = 0;
+    // the bits read since the last symbol, they must be the start of EOS at the end
+    let mut pending_bits = 0;
+    let mut pending_ones = true;
+    for byte in string {
+        for bit in (0..8).rev() {
+            let bit = (byte >> bit) as usize & 1;
+            node = tree[node].children[bit].ok_or(DecodeError)? as usize;
+            pending_bits += 1;
+            pending_ones &= bit == 1;
+            if let Some(symbol) = tree[node].symbol {
+                if symbol == EOS {
+                    return Err(DecodeError);
+                }
+                decoded.push(symbol as u8);
+                node = 0;
+                pending_bits = 0;
+                pending_ones = true;
+            }
+        }
+    }
+    if pending_bits > 7 || !pending_ones {
+        return Err(DecodeError);
+    }
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(hex: &str) -> Vec<u8> {
+        let hex: String = hex.split_whitespace().collect();
+        (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect()
+    }
+
+    fn fields(fields: &[(&str, &str)]) -> Vec<Field> {
+        fields.iter().map(|(name, value)| (name.as_bytes().to_vec(), value.as_bytes().to_vec())).collect()
+    }
+
+    #[test]
+    fn test_decode_requests() {
+        // the examples of RFC 7541, C.4: three requests on one connection, with Huffman coding
+        let mut decoder = Decoder::default();
+        let first = decoder.decode(&hex("8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff")).unwrap();
+        assert_eq!(first, fields(&[(":method", "GET"), (":scheme", "http"), (":path", "/"), (":authority", "www.example.com")]));
+        assert_eq!(decoder.table_size, 57);
+
+        let second = decoder.decode(&hex("8286 84be 5886 a8eb 1064 9cbf")).unwrap();
+        assert_eq!(second, fields(&[(":method", "GET"),
+                                    (":scheme", "http"),
+                                    (":path", "/"),
+                                    (":authority", "www.example.com"),
+                                    ("cache-control", "no-cache")]));
+        assert_eq!(decoder.table_size, 110);
+
+        let third = decoder.decode(&hex("8287 85bf 400a 6375 7374 6f6d 2d6b 6579 8925 a849 e95b b8e8 b4bf")).unwrap();
+        assert_eq!(third, fields(&[(":method", "GET"),
+                                   (":scheme", "https"),
+                                   (":path", "/index.html"),
+                                   (":authority", "www.example.com"),
+                                   ("custom-key", "custom-value")]));
+        assert_eq!(decoder.table_size, 164);
+
+        // without Huffman coding, C.3.1
+        let mut decoder = Decoder::default();
+        let first = decoder.decode(&hex("8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d")).unwrap();
+        assert_eq!(first[3], (b":authority".to_vec(), b"www.example.com".to_vec()));
+    }
+
+    #[test]
+    fn test_table_size() {
+        let mut decoder = Decoder::default();
+        decoder.decode(&hex("4003 6b65 7903 7661 6c")).unwrap();
+        assert_eq!(decoder.entry(62).unwrap(), &(b"key".to_vec(), b"val".to_vec()));
+        // shrinking the table evicts the entry
+        assert_eq!(decoder.decode(&hex("20")).unwrap(), Vec::new());
+        assert_eq!(decoder.entry(62), Err(DecodeError));
+        // an update after a field, or past the limit, is an error
+        assert_eq!(decoder.decode(&hex("82 20")), Err(DecodeError));
+        assert_eq!(decoder.decode(&hex("3fe2 1f")), Err(DecodeError));
+    }
+
+    #[test]
+    fn test_invalid_blocks() {
+        let mut decoder = Decoder::default();
+        // index 0, an index past the tables, a truncated string
+        assert_eq!(decoder.decode(&hex("80")), Err(DecodeError));
+        assert_eq!(decoder.decode(&hex("be")), Err(DecodeError));
+        assert_eq!(decoder.decode(&hex("0003 6b65")), Err(DecodeError));
+        // Huffman padding that isn't the start of EOS
+        assert_eq!(decode_huffman(&hex("f1e3 c2e5 f23a 6ba0 ab90 f4fe")), Err(DecodeError));
+        assert_eq!(decode_huffman(&hex("f1e3 c2e5 f23a 6ba0 ab90 f4ff")).unwrap(), b"www.example.com");
+    }
+
+    #[test]
+    fn test_encode() {
+        let response = [(":status", "200"), (":status", "201"), ("content-type", "text/plain"), ("x-request-id", "7")];
+        let block = encode(&response);
+        assert_eq!(block[0], 0x88);
+        assert_eq!(Decoder::default().decode(&block).unwrap(), fields(&response));
+
+        // integers past the prefix take more bytes, C.1.2
+        let mut block = Vec::new();
+        encode_integer(&mut block, 0x00, 5, 1337);
+        assert_eq!(block, [0x1f, 0x9a, 0x0a]);
+        assert_eq!(decode_integer(&mut block.as_slice(), 5), Ok(1337));
+    }
+}
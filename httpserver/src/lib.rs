@@ -0,0 +1,16 @@
+pub mod connection;
+pub mod extract;
+pub mod handler;
+mod http2;
+pub mod middleware;
+pub mod pool;
+pub mod problem;
+pub mod router;
+pub mod server;
+pub mod shutdown;
+pub mod sse;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "tls")]
+pub mod tls;
+pub mod websocket;
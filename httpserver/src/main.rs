@@ -1,36 +1,244 @@
-mod server;
-mod router;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::{env, fs, process, thread};
+#[cfg(feature = "tls")]
+use std::sync::Arc;
 
-use http::{httprequest::HttpRequest, httpresponse::HttpResponse};
-use server::Server;
+use http::{httprequest::HttpRequest, httpresponse::{HttpResponse, StatusCode}};
+use httpserver::{extract::Path, middleware::Next, router::Router, server::Server};
+#[cfg(feature = "tls")]
+use httpserver::tls::{self, CertificateStore};
+use serde::Deserialize;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
 
-fn hello(req: &HttpRequest) -> HttpResponse {    
-    let body = b"Hello World!".to_vec();
-    HttpResponse::new("200", None, Some(body))
+fn hello(_req: &HttpRequest) -> &'static str {
+    "Hello World!"
 }
 
-fn greeting(req: &HttpRequest) -> HttpResponse { 
+fn greeting(req: &HttpRequest) -> HttpResponse {
     let username = req.path_params.get("name").unwrap();
     let body =  format!("Hello {}!", username);
     HttpResponse::new("200", None, Some(body.into_bytes()))
 }
 
-fn user_order_details(req: &HttpRequest) -> HttpResponse { 
-    let user_id = req.path_params.get("user_id").unwrap();
-    let order_id = req.path_params.get("order_id").unwrap();
+fn echo(req: &HttpRequest) -> HttpResponse {
+    HttpResponse::new("200", None, Some(req.body.clone()))
+}
+
+struct AppState {
+    visits: AtomicUsize,
+}
+
+fn visits(_req: &HttpRequest, state: &AppState) -> HttpResponse {
+    let visits = state.visits.fetch_add(1, Ordering::SeqCst) + 1;
+    HttpResponse::new("200", None, Some(format!("Visits: {}", visits).into_bytes()))
+}
 
-    let body =  format!("UserId: {}, OrderId: {}", user_id, order_id);
+#[derive(Deserialize)]
+struct OrderPath {
+    user_id: u64,
+    order_id: String,
+}
+
+fn user_order_details(Path(path): Path<OrderPath>) -> HttpResponse {
+    let body =  format!("UserId: {}, OrderId: {}", path.user_id, path.order_id);
     HttpResponse::new("200", None, Some(body.into_bytes()))
 }
 
+/// Configuration of the binary, read from the JSON file given as the first
+/// argument and read again on SIGHUP:
+///
+/// ```text
+/// {
+///     "log_level": "info",
+///     "routes": [{ "path": "/about", "body": "<h1>About</h1>", "content_type": "text/html" }],
+///     "tls": { "cert": "cert.pem", "key": "key.pem" }
+/// }
+/// ```
+///
+/// SIGHUP also reloads the certificates whose files changed. Turning TLS on or
+/// off, or pointing it to other files, needs a restart.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct Config {
+    log_level: LogLevel,
+    routes: Vec<StaticRoute>,
+    tls: Option<TlsSettings>,
+}
+
+/// Where the certificates are, for a binary built with the `tls` feature
+#[derive(Deserialize)]
+#[cfg_attr(not(feature = "tls"), allow(dead_code))] // only refused without the feature
+#[serde(untagged)]
+enum TlsSettings {
+    /// A single certificate chain and its key, sent to every client
+    Files { cert: PathBuf, key: PathBuf },
+    /// A certificate per hostname, see `CertificateStore::from_dir`
+    Dir { certs_dir: PathBuf },
+}
+
+/// A route that always answers the same body
+#[derive(Deserialize)]
+struct StaticRoute {
+    path: String,
+    body: String,
+    #[serde(default = "default_content_type")]
+    content_type: String,
+}
+
+fn default_content_type() -> String {
+    String::from("text/plain; charset=utf-8")
+}
+
+#[derive(Deserialize, Default, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+enum LogLevel {
+    Error = 0,
+    Warn = 1,
+    #[default]
+    Info = 2,
+    Debug = 3,
+}
+
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+fn log_enabled(level: LogLevel) -> bool {
+    level as u8 <= LOG_LEVEL.load(Ordering::Relaxed)
+}
+
+fn load_config(path: Option<&str>) -> Result<Config, String> {
+    match path {
+        Some(path) => {
+            let contents = fs::read_to_string(path).map_err(|e| format!("Couldn't read {}: {}", path, e))?;
+            serde_json::from_str(&contents).map_err(|e| format!("Invalid configuration in {}: {}", path, e))
+        },
+        None => Ok(Config::default()),
+    }
+}
+
+/// The routes of the application followed by the ones of the configuration,
+/// whose paths may not compile
+fn build_router(server: &Server<AppState>, config: &Config) -> Result<Router<AppState>, String> {
+    let mut router = server.new_router();
+    router.get("/hello", hello);
+    router.get("/hello/{name}", greeting);
+    router.post("/echo", echo);
+    router.get("/visits", visits);
+    router.get("/users/{user_id:u64}/orders/{order_id}", user_order_details);
+
+    for route in &config.routes {
+        let body = route.body.clone();
+        let content_type = route.content_type.clone();
+        router.try_get(&route.path, move |_: &HttpRequest| {
+            HttpResponse::with_status(StatusCode::OK, Some(body.clone().into_bytes()))
+                .with_header("Content-Type", &content_type)
+        }).map_err(|e| e.to_string())?;
+    }
+    Ok(router)
+}
+
+/// Nothing changes if the configuration can't be applied
+fn apply_config(server: &Server<AppState>, config: &Config) -> Result<(), String> {
+    let router = build_router(server, config)?;
+    LOG_LEVEL.store(config.log_level as u8, Ordering::Relaxed);
+    server.replace_router(router);
+    Ok(())
+}
+
+#[cfg(feature = "tls")]
+fn load_certificates(settings: &TlsSettings) -> Result<Arc<CertificateStore>, String> {
+    let certificates = match settings {
+        TlsSettings::Files { cert, key } => {
+            let certificates = CertificateStore::new();
+            certificates.add("default", cert, key).map(|_| certificates)
+        },
+        TlsSettings::Dir { certs_dir } => CertificateStore::from_dir(certs_dir),
+    };
+    certificates.map(Arc::new).map_err(|e| format!("Couldn't load the certificates: {}", e))
+}
+
+fn log_requests(req: &HttpRequest, next: Next) -> HttpResponse {
+    let response = next.run(req);
+    if log_enabled(LogLevel::Info) {
+        println!("{} {} {}", req.method, req.resource, response.status_code);
+    }
+    response
+}
+
 fn main() {
     let bind_address = "127.0.0.1:8000";
-    let server = Server::new(&bind_address);
+    let config_path = env::args().nth(1);
+    let config = load_config(config_path.as_deref()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
 
-    server.get("/hello", hello);    
-    server.get("/hello/{name}", greeting);
-    server.get("/users/{user_id}/orders/{order_id}", user_order_details);    
+    let state = AppState { visits: AtomicUsize::new(0) };
+    let server = Server::new(bind_address).with_state(state);
+    #[cfg(feature = "tls")]
+    let (server, certificates) = match &config.tls {
+        Some(settings) => {
+            let certificates = load_certificates(settings).unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                process::exit(1);
+            });
+            (server.with_tls(tls::sni_server_config(certificates.clone())), Some(certificates))
+        },
+        None => (server, None),
+    };
+    #[cfg(not(feature = "tls"))]
+    if config.tls.is_some() {
+        eprintln!("The tls section needs a binary built with the tls feature");
+        process::exit(1);
+    }
+    server.layer(log_requests);
+    apply_config(&server, &config).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
 
+    // SIGTERM and SIGINT drain the server, SIGHUP reloads the configuration
+    // while it keeps listening
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP]).expect("Couldn't register the signal handlers");
+    let signals_handle = signals.handle();
+    let shutdown = server.shutdown_handle();
+
+    print!("{}", server.routes());
     println!("Server is listening {}", bind_address);
-    server.run();
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            for signal in signals.forever() {
+                match signal {
+                    SIGHUP => match load_config(config_path.as_deref()).and_then(|config| apply_config(&server, &config)) {
+                        Ok(()) => {
+                            // the handshakes that follow use the new certificates
+                            #[cfg(feature = "tls")]
+                            if let Some(certificates) = &certificates {
+                                if let Err(errors) = certificates.reload() {
+                                    for e in errors {
+                                        eprintln!("Couldn't reload a certificate: {}", e);
+                                    }
+                                }
+                            }
+                            if log_enabled(LogLevel::Info) {
+                                println!("Configuration reloaded");
+                            }
+                        },
+                        // keeps the current configuration
+                        Err(e) => eprintln!("{}", e),
+                    },
+                    _ => {
+                        if log_enabled(LogLevel::Info) {
+                            println!("Shutting down");
+                        }
+                        shutdown.shutdown();
+                    },
+                }
+            }
+        });
+
+        server.run();
+        signals_handle.close();
+    });
 }
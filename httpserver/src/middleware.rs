@@ -0,0 +1,95 @@
+use std::sync::Arc;
+
+use http::{httprequest::HttpRequest, httpresponse::HttpResponse};
+use crate::router::RouteHandler;
+
+/// Runs around a handler: it receives the request and the rest of the chain,
+/// and can return a response of its own without calling [`Next::run`] or
+/// change the response that `next` returns.
+///
+/// ```text
+/// fn log(req: &HttpRequest, next: Next) -> HttpResponse {
+///     let response = next.run(req);
+///     println!("{} {} {}", req.method, req.resource, response.status_code);
+///     response
+/// }
+/// ```
+///
+/// Middleware can be attached to the server, to a router and to a single
+/// route. The server's run first, then the ones of the routers from the
+/// outermost to the nested one, then the ones of the route. At each level they
+/// run in the order they were added.
+pub type Middleware = Arc<dyn Fn(&HttpRequest, Next<'_>) -> HttpResponse + Send + Sync>;
+
+/// The rest of the middleware chain, ending with the handler
+pub struct Next<'a> {
+    endpoint: &'a (dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync),
+}
+
+impl Next<'_> {
+    pub fn run(self, request: &HttpRequest) -> HttpResponse {
+        (self.endpoint)(request)
+    }
+}
+
+/// Wraps the handler with the middleware, the first one being the outermost
+pub fn wrap(handler: RouteHandler, middleware: &[Middleware]) -> RouteHandler {
+    middleware.iter().rev().fold(handler, |inner, middleware| {
+        let middleware = middleware.clone();
+        Arc::new(move |request: &HttpRequest| middleware(request, Next { endpoint: &*inner }))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    fn tracing_middleware(name: &'static str, trace: Arc<Mutex<Vec<String>>>) -> Middleware {
+        Arc::new(move |request: &HttpRequest, next: Next| {
+            trace.lock().unwrap().push(format!("before {}", name));
+            let response = next.run(request);
+            trace.lock().unwrap().push(format!("after {}", name));
+            response
+        })
+    }
+
+    #[test]
+    fn test_wrap_order() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let handler_trace = trace.clone();
+        let handler: RouteHandler = Arc::new(move |_: &HttpRequest| {
+            handler_trace.lock().unwrap().push(String::from("handler"));
+            HttpResponse::default()
+        });
+
+        let middleware = vec![tracing_middleware("first", trace.clone()), tracing_middleware("second", trace.clone())];
+        let request = HttpRequest::parse(b"GET / HTTP/1.1\r\n\r\n".to_vec()).unwrap();
+        wrap(handler, &middleware)(&request);
+
+        assert_eq!(*trace.lock().unwrap(), vec!["before first", "before second", "handler", "after second", "after first"]);
+    }
+
+    #[test]
+    fn test_short_circuit_and_modify() {
+        let handler: RouteHandler = Arc::new(|_: &HttpRequest| HttpResponse::default());
+        let auth: Middleware = Arc::new(|request: &HttpRequest, next: Next| {
+            match request.header_value("Authorization") {
+                Some(_) => next.run(request),
+                None => HttpResponse::new("401", None, None),
+            }
+        });
+        let powered_by: Middleware = Arc::new(|request: &HttpRequest, next: Next| {
+            next.run(request).with_header("X-Powered-By", "rserver")
+        });
+        let handler = wrap(handler, &[powered_by, auth]);
+
+        let request = HttpRequest::parse(b"GET / HTTP/1.1\r\n\r\n".to_vec()).unwrap();
+        let response = handler(&request);
+        assert_eq!(response.status_code, "401");
+        assert_eq!(response.headers.unwrap().get("X-Powered-By").unwrap(), "rserver");
+
+        let request = HttpRequest::parse(b"GET / HTTP/1.1\r\nAuthorization: Bearer 1\r\n\r\n".to_vec()).unwrap();
+        assert_eq!(handler(&request).status_code, "200");
+    }
+}
@@ -0,0 +1,162 @@
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// What the server does with a new connection when all the workers are busy
+/// and the queue of waiting connections is full.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum OverloadPolicy {
+    /// Stop accepting connections until a worker is free
+    #[default]
+    Block,
+    /// Answer `503 Service Unavailable` and close the connection
+    Reject,
+    /// Close the connection without answering
+    Drop,
+}
+
+/// Fixed number of threads that handle the items sent to the pool, with a
+/// bounded queue of the items that wait for a free thread.
+pub struct ThreadPool<T> {
+    sender: Option<SyncSender<T>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl<T: Send + 'static> ThreadPool<T> {
+    pub fn new<F>(size: usize, queue_size: usize, handler: F) -> Self
+    where
+        F: Fn(T) + Send + Sync + 'static,
+    {
+        assert!(size > 0, "The pool needs at least one worker");
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let handler = Arc::new(handler);
+
+        let workers = (0..size).map(|id| {
+            let receiver = receiver.clone();
+            let handler = handler.clone();
+            thread::Builder::new().name(format!("worker-{}", id))
+                                  .spawn(move || Self::work(&receiver, &*handler))
+                                  .expect("Couldn't spawn a worker thread")
+        }).collect();
+
+        ThreadPool {
+            sender: Some(sender),
+            workers,
+        }
+    }
+
+    fn work(receiver: &Mutex<Receiver<T>>, handler: &(dyn Fn(T) + Send + Sync)) {
+        loop {
+            // the lock is released before the item is handled
+            let item = receiver.lock().unwrap().recv();
+            match item {
+                Ok(item) => handler(item),
+                Err(_) => break, // the pool was dropped
+            }
+        }
+    }
+
+    /// Waits for room in the queue
+    pub fn execute(&self, item: T) {
+        // the workers only stop once the sender is dropped
+        let _ = self.sender.as_ref().unwrap().send(item);
+    }
+
+    /// Gives the item back if the queue is full
+    pub fn try_execute(&self, item: T) -> Result<(), T> {
+        match self.sender.as_ref().unwrap().try_send(item) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(item)) | Err(TrySendError::Disconnected(item)) => Err(item),
+        }
+    }
+}
+
+impl<T> ThreadPool<T> {
+    /// Lets the workers finish the queued items, waiting for them at most until
+    /// the deadline. Returns how many workers are still busy, they are left running.
+    pub fn shutdown(mut self, deadline: Duration) -> usize {
+        drop(self.sender.take());
+        let deadline = Instant::now() + deadline;
+        while self.workers.iter().any(|worker| !worker.is_finished()) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let (finished, busy): (Vec<_>, Vec<_>) = self.workers.drain(..).partition(|worker| worker.is_finished());
+        for worker in finished {
+            let _ = worker.join();
+        }
+        busy.len()
+    }
+}
+
+/// Lets the workers finish the queued items and waits for them
+impl<T> Drop for ThreadPool<T> {
+    fn drop(&mut self) {
+        drop(self.sender.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_pool_handles_every_item() {
+        let handled = Arc::new(AtomicUsize::new(0));
+        let counter = handled.clone();
+        let pool = ThreadPool::new(4, 2, move |n: usize| {
+            counter.fetch_add(n, Ordering::SeqCst);
+        });
+        for n in 1..=100 {
+            pool.execute(n);
+        }
+        drop(pool);
+        assert_eq!(handled.load(Ordering::SeqCst), 5050);
+    }
+
+    #[test]
+    fn test_full_queue() {
+        let (started_sender, started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let pool = ThreadPool::new(1, 1, move |n: usize| {
+            started_sender.send(n).unwrap();
+            released.lock().unwrap().recv().unwrap();
+        });
+
+        pool.execute(1);
+        assert_eq!(started.recv().unwrap(), 1);
+        // the only worker is busy, so the next item waits in the queue and the one after is refused
+        assert_eq!(pool.try_execute(2), Ok(()));
+        assert_eq!(pool.try_execute(3), Err(3));
+
+        release.send(()).unwrap();
+        assert_eq!(started.recv().unwrap(), 2);
+        release.send(()).unwrap();
+    }
+
+    #[test]
+    fn test_shutdown_deadline() {
+        let (release, released) = mpsc::channel::<()>();
+        let released = Mutex::new(released);
+        let pool = ThreadPool::new(2, 2, move |wait: bool| {
+            if wait {
+                released.lock().unwrap().recv().unwrap();
+            }
+        });
+        pool.execute(false);
+        pool.execute(true);
+        assert_eq!(pool.shutdown(Duration::from_millis(50)), 1);
+        release.send(()).unwrap();
+
+        let pool = ThreadPool::new(2, 2, |_: bool| ());
+        pool.execute(false);
+        assert_eq!(pool.shutdown(Duration::from_secs(5)), 0);
+    }
+}
@@ -0,0 +1,135 @@
+use serde::Serialize;
+use serde_json::{Map, Value};
+use http::httpresponse::{HttpResponse, IntoResponse, StatusCode};
+
+/// The members defined by RFC 9457, which an extension can't replace
+const RESERVED_MEMBERS: [&str; 5] = ["type", "title", "status", "detail", "instance"];
+
+/// A problem details document (RFC 9457), sent as `application/problem+json`.
+///
+/// ```text
+/// server.error_handler(StatusCode::NOT_FOUND, |req: &HttpRequest, _| {
+///     Problem::new(StatusCode::NOT_FOUND)
+///         .with_detail(format!("{} doesn't exist", req.path()))
+///         .into_response()
+/// });
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(flatten)]
+    pub extensions: Map<String, Value>,
+}
+
+impl Problem {
+    /// A problem of type `about:blank`, whose title is the reason phrase of the status.
+    pub fn new(status: StatusCode) -> Self {
+        Problem {
+            problem_type: String::from("about:blank"),
+            title: status.reason().to_owned(),
+            status: status.as_u16(),
+            detail: None,
+            instance: None,
+            extensions: Map::new(),
+        }
+    }
+
+    /// A URI that identifies the type of the problem.
+    pub fn with_type(mut self, problem_type: &str) -> Self {
+        self.problem_type = problem_type.to_owned();
+        self
+    }
+
+    pub fn with_title(mut self, title: &str) -> Self {
+        self.title = title.to_owned();
+        self
+    }
+
+    /// An explanation specific to this occurrence of the problem.
+    pub fn with_detail(mut self, detail: impl Into<String>) -> Self {
+        self.detail = Some(detail.into());
+        self
+    }
+
+    /// A URI that identifies this occurrence of the problem, e.g. the path of the request.
+    pub fn with_instance(mut self, instance: &str) -> Self {
+        self.instance = Some(instance.to_owned());
+        self
+    }
+
+    /// Adds a member of its own to the document, e.g. the invalid fields of a form.
+    /// Extensions named like the standard members, e.g. `status`, are ignored.
+    pub fn with_extension(mut self, name: &str, value: impl Serialize) -> Self {
+        if RESERVED_MEMBERS.contains(&name) {
+            return self;
+        }
+        let value = serde_json::to_value(value).unwrap_or(Value::Null);
+        self.extensions.insert(name.to_owned(), value);
+        self
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(mut self) -> HttpResponse {
+        // the extensions can also be filled in directly, a member twice isn't valid JSON
+        self.extensions.retain(|name, _| !RESERVED_MEMBERS.contains(&name.as_str()));
+        match serde_json::to_vec(&self) {
+            Ok(body) => HttpResponse::with_status(StatusCode(self.status), Some(body))
+                            .with_header("Content-Type", "application/problem+json"),
+            Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Couldn't serialize the problem: {}", e))
+                          .into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_problem_response() {
+        let response = Problem::new(StatusCode::UNPROCESSABLE_ENTITY)
+                           .with_type("https://example.com/probs/invalid-order")
+                           .with_detail("quantity must be positive")
+                           .with_instance("/orders/12")
+                           .with_extension("fields", ["quantity"])
+                           .into_response();
+
+        assert_eq!(response.status_code, "422");
+        assert_eq!(response.headers.as_ref().unwrap().get("Content-Type").unwrap(), "application/problem+json");
+        let body: Value = serde_json::from_slice(&response.body.unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({
+            "type": "https://example.com/probs/invalid-order",
+            "title": "Unprocessable Entity",
+            "status": 422,
+            "detail": "quantity must be positive",
+            "instance": "/orders/12",
+            "fields": ["quantity"],
+        }));
+
+        let body: Value = serde_json::from_slice(&Problem::new(StatusCode::NOT_FOUND).into_response().body.unwrap()).unwrap();
+        assert_eq!(body, serde_json::json!({"type": "about:blank", "title": "Not Found", "status": 404}));
+    }
+
+    #[test]
+    fn test_reserved_extensions() {
+        let mut problem = Problem::new(StatusCode::BAD_REQUEST).with_extension("status", 200)
+                                                               .with_extension("type", "other")
+                                                               .with_extension("balance", 30);
+        assert_eq!(problem.extensions.len(), 1);
+        problem.extensions.insert(String::from("title"), Value::from("Other"));
+
+        let body = problem.into_response().body.unwrap();
+        let text = String::from_utf8(body.clone()).unwrap();
+        assert_eq!(text.matches("\"title\"").count(), 1);
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, serde_json::json!({"type": "about:blank", "title": "Bad Request", "status": 400, "balance": 30}));
+    }
+}
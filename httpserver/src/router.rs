@@ -1,13 +1,78 @@
 use std::collections::HashMap;
-use indexmap::IndexMap;
+use std::fmt::Display;
+use std::panic::Location;
+use std::sync::Arc;
+use indexmap::{IndexMap, map::Entry};
 use regex::Regex;
 
 use http::httprequest::{HttpRequest, Method};
 use http::httpresponse::HttpResponse;
+use crate::handler::Handler;
+use crate::middleware::{Middleware, Next};
+use crate::sse::{self, EventStream, EventStreamHandler};
+use crate::websocket::{self, WebSocket, WebSocketHandler};
 
-pub type RouteHandler = fn(&HttpRequest) -> HttpResponse;
+/// A handler can be a plain function or a closure that captures its own state,
+/// e.g. a database pool or a counter.
+pub type RouteHandler = Arc<dyn Fn(&HttpRequest) -> HttpResponse + Send + Sync>;
+
+/// The future of the response of an async handler.
+#[cfg(feature = "tokio")]
+pub type ResponseFuture = std::pin::Pin<Box<dyn std::future::Future<Output = HttpResponse> + Send>>;
+
+/// An async handler, which gets its own copy of the request since the future outlives the call.
+#[cfg(feature = "tokio")]
+pub type AsyncRouteHandler = Arc<dyn Fn(HttpRequest) -> ResponseFuture + Send + Sync>;
 
 const CATCH_ALL: &str = "[^/]+"; // catch everything expect slash
+const UUID: &str = "[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}";
+
+/// Constraint attached to a path parameter, e.g. `{id:int}` or `{slug:[a-z-]+}`.
+/// A segment that doesn't satisfy the constraint doesn't match the route, so
+/// the router falls through to the next candidate.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ParamConstraint {
+    Int,
+    I32,
+    U32,
+    U64,
+    Uuid,
+    Regex(String),
+}
+
+impl ParamConstraint {
+    fn parse(constraint: &str) -> ParamConstraint {
+        match constraint {
+            "int" | "i64" => ParamConstraint::Int,
+            "i32" => ParamConstraint::I32,
+            "u32" => ParamConstraint::U32,
+            "u64" => ParamConstraint::U64,
+            "uuid" => ParamConstraint::Uuid,
+            other => ParamConstraint::Regex(other.to_string()),
+        }
+    }
+
+    /// Regex used for the segment in the route's regex
+    fn pattern(&self) -> String {
+        match self {
+            ParamConstraint::Int | ParamConstraint::I32 => "-?[0-9]+".to_string(),
+            ParamConstraint::U32 | ParamConstraint::U64 => "[0-9]+".to_string(),
+            ParamConstraint::Uuid => UUID.to_string(),
+            ParamConstraint::Regex(regex) => format!("(?:{})", regex),
+        }
+    }
+
+    /// Checks what the regex can't, e.g. that the value fits in the integer type
+    fn accepts(&self, value: &str) -> bool {
+        match self {
+            ParamConstraint::Int => value.parse::<i64>().is_ok(),
+            ParamConstraint::I32 => value.parse::<i32>().is_ok(),
+            ParamConstraint::U32 => value.parse::<u32>().is_ok(),
+            ParamConstraint::U64 => value.parse::<u64>().is_ok(),
+            ParamConstraint::Uuid | ParamConstraint::Regex(_) => true,
+        }
+    }
+}
 
 /// Normalizes a path by removing leading and trailing slashes.
 pub fn normalize_path(path: &str) -> &str {        
@@ -26,36 +91,67 @@ pub fn normalize_path(path: &str) -> &str {
     }        
 }
 
+/// Splits a `{name}` or `{name:constraint}` segment into its name and constraint
+fn parse_param(part: &str) -> Option<(&str, Option<&str>)> {
+    if part.starts_with('{') && part.ends_with('}') && part.len() > 2 {
+        let param = &part[1..part.len()-1];
+        match param.split_once(':') {
+            Some((name, constraint)) => Some((name, Some(constraint))),
+            None => Some((param, None)),
+        }
+    } else {
+        None
+    }
+}
+
 fn find_params(path: &str) -> HashMap<usize, String> {
     let normalized_path = normalize_path(path);
     let parts = normalized_path.split("/");
     let mut result:HashMap<usize, String> = HashMap::new();
-    let mut position: usize = 0;
-    for part in parts {
-        if part.starts_with('{') && part.ends_with('}') && part.len() > 2 {
-            let param_name = &part[1..part.len()-1];
+    for (position, part) in parts.enumerate() {
+        if let Some((param_name, _)) = parse_param(part) {
             result.insert(position, param_name.to_string());
         }
-        position+=1;
+    }
+    result
+}
+
+fn find_constraints(path: &str) -> HashMap<usize, ParamConstraint> {
+    let normalized_path = normalize_path(path);
+    let parts = normalized_path.split("/");
+    let mut result:HashMap<usize, ParamConstraint> = HashMap::new();
+    for (position, part) in parts.enumerate() {
+        if let Some((_, Some(constraint))) = parse_param(part) {
+            result.insert(position, ParamConstraint::parse(constraint));
+        }
     }
     result
 }
 
 /// Build the regex experssion that matches the path
 fn regex_that_match(path: &str) -> String {
+    segments_regex(path, &find_constraints(path))
+}
+
+/// The regex of the path whose parameters match any segment, their constraints
+/// are checked on the decoded segments instead
+fn regex_of_segments(path: &str) -> String {
+    segments_regex(path, &HashMap::new())
+}
+
+fn segments_regex(path: &str, constraints: &HashMap<usize, ParamConstraint>) -> String {
     let params = find_params(path);
     let normalized_path = normalize_path(path);
     let parts:Vec<&str> = normalized_path.split('/').collect();
-    let mut position: usize = 0;
     let mut regex_expersion_parts: Vec<String> = Vec::new();
     let part_size = parts.len();
 
-    for part in parts {
-        let item = match params.get(&position) {
-            Some(_) => CATCH_ALL, 
-            None => part, 
+    for (position, part) in parts.into_iter().enumerate() {
+        let mut regex_item = match (params.get(&position), constraints.get(&position)) {
+            (Some(_), Some(constraint)) => constraint.pattern(),
+            (Some(_), None) => CATCH_ALL.to_string(),
+            (None, _) => part.to_string(),
         };
-        let mut regex_item = format!("{}", item);        
         if position == 0 {
             if path.starts_with('/') {
                 regex_item = format!("^/{}", regex_item);
@@ -71,7 +167,6 @@ fn regex_that_match(path: &str) -> String {
             }
         }
         regex_expersion_parts.push(regex_item);
-        position+=1;
     }
 
     regex_expersion_parts.join("/")
@@ -79,68 +174,619 @@ fn regex_that_match(path: &str) -> String {
 
 pub struct RouteInfo {
     pub handler: RouteHandler,
+    pub handler_name: &'static str, // type name of the handler, e.g. httpserver::hello
+    pub method: Method,
+    pub template: String, // the path as it was registered, e.g. /users/{user_id:u64}
+    pub name: Option<String>, // used to build URLs with Router::url_for
+    pub registered_at: &'static Location<'static>, // where the handler was registered in the source
+    pub middleware: Vec<Middleware>, // runs around the handler, the first one is the outermost
+    #[cfg(feature = "tokio")]
+    pub async_handler: Option<AsyncRouteHandler>, // awaited by the tokio backend instead of calling `handler`
+    pub websocket_handler: Option<WebSocketHandler>, // runs on the connection once `handler` switched it to WebSocket
+    pub event_stream_handler: Option<EventStreamHandler>, // writes the events once `handler` opened the stream
     pub params_pos: HashMap<usize, String>, // key: position, value: parameter name
+    pub constraints: HashMap<usize, ParamConstraint>, // key: position, value: constraint of the parameter
+    constraint_regexes: HashMap<usize, Regex>, // compiled once, checks the values given to `url`
+}
+
+impl RouteInfo {
+    fn new(method: Method, path: &str, handler: RouteHandler, handler_name: &'static str,
+           registered_at: &'static Location<'static>) -> Self {
+        let constraints = find_constraints(path);
+        let constraint_regexes = constraints.iter()
+                                            .map(|(position, constraint)| {
+                                                let regex = Regex::new(&format!("^{}$", constraint.pattern()))
+                                                    .unwrap_or_else(|e| panic!("Invalid route {}: {}", path, e));
+                                                (*position, regex)
+                                            })
+                                            .collect();
+        RouteInfo {
+            handler,
+            handler_name,
+            method,
+            template: path.to_string(),
+            name: None,
+            registered_at,
+            middleware: Vec::new(),
+            #[cfg(feature = "tokio")]
+            async_handler: None,
+            websocket_handler: None,
+            event_stream_handler: None,
+            params_pos: find_params(path),
+            constraints,
+            constraint_regexes,
+        }
+    }
+
+    /// Names the route so its URL can be built with [`Router::url_for`].
+    pub fn name(&mut self, name: &str) -> &mut Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Adds middleware that only runs for this route, inside the middleware of the router.
+    pub fn layer<M>(&mut self, middleware: M) -> &mut Self
+    where
+        M: Fn(&HttpRequest, Next<'_>) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Builds the path of this route, filling in the parameters of the template
+    fn url(&self, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        let route_name = self.name.clone().unwrap_or_default();
+        for (param_name, _) in params {
+            if !self.params_pos.values().any(|name| name == param_name) {
+                return Err(UrlError::ExtraParam(route_name, param_name.to_string()));
+            }
+        }
+
+        let mut parts: Vec<String> = Vec::new();
+        for (position, part) in normalize_path(&self.template).split('/').enumerate() {
+            let param_name = match self.params_pos.get(&position) {
+                Some(param_name) => param_name,
+                None => {
+                    parts.push(part.to_string());
+                    continue;
+                }
+            };
+            let value = params.iter()
+                              .find(|(name, _)| name == param_name)
+                              .map(|(_, value)| *value)
+                              .ok_or_else(|| UrlError::MissingParam(route_name.clone(), param_name.clone()))?;
+            if let Some(constraint) = self.constraints.get(&position) {
+                if !self.constraint_regexes[&position].is_match(value) || !constraint.accepts(value) {
+                    return Err(UrlError::InvalidParam(route_name, param_name.clone(), value.to_string()));
+                }
+            }
+            parts.push(percent_encode(value));
+        }
+
+        let mut url = parts.join("/");
+        if self.template.len() > 2 && self.template.starts_with('/') {
+            url = format!("/{}", url);
+        }
+        if self.template.len() > 2 && self.template.ends_with('/') {
+            url = format!("{}/", url);
+        }
+        Ok(url)
+    }
+
+    /// Reads the values of the path parameters from a path that matches the
+    /// route, percent-decoded
+    pub fn extract_path_params(&self, path: &str) -> HashMap<String, String> {
+        let normalized_path = normalize_path(path);
+        let parts: Vec<&str> = normalized_path.split('/').collect();
+        let mut result = HashMap::new();
+
+        for (position, param_name) in self.params_pos.iter() {
+            let param_value = parts[*position];
+            let param_value = percent_decode(param_value).unwrap_or_else(|| param_value.to_owned());
+            result.insert(param_name.to_owned(), param_value);
+        }
+
+        result
+    }
+
+    /// Checks that the path parameters are percent-encoded UTF-8, and their
+    /// constraints once they're decoded
+    fn accepts(&self, path: &str) -> bool {
+        let normalized_path = normalize_path(path);
+        let parts: Vec<&str> = normalized_path.split('/').collect();
+        self.params_pos.keys().all(|position| {
+            let Some(value) = parts.get(*position).and_then(|part| percent_decode(part)) else {
+                return false;
+            };
+            match self.constraints.get(position) {
+                Some(constraint) => self.constraint_regexes[position].is_match(&value) && constraint.accepts(&value),
+                None => true,
+            }
+        })
+    }
 }
 
-pub struct Router {
-    // Paths for Get method
-    get_entries: IndexMap<String, RouteInfo>,
+/// Result of looking up a request in the router
+pub enum RouteMatch<'a> {
+    Found(&'a RouteInfo),
+    // The path exists but not for the requested method
+    MethodNotAllowed(Vec<Method>),
+    NotFound,
+}
+
+/// Routes requests to handlers. `S` is the state shared by the handlers of the
+/// router, see [`Router::with_state`].
+pub struct Router<S = ()> {
+    state: Arc<S>,
+
+    // runs around the handlers of all the routes, the first one is the outermost
+    middleware: Vec<Middleware>,
 
-    // Paths for Post method
-    post_entries: IndexMap<String, RouteInfo>,
+    // key: regex of the path
+    entries: IndexMap<String, RouteEntry>,
+}
+
+/// The handlers of a path per method, with the regex of the path compiled once
+/// when the path is registered
+struct RouteEntry {
+    regex: Regex,
+    handlers: HashMap<Method, RouteInfo>,
 }
 
 impl Default for Router {
     fn default() -> Self {
         Router {
-            get_entries: IndexMap::new(),
-            post_entries: IndexMap::new(),
+            state: Arc::new(()),
+            middleware: Vec::new(),
+            entries: IndexMap::new(),
         }
     }
 }
 
-impl Router {
-    pub fn get(&mut self, path: &str, handler: RouteHandler) {
-        let regex_for_path = regex_that_match(path);
-        let path_parameters = find_params(path);
-        self.get_entries.insert(regex_for_path, RouteInfo{
-            handler: handler,
-            params_pos: path_parameters,
-        });        
+impl<S> Router<S> {
+    /// Sets the state that handlers receive along with the request, e.g.
+    /// `fn handler(req: &HttpRequest, state: &S)`. Handlers that were already
+    /// registered keep the state they were registered with.
+    pub fn with_state<T>(self, state: T) -> Router<T> {
+        self.with_shared_state(Arc::new(state))
     }
 
-    pub fn post(&mut self, path: &str, handler: RouteHandler) {
+    /// Same as [`Router::with_state`] for a state that is already shared, e.g.
+    /// with another router.
+    pub fn with_shared_state<T>(self, state: Arc<T>) -> Router<T> {
+        Router {
+            state,
+            middleware: self.middleware,
+            entries: self.entries,
+        }
+    }
+
+    pub fn state(&self) -> &Arc<S> {
+        &self.state
+    }
+
+    /// Adds middleware that runs around the handlers of all the routes of the
+    /// router, including the ones of nested routers.
+    pub fn layer<M>(&mut self, middleware: M) -> &mut Self
+    where
+        M: Fn(&HttpRequest, Next<'_>) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    pub fn middleware(&self) -> &[Middleware] {
+        &self.middleware
+    }
+
+    #[track_caller]
+    pub fn get<H, M>(&mut self, path: &str, handler: H) -> &mut RouteInfo
+    where
+        H: Handler<S, M>,
+    {
+        self.add_handler(Method::Get, path, handler)
+    }
+
+    /// Like [`Router::get`], but a template that doesn't compile, e.g. `/x/{id:[}`,
+    /// is an error instead of a panic, for the routes that aren't in the source.
+    #[track_caller]
+    pub fn try_get<H, M>(&mut self, path: &str, handler: H) -> Result<&mut RouteInfo, RouteError>
+    where
+        H: Handler<S, M>,
+    {
+        check_template(path)?;
+        Ok(self.get(path, handler))
+    }
+
+    #[track_caller]
+    pub fn post<H, M>(&mut self, path: &str, handler: H) -> &mut RouteInfo
+    where
+        H: Handler<S, M>,
+    {
+        self.add_handler(Method::Post, path, handler)
+    }
+
+    /// Registers an explicit handler for HEAD, otherwise HEAD requests are served by the GET handler.
+    #[track_caller]
+    pub fn head<H, M>(&mut self, path: &str, handler: H) -> &mut RouteInfo
+    where
+        H: Handler<S, M>,
+    {
+        self.add_handler(Method::Head, path, handler)
+    }
+
+    /// Registers an explicit handler for OPTIONS, which replaces the automatic response.
+    #[track_caller]
+    pub fn options<H, M>(&mut self, path: &str, handler: H) -> &mut RouteInfo
+    where
+        H: Handler<S, M>,
+    {
+        self.add_handler(Method::Options, path, handler)
+    }
+
+    /// Registers a WebSocket endpoint: its GET route answers the opening
+    /// handshake, then the handler gets the connection, see [`crate::websocket`].
+    /// The handler shares the state of the router by capturing it.
+    #[track_caller]
+    pub fn websocket<F>(&mut self, path: &str, handler: F) -> &mut RouteInfo
+    where
+        F: Fn(&HttpRequest, WebSocket<'_>) + Send + Sync + 'static,
+    {
+        let route_info = self.add(Method::Get, path, Arc::new(websocket::handshake), std::any::type_name::<F>());
+        route_info.websocket_handler = Some(Arc::new(handler));
+        route_info
+    }
+
+    /// Registers a Server-Sent Events endpoint: its GET route answers with the
+    /// headers of the stream, then the handler writes the events, see [`crate::sse`].
+    /// The handler shares the state of the router by capturing it.
+    #[track_caller]
+    pub fn sse<F>(&mut self, path: &str, handler: F) -> &mut RouteInfo
+    where
+        F: Fn(&HttpRequest, EventStream<'_>) + Send + Sync + 'static,
+    {
+        let route_info = self.add(Method::Get, path, Arc::new(sse::open), std::any::type_name::<F>());
+        route_info.event_stream_handler = Some(Arc::new(handler));
+        route_info
+    }
+
+    /// Mounts all the routes of `router` under `prefix`. Parameters in the prefix,
+    /// e.g. `/users/{user_id}`, are passed to the handlers of the nested router.
+    /// Route names are kept, so they must be unique across the nested routers.
+    /// The nested router can have its own type of state and its middleware
+    /// only runs for its own routes. Panics if a nested route is already
+    /// registered, with both the places they were registered at.
+    #[track_caller]
+    pub fn nest<T>(&mut self, prefix: &str, router: Router<T>) {
+        for entry in router.entries.into_values() {
+            for (method, route_info) in entry.handlers {
+                let path = join_paths(prefix, &route_info.template);
+                let existing = self.entries.get(&regex_that_match(&path))
+                                           .and_then(|entry| entry.handlers.get(&method));
+                if let Some(existing) = existing {
+                    panic!("Nested route {} {} registered at {} collides with {} registered at {}",
+                           method, path, route_info.registered_at, existing.template, existing.registered_at);
+                }
+                let nested_route = self.add(method, &path, route_info.handler, route_info.handler_name);
+                nested_route.name = route_info.name;
+                nested_route.registered_at = route_info.registered_at;
+                #[cfg(feature = "tokio")]
+                {
+                    nested_route.async_handler = route_info.async_handler;
+                }
+                nested_route.websocket_handler = route_info.websocket_handler;
+                nested_route.event_stream_handler = route_info.event_stream_handler;
+                nested_route.middleware = router.middleware.iter()
+                                                           .chain(&route_info.middleware)
+                                                           .cloned()
+                                                           .collect();
+            }
+        }
+    }
+
+    #[track_caller]
+    fn add_handler<H, M>(&mut self, method: Method, path: &str, handler: H) -> &mut RouteInfo
+    where
+        H: Handler<S, M>,
+    {
+        let handler_name = std::any::type_name::<H>();
+        #[cfg(not(feature = "tokio"))]
+        let route_info = self.add(method, path, handler.into_route_handler(self.state.clone()), handler_name);
+        #[cfg(feature = "tokio")]
+        let route_info = {
+            let (route_handler, async_handler) = handler.into_endpoint(self.state.clone());
+            let route_info = self.add(method, path, route_handler, handler_name);
+            route_info.async_handler = async_handler;
+            route_info
+        };
+        route_info
+    }
+
+    #[track_caller]
+    fn add(&mut self, method: Method, path: &str, handler: RouteHandler, handler_name: &'static str) -> &mut RouteInfo {
         let regex_for_path = regex_that_match(path);
-        let path_parameters = find_params(path);
-        self.post_entries.insert(regex_for_path, RouteInfo{
-            handler: handler,
-            params_pos: path_parameters,
-        });        
+        let route_info = RouteInfo::new(method, path, handler, handler_name, Location::caller());
+        let entry = match self.entries.entry(regex_for_path) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let regex = Regex::new(&regex_of_segments(path)).unwrap_or_else(|e| panic!("Invalid route {}: {}", path, e));
+                entry.insert(RouteEntry { regex, handlers: HashMap::new() })
+            },
+        };
+        let handlers = &mut entry.handlers;
+        handlers.insert(method, route_info);
+        handlers.get_mut(&method).unwrap()
+    }
+
+    /// Builds the percent-encoded path of the route named `name` from its template,
+    /// e.g. `/users/1/orders/A1` for `url_for("user_order", &[("user_id", "1"), ("order_id", "A1")])`
+    /// when the route was registered with
+    /// `router.get("/users/{user_id}/orders/{order_id}", handler).name("user_order")`.
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        self.entries.values()
+                    .flat_map(|entry| entry.handlers.values())
+                    .find(|route_info| route_info.name.as_deref() == Some(name))
+                    .ok_or_else(|| UrlError::UnknownRoute(name.to_string()))?
+                    .url(params)
+    }
+
+    /// Returns the routes whose path matches, regardless of the method
+    fn matching_entries(&self, path: &str) -> Vec<(Method, &RouteInfo)> {
+        let mut result = Vec::new();
+        for entry in self.entries.values() {
+            if !entry.regex.is_match(path) {
+                continue;
+            }
+            for (method, route_info) in &entry.handlers {
+                if route_info.accepts(path) {
+                    result.push((*method, route_info));
+                }
+            }
+        }
+        result
+    }
+
+    /// Iterates over all the routes, grouped by path in the order the paths
+    /// were registered, then sorted by method
+    pub fn routes(&self) -> impl Iterator<Item = &RouteInfo> {
+        self.entries.values().flat_map(|entry| {
+            let mut routes: Vec<&RouteInfo> = entry.handlers.values().collect();
+            routes.sort_by_key(|route_info| route_info.method);
+            routes
+        })
     }
 
     pub fn find_handler(&self, method: Method, path: &str) -> Option<&RouteInfo> {
-        let hashmap = match method {
-            Method::Get => &self.get_entries,
-            Method::Post => &self.post_entries,
-            Method::Uninitialized => {
-                return None;
+        find_method(&self.matching_entries(path), method)
+    }
+
+    /// The methods that can be used for the path, in a stable order. OPTIONS is
+    /// always allowed for an existing path and HEAD whenever GET is. Empty if
+    /// the path doesn't exist.
+    pub fn allowed_methods(&self, path: &str) -> Vec<Method> {
+        methods_of(&self.matching_entries(path))
+    }
+
+    /// Looks the request up, matching the path against the routes only once
+    pub fn route(&self, method: Method, path: &str) -> RouteMatch<'_> {
+        let matching = self.matching_entries(path);
+        if let Some(route_info) = find_method(&matching, method) {
+            return RouteMatch::Found(route_info);
+        }
+
+        // HEAD is served by the GET handler unless it has its own
+        if method == Method::Head {
+            if let Some(route_info) = find_method(&matching, Method::Get) {
+                return RouteMatch::Found(route_info);
             }
-        };
+        }
+
+        let allowed = methods_of(&matching);
+        if allowed.is_empty() {
+            RouteMatch::NotFound
+        } else {
+            RouteMatch::MethodNotAllowed(allowed)
+        }
+    }
+}
+
+fn find_method<'a>(matching: &[(Method, &'a RouteInfo)], method: Method) -> Option<&'a RouteInfo> {
+    matching.iter()
+            .find(|(route_method, _)| *route_method == method)
+            .map(|(_, route_info)| *route_info)
+}
+
+/// See [`Router::allowed_methods`]
+fn methods_of(matching: &[(Method, &RouteInfo)]) -> Vec<Method> {
+    let mut methods: Vec<Method> = matching.iter().map(|(method, _)| *method).collect();
+    if !methods.is_empty() {
+        methods.push(Method::Options);
+    }
+    if methods.contains(&Method::Get) {
+        methods.push(Method::Head);
+    }
+    methods.sort();
+    methods.dedup();
+    methods
+}
+
+/// Joins the prefix of a nested router with the path of one of its routes
+fn join_paths(prefix: &str, path: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    let path = path.trim_start_matches('/');
+    let prefix = if prefix.starts_with('/') {
+        prefix.to_string()
+    } else {
+        format!("/{}", prefix)
+    };
+
+    if path.is_empty() {
+        prefix
+    } else if prefix == "/" {
+        format!("/{}", path)
+    } else {
+        format!("{}/{}", prefix, path)
+    }
+}
 
-        for (regex_exper, route_info) in hashmap {            
-            let regex = Regex::new(regex_exper).unwrap();
-            if regex.is_match(path) {                
-                return Some(route_info);
+/// Prints the route table, one route per line, like `rails routes`:
+///
+/// ```text
+///  Name Method Path                                     Handler                        Source
+/// hello GET    /hello                                   httpserver::hello              httpserver/src/main.rs:36:12
+///       GET    /visits                                  httpserver::main::{{closure}}  httpserver/src/main.rs:41:12
+///       GET    /users/{user_id:u64}/orders/{order_id}   httpserver::user_order_details httpserver/src/main.rs:45:12
+/// ```
+impl<S> Display for Router<S> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let rows: Vec<[String; 5]> = self.routes().map(|route_info| {
+            [
+                route_info.name.clone().unwrap_or_default(),
+                route_info.method.to_string(),
+                route_info.template.clone(),
+                route_info.handler_name.to_string(),
+                route_info.registered_at.to_string(),
+            ]
+        }).collect();
+
+        let header = [String::from("Name"), String::from("Method"), String::from("Path"),
+                      String::from("Handler"), String::from("Source")];
+        let mut widths = header.clone().map(|column| column.len());
+        for row in &rows {
+            for (width, column) in widths.iter_mut().zip(row) {
+                *width = (*width).max(column.len());
             }
         }
 
-        None
+        for row in std::iter::once(&header).chain(&rows) {
+            writeln!(f, "{:>name$} {:<method$} {:<path$}   {:<handler$} {}",
+                     row[0], row[1], row[2], row[3], row[4],
+                     name = widths[0], method = widths[1], path = widths[2], handler = widths[3])?;
+        }
+        Ok(())
+    }
+}
+
+/// Error returned by [`Router::url_for`]
+#[derive(Debug, PartialEq)]
+pub enum UrlError {
+    UnknownRoute(String),
+    MissingParam(String, String), // route name, parameter name
+    ExtraParam(String, String), // route name, parameter name
+    InvalidParam(String, String, String), // route name, parameter name, value
+}
+
+impl Display for UrlError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UnknownRoute(route) => write!(f, "there is no route named `{}`", route),
+            Self::MissingParam(route, param) => write!(f, "route `{}` requires parameter `{}`", route, param),
+            Self::ExtraParam(route, param) => write!(f, "route `{}` has no parameter `{}`", route, param),
+            Self::InvalidParam(route, param, value) => {
+                write!(f, "value `{}` doesn't satisfy the constraint of parameter `{}` of route `{}`", value, param, route)
+            }
+        }
+    }
+}
+
+impl std::error::Error for UrlError {}
+
+/// A template whose regex, or the one of a constraint, doesn't compile
+#[derive(Debug, PartialEq)]
+pub struct RouteError {
+    pub path: String,
+    pub reason: String,
+}
+
+impl Display for RouteError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid route {}: {}", self.path, self.reason)
+    }
+}
+
+impl std::error::Error for RouteError {}
+
+/// Compiles the regexes of the template, as registering it does
+fn check_template(path: &str) -> Result<(), RouteError> {
+    let patterns = find_constraints(path).into_values()
+                                         .map(|constraint| format!("^{}$", constraint.pattern()))
+                                         .chain([regex_of_segments(path)]);
+    for pattern in patterns {
+        Regex::new(&pattern).map_err(|e| RouteError { path: path.to_string(), reason: e.to_string() })?;
+    }
+    Ok(())
+}
+
+/// Percent-encodes everything except the unreserved characters of RFC 3986
+fn percent_encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(byte as char),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
     }
+    encoded
+}
+
+/// Decodes the percent-encoded bytes, None if they aren't UTF-8 or a `%`
+/// isn't followed by two hex digits
+fn percent_decode(value: &str) -> Option<String> {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            if !hex.bytes().all(|digit| digit.is_ascii_hexdigit()) {
+                return None;
+            }
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Value of the `Allow` header for the given methods
+pub fn allow_header(methods: &[Method]) -> String {
+    methods.iter()
+           .map(|method| method.to_string())
+           .collect::<Vec<String>>()
+           .join(", ")
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use http::httprequest::ParamError;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    type PlainHandler = fn(&HttpRequest) -> HttpResponse;
+
+    fn respond(body: &str) -> HttpResponse {
+        HttpResponse::new("200", None, Some(body.as_bytes().to_vec()))
+    }
+
+    /// Routes the raw request and returns the status code and the body of the response
+    fn call<S>(router: &Router<S>, raw_request: &str) -> (String, String) {
+        let mut request = HttpRequest::parse(raw_request.as_bytes().to_vec()).unwrap();
+        let route_info = router.find_handler(request.method, request.path()).unwrap();
+        let path_params = route_info.extract_path_params(request.path());
+        request.with_path_params(&path_params);
+        let response = (route_info.handler)(&request);
+        (response.status_code, String::from_utf8(response.body.unwrap()).unwrap())
+    }
+
+    /// Calls the handler of the route to tell which one was found
+    fn handled_by(route_info: Option<&RouteInfo>) -> String {
+        let request = HttpRequest::parse(b"GET / HTTP/1.1\r\n\r\n".to_vec()).unwrap();
+        let response = (route_info.expect("A route should match").handler)(&request);
+        String::from_utf8(response.body.unwrap()).unwrap()
+    }
 
     #[test]
     fn test_normalize_path() {        
@@ -187,23 +833,23 @@ mod tests {
 
     #[test]
     fn test_path_matching() {
-        let home_handler: RouteHandler = |_: &HttpRequest| -> HttpResponse {
-            HttpResponse::default()
+        let home_handler: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("home_handler")
         };
-        let all_users: RouteHandler = |_: &HttpRequest| -> HttpResponse {
-            HttpResponse::default()
+        let all_users: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("all_users")
         };
-        let user_activity: RouteHandler = |_: &HttpRequest| -> HttpResponse {
-            HttpResponse::default()
+        let user_activity: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("user_activity")
         };
-        let user_detail: RouteHandler = |_: &HttpRequest| -> HttpResponse {
-            HttpResponse::default()
+        let user_detail: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("user_detail")
         };
-        let user_all_orders: RouteHandler = |_: &HttpRequest| -> HttpResponse {
-            HttpResponse::default()
+        let user_all_orders: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("user_all_orders")
         };
-        let user_order_details: RouteHandler = |_: &HttpRequest| -> HttpResponse {
-            HttpResponse::default()
+        let user_order_details: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("user_order_details")
         };
 
         let mut router = Router::default();
@@ -215,22 +861,542 @@ mod tests {
         router.get("/users/{user_id}/orders/{order_id}", user_order_details);
 
         let handler = router.find_handler(Method::Get, "/users/123/orders");
-        assert_eq!(handler.is_some(), true);
-        assert_eq!(handler.unwrap().handler, user_all_orders);
+        assert!(handler.is_some());
+        assert_eq!(handled_by(handler), "user_all_orders");
 
         let handler = router.find_handler(Method::Get, "/users/123/orders/A123");
-        assert_eq!(handler.is_some(), true);
-        assert_eq!(handler.unwrap().handler, user_order_details);
+        assert!(handler.is_some());
+        assert_eq!(handled_by(handler), "user_order_details");
 
         let handler = router.find_handler(Method::Get, "/users/user1");
-        assert_eq!(handler.is_some(), true);
-        assert_eq!(handler.unwrap().handler, user_detail);
+        assert!(handler.is_some());
+        assert_eq!(handled_by(handler), "user_detail");
 
         let handler = router.find_handler(Method::Get, "/users");
-        assert_eq!(handler.is_some(), true);
-        assert_eq!(handler.unwrap().handler, all_users);
+        assert!(handler.is_some());
+        assert_eq!(handled_by(handler), "all_users");
 
         let handler = router.find_handler(Method::Get, "/user");
-        assert_eq!(handler.is_some(), false);
+        assert!(handler.is_none());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_find_constraints() {
+        let result = find_constraints("/users/{user_id}");
+        assert_eq!(result.len(), 0);
+
+        let result = find_constraints("/users/{user_id:int}/files/{file:uuid}/{slug:[a-z-]+}");
+        assert_eq!(result.len(), 3);
+        assert_eq!(result.get(&1), Some(&ParamConstraint::Int));
+        assert_eq!(result.get(&3), Some(&ParamConstraint::Uuid));
+        assert_eq!(result.get(&4), Some(&ParamConstraint::Regex(String::from("[a-z-]+"))));
+
+        // the constraint isn't part of the parameter name
+        let result = find_params("/users/{user_id:u64}");
+        assert_eq!(result.get(&1).expect("At position 1 there is a parameter"), "user_id");
+    }
+
+    #[test]
+    fn test_regex_that_match_with_constraints() {
+        assert_eq!(regex_that_match("/users/{id:u64}"), "^/users/[0-9]+$");
+        assert_eq!(regex_that_match("/posts/{slug:[a-z-]+}"), "^/posts/(?:[a-z-]+)$");
+        assert_eq!(regex_that_match("/files/{id:uuid}/"), format!("^/files/{}/$", UUID));
+    }
+
+    #[test]
+    fn test_constrained_path_matching() {
+        let user_by_id: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("user_by_id")
+        };
+        let user_by_name: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("user_by_name")
+        };
+        let file: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("file")
+        };
+        let post: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("post")
+        };
+
+        let mut router = Router::default();
+        router.get("/users/{id:u64}", user_by_id);
+        router.get("/users/{name:[a-z]+}", user_by_name);
+        router.get("/files/{id:uuid}", file);
+        router.get("/posts/{slug:[a-z-]+}", post);
+
+        let handler = router.find_handler(Method::Get, "/users/42");
+        assert_eq!(handled_by(handler), "user_by_id");
+
+        // falls through to the next route when the constraint fails
+        let handler = router.find_handler(Method::Get, "/users/bob");
+        assert_eq!(handled_by(handler), "user_by_name");
+
+        // doesn't fit in u64
+        let handler = router.find_handler(Method::Get, "/users/99999999999999999999999");
+        assert!(handler.is_none());
+
+        let handler = router.find_handler(Method::Get, "/users/-1");
+        assert!(handler.is_none());
+
+        let handler = router.find_handler(Method::Get, "/files/67e55044-10b1-426f-9247-bb680e5fe0c8");
+        assert_eq!(handled_by(handler), "file");
+
+        let handler = router.find_handler(Method::Get, "/files/not-a-uuid");
+        assert!(handler.is_none());
+
+        let handler = router.find_handler(Method::Get, "/posts/hello-world");
+        assert_eq!(handled_by(handler), "post");
+
+        let handler = router.find_handler(Method::Get, "/posts/Hello_World");
+        assert!(handler.is_none());
+
+        // the constraints apply to the decoded segments
+        let handler = router.find_handler(Method::Get, "/posts/hello%2Dworld");
+        assert_eq!(handled_by(handler), "post");
+        let handler = router.find_handler(Method::Get, "/users/%34%32");
+        assert_eq!(handled_by(handler), "user_by_id");
+        let handler = router.find_handler(Method::Get, "/posts/hello%20world");
+        assert!(handler.is_none());
+        // not percent-encoded UTF-8
+        let handler = router.find_handler(Method::Get, "/users/%ff");
+        assert!(handler.is_none());
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let handler: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("handler")
+        };
+        let options: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("options")
+        };
+
+        let mut router = Router::default();
+        router.get("/users", handler);
+        router.post("/users", handler);
+        router.get("/users/{id:int}", handler);
+        router.post("/users/{name}", handler);
+        router.options("/reports", options);
+
+        assert!(matches!(router.route(Method::Get, "/users"), RouteMatch::Found(_)));
+        assert!(matches!(router.route(Method::Get, "/nothing"), RouteMatch::NotFound));
+
+        assert_eq!(router.allowed_methods("/users"), vec![Method::Get, Method::Head, Method::Post, Method::Options]);
+        // methods are collected from all the routes that match the path
+        assert_eq!(router.allowed_methods("/users/1"), vec![Method::Get, Method::Head, Method::Post, Method::Options]);
+        assert_eq!(router.allowed_methods("/users/bob"), vec![Method::Post, Method::Options]);
+        assert_eq!(router.allowed_methods("/nothing"), Vec::new());
+
+        match router.route(Method::Get, "/users/bob") {
+            RouteMatch::MethodNotAllowed(allowed) => assert_eq!(allow_header(&allowed), "POST, OPTIONS"),
+            _ => panic!("GET /users/bob should not be allowed"),
+        }
+
+        // automatic OPTIONS is handled by the server, only explicit ones are found
+        assert!(router.find_handler(Method::Options, "/users").is_none());
+        let handler = router.find_handler(Method::Options, "/reports");
+        assert_eq!(handled_by(handler), "options");
+    }
+
+    #[test]
+    fn test_head_falls_back_to_get() {
+        let get: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("get")
+        };
+        let head: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("head")
+        };
+        let post: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("post")
+        };
+
+        let mut router = Router::default();
+        router.get("/users", get);
+        router.get("/reports", get);
+        router.head("/reports", head);
+        router.post("/orders", post);
+
+        match router.route(Method::Head, "/users") {
+            RouteMatch::Found(route_info) => assert_eq!(handled_by(Some(route_info)), "get"),
+            _ => panic!("HEAD /users should be served by the GET handler"),
+        }
+        match router.route(Method::Head, "/reports") {
+            RouteMatch::Found(route_info) => assert_eq!(handled_by(Some(route_info)), "head"),
+            _ => panic!("HEAD /reports has an explicit handler"),
+        }
+        match router.route(Method::Head, "/orders") {
+            RouteMatch::MethodNotAllowed(allowed) => assert_eq!(allow_header(&allowed), "POST, OPTIONS"),
+            _ => panic!("HEAD /orders should not be allowed"),
+        }
+    }
+
+    #[test]
+    fn test_join_paths() {
+        assert_eq!(join_paths("/api/v1", "/users"), "/api/v1/users");
+        assert_eq!(join_paths("/api/v1/", "users/"), "/api/v1/users/");
+        assert_eq!(join_paths("api", "/"), "/api");
+        assert_eq!(join_paths("/", "/users"), "/users");
+        assert_eq!(join_paths("", "/users"), "/users");
+    }
+
+    #[test]
+    fn test_nested_routers() {
+        let list_users: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("list_users")
+        };
+        let create_user: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("create_user")
+        };
+        let order_details: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("order_details")
+        };
+        let v1_index: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("v1_index")
+        };
+
+        let mut orders = Router::default();
+        orders.get("/orders/{order_id}", order_details);
+
+        let mut v1 = Router::default();
+        v1.get("/", v1_index);
+        v1.post("/users", create_user);
+        v1.nest("/users/{user_id:u64}", orders);
+
+        let mut router = Router::default();
+        router.get("/api/v1/users", list_users);
+        router.nest("/api/v1", v1);
+
+        let handler = router.find_handler(Method::Get, "/api/v1");
+        assert_eq!(handled_by(handler), "v1_index");
+
+        let handler = router.find_handler(Method::Post, "/api/v1/users");
+        assert_eq!(handled_by(handler), "create_user");
+
+        // the parameter of the prefix is passed to the nested handler
+        let handler = router.find_handler(Method::Get, "/api/v1/users/42/orders/A1").unwrap();
+        assert_eq!(handled_by(Some(handler)), "order_details");
+        assert_eq!(handler.template, "/api/v1/users/{user_id:u64}/orders/{order_id}");
+        assert_eq!(handler.params_pos.get(&3).unwrap(), "user_id");
+        assert_eq!(handler.params_pos.get(&5).unwrap(), "order_id");
+
+        // the constraint of the prefix still applies
+        assert!(matches!(router.route(Method::Get, "/api/v1/users/bob/orders/A1"), RouteMatch::NotFound));
+
+        // methods of the parent and the nested router are combined
+        assert_eq!(router.allowed_methods("/api/v1/users"), vec![Method::Get, Method::Head, Method::Post, Method::Options]);
+        match router.route(Method::Post, "/api/v1/users/42/orders/A1") {
+            RouteMatch::MethodNotAllowed(allowed) => assert_eq!(allow_header(&allowed), "GET, HEAD, OPTIONS"),
+            _ => panic!("POST is not allowed on a nested GET route"),
+        }
+    }
+
+    #[test]
+    fn test_try_get() {
+        let handler: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("handler")
+        };
+
+        let mut router = Router::default();
+        for path in ["/a(b", "/x/{id:[}"] {
+            let error = router.try_get(path, handler).err().unwrap();
+            assert_eq!(error.path, path);
+        }
+        assert_eq!(router.routes().count(), 0);
+
+        router.try_get("/users/{user_id:u64}", handler).unwrap().name("user");
+        assert_eq!(router.url_for("user", &[("user_id", "1")]), Ok(String::from("/users/1")));
+    }
+
+    #[test]
+    fn test_percent_encode() {
+        assert_eq!(percent_encode("A1"), "A1");
+        assert_eq!(percent_encode("a b/c"), "a%20b%2Fc");
+        assert_eq!(percent_encode("ü?&"), "%C3%BC%3F%26");
+    }
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("A1"), Some(String::from("A1")));
+        assert_eq!(percent_decode("a%20b%2fc+d"), Some(String::from("a b/c+d")));
+        assert_eq!(percent_decode("%C3%BC%3F%26"), Some(String::from("ü?&")));
+        assert_eq!(percent_decode("%2"), None);
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%C3"), None);
+    }
+
+    #[test]
+    fn test_url_for() {
+        let handler: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("handler")
+        };
+
+        let mut orders = Router::default();
+        orders.get("/orders/{order_id}", handler).name("user_order");
+
+        let mut router = Router::default();
+        router.get("/home/", handler).name("home");
+        router.get("/users/{user_id:u64}", handler).name("user");
+        router.nest("/users/{user_id:u64}", orders);
+
+        assert_eq!(router.url_for("home", &[]), Ok(String::from("/home/")));
+        assert_eq!(router.url_for("user", &[("user_id", "1")]), Ok(String::from("/users/1")));
+        assert_eq!(router.url_for("user_order", &[("user_id", "1"), ("order_id", "A 1/2")]),
+                   Ok(String::from("/users/1/orders/A%201%2F2")));
+
+        assert_eq!(router.url_for("nothing", &[]), Err(UrlError::UnknownRoute(String::from("nothing"))));
+        assert_eq!(router.url_for("user_order", &[("user_id", "1")]),
+                   Err(UrlError::MissingParam(String::from("user_order"), String::from("order_id"))));
+        assert_eq!(router.url_for("user", &[("user_id", "1"), ("order_id", "A1")]),
+                   Err(UrlError::ExtraParam(String::from("user"), String::from("order_id"))));
+        assert_eq!(router.url_for("user", &[("user_id", "bob")]),
+                   Err(UrlError::InvalidParam(String::from("user"), String::from("user_id"), String::from("bob"))));
+        assert_eq!(router.url_for("user", &[]).unwrap_err().to_string(), "route `user` requires parameter `user_id`");
+    }
+
+    #[test]
+    fn test_routes_introspection() {
+        let handler: PlainHandler = |_: &HttpRequest| -> HttpResponse {
+            respond("handler")
+        };
+
+        let mut admin = Router::default();
+        admin.get("/stats", handler).name("admin_stats");
+
+        let mut router = Router::default();
+        router.post("/users", handler);
+        router.get("/users", handler).name("users");
+        router.get("/users/{user_id:u64}", handler);
+        router.nest("/admin", admin);
+
+        let routes: Vec<(Method, &str, Option<&str>)> = router.routes()
+            .map(|route_info| (route_info.method, route_info.template.as_str(), route_info.name.as_deref()))
+            .collect();
+        assert_eq!(routes, vec![
+            (Method::Get, "/users", Some("users")),
+            (Method::Post, "/users", None),
+            (Method::Get, "/users/{user_id:u64}", None),
+            (Method::Get, "/admin/stats", Some("admin_stats")),
+        ]);
+        assert!(router.routes().all(|route_info| route_info.registered_at.file().ends_with("router.rs")));
+
+        let table = router.to_string();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].starts_with("       Name Method Path"));
+        assert!(lines[1].starts_with("      users GET    /users "));
+        assert!(lines[2].starts_with("            POST   /users "));
+        assert!(lines[4].starts_with("admin_stats GET    /admin/stats "));
+    }
+
+    #[test]
+    fn test_closure_handlers() {
+        fn plain_function(_: &HttpRequest) -> HttpResponse {
+            respond("plain function")
+        }
+
+        let counter = Arc::new(AtomicUsize::new(0));
+        let visits = counter.clone();
+        let greeting = String::from("Hello");
+
+        let mut router = Router::default();
+        router.get("/plain", plain_function);
+        router.get("/greeting", move |_: &HttpRequest| respond(&greeting));
+        router.get("/visits", move |_: &HttpRequest| {
+            let visits = visits.fetch_add(1, Ordering::SeqCst) + 1;
+            respond(&visits.to_string())
+        });
+
+        assert_eq!(handled_by(router.find_handler(Method::Get, "/plain")), "plain function");
+        assert_eq!(handled_by(router.find_handler(Method::Get, "/greeting")), "Hello");
+        assert_eq!(handled_by(router.find_handler(Method::Get, "/visits")), "1");
+        assert_eq!(handled_by(router.find_handler(Method::Get, "/visits")), "2");
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+
+        let handler_names: Vec<&str> = router.routes().map(|route_info| route_info.handler_name).collect();
+        assert!(handler_names[0].ends_with("plain_function"));
+        assert!(handler_names[1].contains("{{closure}}"));
+    }
+
+    #[test]
+    fn test_router_state() {
+        struct AppState {
+            greeting: String,
+            visits: AtomicUsize,
+        }
+        struct AdminState {
+            secret: &'static str,
+        }
+
+        fn greeting(_: &HttpRequest, state: &AppState) -> HttpResponse {
+            respond(&state.greeting)
+        }
+
+        let state = AppState { greeting: String::from("Hello"), visits: AtomicUsize::new(0) };
+        let mut admin = Router::default().with_state(AdminState { secret: "42" });
+        admin.get("/secret", |_: &HttpRequest, state: &AdminState| respond(state.secret));
+
+        let mut router = Router::default().with_state(state);
+        router.get("/greeting", greeting);
+        router.get("/visits", |_: &HttpRequest, state: Arc<AppState>| {
+            let visits = state.visits.fetch_add(1, Ordering::SeqCst) + 1;
+            respond(&visits.to_string())
+        });
+        router.get("/plain", |_: &HttpRequest| respond("plain"));
+        router.nest("/admin", admin);
+
+        assert_eq!(handled_by(router.find_handler(Method::Get, "/greeting")), "Hello");
+        assert_eq!(handled_by(router.find_handler(Method::Get, "/visits")), "1");
+        assert_eq!(handled_by(router.find_handler(Method::Get, "/visits")), "2");
+        assert_eq!(handled_by(router.find_handler(Method::Get, "/plain")), "plain");
+        assert_eq!(handled_by(router.find_handler(Method::Get, "/admin/secret")), "42");
+        assert_eq!(router.state().visits.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_extractor_handlers() {
+        use crate::extract::{Header, Json, Path, Query, State, UserAgent};
+        use serde::Deserialize;
+
+        #[derive(Deserialize)]
+        struct OrderPath {
+            user_id: u64,
+            order_id: String,
+        }
+
+        #[derive(Deserialize)]
+        struct Pagination {
+            page: u32,
+        }
+
+        fn order(Path(path): Path<OrderPath>, State(prefix): State<String>) -> HttpResponse {
+            respond(&format!("{}{}/{}", prefix, path.user_id, path.order_id))
+        }
+
+        let mut router = Router::default().with_state(String::from("order:"));
+        router.get("/users/{user_id}/orders/{order_id}", order);
+        router.get("/page", |Query(pagination): Query<Pagination>| respond(&pagination.page.to_string()));
+        router.get("/agent", |Header(UserAgent(agent)): Header<UserAgent>| respond(&agent));
+        router.post("/echo", |Json(value): Json<serde_json::Value>| respond(&value.to_string()));
+        router.get("/ping", || respond("pong"));
+
+        assert_eq!(call(&router, "GET /users/1/orders/A1 HTTP/1.1\r\n\r\n"), (String::from("200"), String::from("order:1/A1")));
+        assert_eq!(call(&router, "GET /users/bob/orders/A1 HTTP/1.1\r\n\r\n"),
+                   (String::from("400"), String::from("Invalid path parameters: `bob` is not a valid u64")));
+        assert_eq!(call(&router, "GET /page?page=3 HTTP/1.1\r\n\r\n"), (String::from("200"), String::from("3")));
+        assert_eq!(call(&router, "GET /page HTTP/1.1\r\n\r\n"),
+                   (String::from("400"), String::from("Invalid query string: missing field `page`")));
+        assert_eq!(call(&router, "GET /agent HTTP/1.1\r\nUser-Agent: curl\r\n\r\n"), (String::from("200"), String::from("curl")));
+        assert_eq!(call(&router, "POST /echo HTTP/1.1\r\nContent-Type: application/json\r\n\r\n{\"a\":1}"),
+                   (String::from("200"), String::from("{\"a\":1}")));
+        assert_eq!(call(&router, "GET /ping HTTP/1.1\r\n\r\n"), (String::from("200"), String::from("pong")));
+    }
+
+    #[test]
+    fn test_fallible_handlers() {
+        use crate::extract::{Json, Path};
+        use http::httpresponse::{IntoResponse, StatusCode};
+        use serde::Serialize;
+
+        #[derive(Debug)]
+        enum AppError {
+            NotFound(u64),
+            Invalid(String),
+        }
+
+        impl IntoResponse for AppError {
+            fn into_response(self) -> HttpResponse {
+                match self {
+                    AppError::NotFound(id) => (StatusCode::NOT_FOUND, format!("no user {}", id)).into_response(),
+                    AppError::Invalid(reason) => (StatusCode::UNPROCESSABLE_ENTITY, reason).into_response(),
+                }
+            }
+        }
+
+        impl From<ParamError> for AppError {
+            fn from(e: ParamError) -> Self {
+                AppError::Invalid(e.to_string())
+            }
+        }
+
+        #[derive(Serialize)]
+        struct User {
+            id: u64,
+        }
+
+        fn find_user(id: u64) -> Result<User, AppError> {
+            match id {
+                1 => Ok(User { id }),
+                _ => Err(AppError::NotFound(id)),
+            }
+        }
+
+        fn user(Path(id): Path<u64>) -> Result<Json<User>, AppError> {
+            let user = find_user(id)?;
+            Ok(Json(user))
+        }
+
+        fn user_name(req: &HttpRequest) -> Result<(StatusCode, String), AppError> {
+            let id: u64 = req.param("id")?;
+            Ok((StatusCode::ACCEPTED, format!("user {}", find_user(id)?.id)))
+        }
+
+        let mut router = Router::default();
+        router.get("/users/{id}", user);
+        router.get("/names/{id}", user_name);
+        router.get("/static", |_: &HttpRequest| "static");
+
+        assert_eq!(call(&router, "GET /users/1 HTTP/1.1\r\n\r\n"), (String::from("200"), String::from("{\"id\":1}")));
+        assert_eq!(call(&router, "GET /users/2 HTTP/1.1\r\n\r\n"), (String::from("404"), String::from("no user 2")));
+        assert_eq!(call(&router, "GET /names/1 HTTP/1.1\r\n\r\n"), (String::from("202"), String::from("user 1")));
+        assert_eq!(call(&router, "GET /names/bob HTTP/1.1\r\n\r\n"),
+                   (String::from("422"), String::from("path parameter `id` has value `bob` which is not a valid u64: invalid digit found in string")));
+        assert_eq!(call(&router, "GET /static HTTP/1.1\r\n\r\n"), (String::from("200"), String::from("static")));
+    }
+
+    #[test]
+    fn test_nested_route_collision() {
+        let mut users = Router::default();
+        users.get("/{id}", |_: &HttpRequest| respond("nested"));
+
+        let mut router = Router::default();
+        router.get("/users/{user_id}", |_: &HttpRequest| respond("user"));
+        let collision = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| router.nest("/users", users)));
+        let message = *collision.unwrap_err().downcast::<String>().unwrap();
+        assert!(message.starts_with("Nested route GET /users/{id} registered at "), "{}", message);
+        assert!(message.contains("collides with /users/{user_id} registered at "), "{}", message);
+        assert_eq!(message.matches("router.rs:").count(), 2, "{}", message);
+    }
+
+    #[test]
+    fn test_nested_middleware() {
+        use crate::middleware::{Next, wrap};
+
+        let tag = |name: &'static str| move |request: &HttpRequest, next: Next| {
+            let response = next.run(request);
+            let body = String::from_utf8(response.body.unwrap()).unwrap();
+            respond(&format!("{}({})", name, body))
+        };
+
+        let mut users = Router::default();
+        users.layer(tag("users"));
+        users.get("/{id}", |_: &HttpRequest| respond("user")).layer(tag("route"));
+        users.get("/", |_: &HttpRequest| respond("list"));
+
+        let mut router = Router::default();
+        router.layer(tag("app"));
+        router.nest("/users", users);
+        router.get("/health", |_: &HttpRequest| respond("ok"));
+
+        let run = |path: &str| {
+            let route_info = router.find_handler(Method::Get, path).unwrap();
+            let handler = wrap(route_info.handler.clone(), &route_info.middleware);
+            let request = HttpRequest::parse(format!("GET {} HTTP/1.1\r\n\r\n", path).into_bytes()).unwrap();
+            let response = wrap(handler, router.middleware())(&request);
+            String::from_utf8(response.body.unwrap()).unwrap()
+        };
+        assert_eq!(run("/users/1"), "app(users(route(user)))");
+        assert_eq!(run("/users"), "app(users(list))");
+        assert_eq!(run("/health"), "app(ok)");
+    }
+}
@@ -1,103 +1,1473 @@
-use std::{collections::HashMap, io::Read, net::{TcpListener, TcpStream}, sync::{Arc, RwLock}, thread};
+use std::{any::Any, collections::HashMap, io::{self, ErrorKind, Read, Write}, net::{Shutdown, TcpListener, TcpStream}, sync::{Arc, Condvar, Mutex, RwLock, mpsc}, thread};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+#[cfg(feature = "tls")]
+use std::path::Path;
 
-use http::{httprequest::HttpRequest, httpresponse::HttpResponse};
-use crate::router::{Router, RouteHandler, normalize_path};
+use http::{httprequest::{HttpRequest, Method, PeerCertificate}, httpresponse::{HttpResponse, IntoResponse, StatusCode}};
+use crate::connection::{ConnectionError, FramingError, Limits, RequestParser, Socket, Transport};
+use crate::handler::Handler;
+use crate::http2;
+use crate::pool::{OverloadPolicy, ThreadPool};
+use crate::problem::Problem;
+use crate::middleware::{Middleware, Next, wrap};
+use crate::shutdown::ShutdownHandle;
+use crate::router::{Router, RouteHandler, RouteMatch, UrlError, allow_header};
+use crate::sse::{EventStream, EventStreamHandler};
+use crate::websocket::{WebSocket, WebSocketHandler};
+#[cfg(feature = "tls")]
+use crate::tls::{self, ServerConfig, TlsError};
 
-pub struct Server<'a> {
+#[cfg(feature = "tokio")]
+mod asynchronous;
+mod reactor;
+
+const DEFAULT_QUEUE_SIZE: usize = 128;
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_IO_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_KEEP_ALIVE_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_RESPONSE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_UPGRADES: usize = 1024;
+const DEFAULT_UPGRADE_IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+/// How many handlers of an HTTP/2 connection of the Threads backend run at
+/// once, and how many streams wait for them before the overload policy applies
+pub(crate) const HTTP2_STREAM_WORKERS: usize = 4;
+
+/// How the server waits for connections and requests. The routes, the
+/// middleware and the handlers work the same way with both.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Backend {
+    /// Blocking sockets, a worker of the pool serves one request per connection,
+    /// or an HTTP/2 connection whose streams are handled by a few workers of
+    /// its own. WebSockets and event streams leave the pool for a thread of their own.
+    #[default]
+    Threads,
+    /// Non-blocking sockets driven by an event loop (epoll on Linux, through
+    /// mio) that hands the requests to the workers. Connections are kept alive
+    /// between requests, idle ones only cost a socket.
+    Events,
+    /// A task per connection on a tokio runtime with as many threads as
+    /// workers, see [`Server::run_async`]. Async handlers are awaited without
+    /// blocking a thread, unless middleware runs around them.
+    #[cfg(feature = "tokio")]
+    Tokio,
+}
+
+pub struct Server<'a, S = ()> {
     socket_addr: &'a str,    
-    router: Arc<RwLock<Router>>,
+    service: Arc<Service<S>>,
+    backend: Backend,
+    workers: usize,
+    queue_size: usize, // connections waiting for a free worker
+    overload_policy: OverloadPolicy,
+    shutdown: ShutdownHandle,
+    drain_timeout: Duration, // how long the in-flight requests can take after a shutdown
+    io_timeout: Duration, // how long a worker of the Threads backend waits on a blocking read or write
+    timeouts: Timeouts, // of the Events and Tokio backends
+    limits: Limits, // of the size of the requests
+    upgrades: Upgrades, // the connections that handlers took over
+    #[cfg(feature = "tls")]
+    tls: Option<Arc<ServerConfig>>,
 }
 
-impl<'a> Server<'a> {
-    pub fn new(socket_addr: &'a str) -> Self {
-        Server {
-            socket_addr,            
-            router: Arc::new(RwLock::new(Router::default()))
+/// Builds the response of an error that the server answers by itself: 404 when
+/// no route matches, 405 when the route doesn't allow the method, 400 when
+/// the request can't be parsed and 503 when the server is overloaded. It receives the request and the default
+/// response, a [`Problem`] with the reason phrase as title, e.g. to keep the
+/// `Allow` header of a 405. Requests that can't be framed, see [`FramingError`],
+/// get a default request.
+pub type ErrorHandler = Arc<dyn Fn(&HttpRequest, HttpResponse) -> HttpResponse + Send + Sync>;
+
+/// A handler, or a middleware, that panicked while handling a request
+#[derive(Debug)]
+pub struct HandlerPanic {
+    pub method: Method,
+    pub route: Option<String>, // the template of the route, None if no route matched
+    pub message: String,
+}
+
+/// Builds the response of a request whose handler panicked, instead of the
+/// default `500 Internal Server Error`.
+pub type PanicHandler = Arc<dyn Fn(&HttpRequest, &HandlerPanic) -> HttpResponse + Send + Sync>;
+
+/// Reports the errors of the connections, e.g. to a log. By default the
+/// disconnects of the clients are ignored and the other errors are printed.
+pub type ConnectionErrorHandler = Arc<dyn Fn(&ConnectionError) + Send + Sync>;
+
+/// A request whose handler takes the connection over once the response is
+/// written, with the handler of its route and the slot of the connection
+pub(crate) struct Upgrade {
+    request: HttpRequest,
+    handler: UpgradeHandler,
+    slot: UpgradeSlot,
+}
+
+enum UpgradeHandler {
+    WebSocket(WebSocketHandler),
+    EventStream(EventStreamHandler),
+}
+
+/// Counts the connections that handlers took over, up to the maximum, see
+/// [`Server::with_max_upgrades`]. Clones share the count.
+#[derive(Clone)]
+pub(crate) struct Upgrades {
+    active: Arc<(Mutex<usize>, Condvar)>,
+    max: usize,
+    idle_timeout: Duration, // how long the client can stay quiet, or not read
+    shutdown: ShutdownHandle,
+}
+
+/// Counts a connection that a handler took over until it's dropped
+pub(crate) struct UpgradeSlot {
+    upgrades: Upgrades,
+}
+
+impl Upgrades {
+    fn new(shutdown: ShutdownHandle) -> Self {
+        Upgrades {
+            active: Arc::new((Mutex::new(0), Condvar::new())),
+            max: DEFAULT_MAX_UPGRADES,
+            idle_timeout: DEFAULT_UPGRADE_IDLE_TIMEOUT,
+            shutdown,
         }
-    }   
+    }
 
-    fn handle_connection(stream: &mut TcpStream, router: Arc<RwLock<Router>>) {
-        let mut raw_request: Vec<u8> = Vec::new();
-        let mut temp_buff = [0u8; 1024];
-        loop {
-            match stream.read(&mut temp_buff) {
-                Ok(0) => break,
-                Ok(n) => {
-                    raw_request.extend_from_slice(&temp_buff);
-                    if n < temp_buff.len() {
-                        break;
-                    }
+    /// None if the maximum is reached
+    fn reserve(&self) -> Option<UpgradeSlot> {
+        let mut active = self.active.0.lock().unwrap();
+        if *active >= self.max {
+            return None;
+        }
+        *active += 1;
+        Some(UpgradeSlot { upgrades: self.clone() })
+    }
+
+    /// Waits until the handlers returned or the timeout is over, returns how
+    /// many still run
+    pub(crate) fn wait(&self, timeout: Duration) -> usize {
+        let (active, returned) = &*self.active;
+        let (active, _) = returned.wait_timeout_while(active.lock().unwrap(), timeout, |active| *active > 0).unwrap();
+        *active
+    }
+}
+
+impl Default for Upgrades {
+    fn default() -> Self {
+        Upgrades::new(ShutdownHandle::default())
+    }
+}
+
+impl Drop for UpgradeSlot {
+    fn drop(&mut self) {
+        let (active, returned) = &*self.upgrades.active;
+        *active.lock().unwrap() -= 1;
+        returned.notify_all();
+    }
+}
+
+/// How long the Events and Tokio backends wait for the clients. The deadlines
+/// are absolute, a client can't push them back by sending a byte at a time.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Timeouts {
+    pub(crate) keep_alive: Duration, // between the requests
+    pub(crate) request: Duration, // from the first byte of a request until its body is received
+    pub(crate) response: Duration, // until the client took the whole response
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Timeouts {
+            keep_alive: DEFAULT_KEEP_ALIVE_TIMEOUT,
+            request: DEFAULT_REQUEST_TIMEOUT,
+            response: DEFAULT_RESPONSE_TIMEOUT,
+        }
+    }
+}
+
+/// How the workers of the Threads backend serve their connections
+#[derive(Default)]
+struct ThreadsSettings {
+    limits: Limits,
+    overload_policy: OverloadPolicy,
+    shutdown: ShutdownHandle,
+    upgrades: Upgrades,
+}
+
+/// Everything that is needed to turn a request into a response, shared by the
+/// connections.
+struct Service<S> {
+    router: RwLock<Router<S>>,
+    middleware: RwLock<Vec<Middleware>>,
+    fallback: RwLock<Option<RouteHandler>>,
+    error_handlers: RwLock<HashMap<StatusCode, ErrorHandler>>,
+    panic_handler: RwLock<Option<PanicHandler>>,
+    connection_error_handler: RwLock<Option<ConnectionErrorHandler>>,
+}
+
+impl<S> Service<S> {
+    fn new(router: Router<S>) -> Self {
+        Service {
+            router: RwLock::new(router),
+            middleware: RwLock::new(Vec::new()),
+            fallback: RwLock::new(None),
+            error_handlers: RwLock::new(HashMap::new()),
+            panic_handler: RwLock::new(None),
+            connection_error_handler: RwLock::new(None),
+        }
+    }
+
+    /// Routes the request and runs the handler inside the middleware chain. The
+    /// server middleware also runs for requests that don't match any route.
+    /// A panic in the chain is answered with a 500 instead of killing the connection.
+    fn handle(&self, request: &mut HttpRequest) -> HttpResponse {
+        let mut route = None;
+        let endpoint: RouteHandler = {
+            let router = self.router.read().unwrap();
+            match router.route(request.method, request.path()) {
+                RouteMatch::Found(route_info) => {
+                    route = Some(route_info.template.clone());
+                    // extract path parameters
+                    let path_params = route_info.extract_path_params(request.path());
+                    request.with_path_params(&path_params);
+
+                    let handler = wrap(route_info.handler.clone(), &route_info.middleware);
+                    wrap(handler, router.middleware())
+                },
+                RouteMatch::MethodNotAllowed(allowed) => {
+                    // OPTIONS without an explicit handler just lists the allowed methods
+                    let status = match request.method {
+                        Method::Options => StatusCode::NO_CONTENT,
+                        _ => StatusCode::METHOD_NOT_ALLOWED,
+                    };
+                    let allow = allow_header(&allowed);
+                    self.error_endpoint(status, Arc::new(move |_: &HttpRequest| {
+                        let response = match status {
+                            StatusCode::NO_CONTENT => HttpResponse::with_status(status, None),
+                            _ => Problem::new(status).into_response(),
+                        };
+                        response.with_header("Allow", &allow)
+                    }))
+                },
+                RouteMatch::NotFound => match &*self.fallback.read().unwrap() {
+                    Some(fallback) => fallback.clone(),
+                    None => self.error_endpoint(StatusCode::NOT_FOUND,
+                                                Arc::new(|_: &HttpRequest| Problem::new(StatusCode::NOT_FOUND).into_response())),
                 },
-                Err(_) => break,
             }
+        };
+
+        // the router lock is released before any handler runs
+        let middleware = self.middleware.read().unwrap().clone();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| wrap(endpoint, &middleware)(request)));
+        match result {
+            Ok(response) => response,
+            Err(payload) => self.panic_response(request, route, payload.as_ref()),
         }
+    }
 
-        fn extract_path_params(path: &str, params_pos: &HashMap<usize, String>) -> HashMap<String, String> {
-            let normalized_path = normalize_path(path);
-            let parts: Vec<&str> = normalized_path.split('/').collect();
-            let mut result = HashMap::new();
+    /// Logs the panic of a handler and builds the response with the panic handler
+    fn panic_response(&self, request: &HttpRequest, route: Option<String>, payload: &(dyn Any + Send)) -> HttpResponse {
+        let handler_panic = HandlerPanic {
+            method: request.method,
+            route,
+            message: panic_message(payload),
+        };
+        eprintln!("Handler of {} {} panicked: {}",
+                  handler_panic.method,
+                  handler_panic.route.as_deref().unwrap_or(request.path()),
+                  handler_panic.message);
+        match &*self.panic_handler.read().unwrap() {
+            Some(panic_handler) => panic_handler(request, &handler_panic),
+            None => Problem::new(StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+        }
+    }
 
-            let s:usize = 12;
-            for (position, param_name) in params_pos.iter() {
-                let param_value = parts[*position];
-                result.insert(param_name.to_owned(), param_value.to_owned());
+    /// Answers a raw request with the bytes of the response, so that they can
+    /// be written to any kind of connection. Also tells whether the connection
+    /// can be kept alive, which needs both the backend and the client to want it.
+    /// The request gets the certificate the client authenticated with, if any.
+    /// Once the response is written, the connection belongs to the handler of
+    /// the upgrade, if there's one.
+    fn respond(&self,
+               raw_request: Vec<u8>,
+               keep_alive: bool,
+               peer_certificate: Option<&PeerCertificate>,
+               upgrades: &Upgrades) -> (Vec<u8>, bool, Option<Upgrade>) {
+        match HttpRequest::parse(raw_request) {
+            Some(mut request) => {
+                request.peer_certificate = peer_certificate.cloned();
+                let keep_alive = keep_alive && request.keep_alive();
+                let response = self.handle(&mut request);
+                let method = request.method;
+                let (response, upgrade) = self.upgrade(request, response, upgrades);
+                // the connection is taken over rather than closed
+                let response = Self::serialize(method, response, keep_alive || upgrade.is_some());
+                (response, keep_alive, upgrade)
+            },
+            None => {
+                let bad_request = self.error_endpoint(StatusCode::BAD_REQUEST,
+                                                      Arc::new(|_: &HttpRequest| Problem::new(StatusCode::BAD_REQUEST).into_response()));
+                let request = HttpRequest::default();
+                (Self::serialize(request.method, bad_request(&request), false), false, None)
             }
+        }
+    }
 
-            return result;
+    /// The handler that takes the connection over, if the route has one and
+    /// the response, which went through the middleware, still lets it: the
+    /// WebSocket handshake was accepted or the event stream opened. Once the
+    /// maximum of connections taken over is reached, the response is a 503 instead.
+    fn upgrade(&self, request: HttpRequest, response: HttpResponse, upgrades: &Upgrades) -> (HttpResponse, Option<Upgrade>) {
+        let Some((request, handler)) = self.upgrade_handler(request, &response) else {
+            return (response, None);
+        };
+        match upgrades.reserve() {
+            Some(slot) => (response, Some(Upgrade { request, handler, slot })),
+            None => (self.unavailable(), None),
         }
+    }
 
-        let mut http_parse_result = HttpRequest::parse(raw_request);
-        match http_parse_result {
-            Some(ref mut request) => {                                
-                match router.read().unwrap().find_handler(request.method, &request.resource) {
-                    Some(route_info) => {
-                        let handler = route_info.handler;                        
-                        // extract path parameters
-                        let path_params = extract_path_params(&request.resource, &route_info.params_pos);
-                        request.with_path_params(&path_params);  
+    fn upgrade_handler(&self, mut request: HttpRequest, response: &HttpResponse) -> Option<(HttpRequest, UpgradeHandler)> {
+        if request.method != Method::Get {
+            return None;
+        }
+        let router = self.router.read().unwrap();
+        let route_info = router.find_handler(Method::Get, request.path())?;
+        let path_params = route_info.extract_path_params(request.path());
+        request.with_path_params(&path_params);
+        if response.status_code == StatusCode::SWITCHING_PROTOCOLS.to_string() {
+            let handler = route_info.websocket_handler.clone()?;
+            Some((request, UpgradeHandler::WebSocket(handler)))
+        } else if response.status_code == StatusCode::OK.to_string() {
+            let handler = route_info.event_stream_handler.clone()?;
+            Some((request, UpgradeHandler::EventStream(handler)))
+        } else {
+            None
+        }
+    }
 
-                        // execute the handler
-                        let response = handler(&request);                        
+    /// Whether the handler of the request takes the connection over, which
+    /// only works with HTTP/1.1
+    fn takes_connection(&self, request: &HttpRequest) -> bool {
+        if request.method != Method::Get {
+            return false;
+        }
+        let router = self.router.read().unwrap();
+        router.find_handler(Method::Get, request.path()).is_some_and(|route_info| {
+            route_info.websocket_handler.is_some() || route_info.event_stream_handler.is_some()
+        })
+    }
 
-                        response.send_response(stream).unwrap();
-                    },
-                    None => {
-                        let not_found = HttpResponse::new("404", None, None);
-                        not_found.send_response(stream).unwrap();        
+    /// Runs the handler that takes the connection over, `received` is what
+    /// the client sent past the request
+    fn run_upgrade(&self, upgrade: Upgrade, stream: &mut dyn Transport, received: Vec<u8>) {
+        let Upgrade { request, handler, slot } = upgrade;
+        let shutdown = slot.upgrades.shutdown.clone();
+        let result = match handler {
+            UpgradeHandler::WebSocket(handler) => {
+                let websocket = WebSocket::new(stream, received).with_shutdown(shutdown);
+                panic::catch_unwind(AssertUnwindSafe(|| handler(&request, websocket)))
+            },
+            UpgradeHandler::EventStream(handler) => {
+                let events = EventStream::new(stream, &request).with_shutdown(shutdown);
+                panic::catch_unwind(AssertUnwindSafe(|| handler(&request, events)))
+            },
+        };
+        if let Err(payload) = result {
+            eprintln!("Handler of {} panicked on the connection it took over: {}",
+                      request.path(),
+                      panic_message(payload.as_ref()));
+        }
+    }
+
+    fn serialize(method: Method, mut response: HttpResponse, keep_alive: bool) -> Vec<u8> {
+        if !keep_alive {
+            response = response.with_header("Connection", "close");
+        }
+        // writing to a vector can't fail
+        let mut written = Vec::new();
+        let _ = match method {
+            Method::Head => response.send_head_response(&mut written),
+            _ => response.send_response(&mut written),
+        };
+        written
+    }
+}
+
+/// What hands work over to other threads, which share the service
+impl<S: Send + Sync + 'static> Service<S> {
+    /// Reads a request from the connection and writes the response back.
+    /// Serves the connection with HTTP/2 instead if it starts with its preface.
+    /// Returns the handler that takes the connection over, if any, with what
+    /// the client sent past the request.
+    fn serve(self: &Arc<Self>,
+             stream: &mut impl Socket,
+             settings: &ThreadsSettings,
+             peer_certificate: Option<&PeerCertificate>) -> Result<Option<(Upgrade, Vec<u8>)>, ConnectionError> {
+        let mut parser = RequestParser::new(settings.limits);
+        let raw_request = match parser.read_request(stream)? {
+            Ok(raw_request) => raw_request,
+            Err(e) => {
+                stream.write_all(&self.framing_error(e))?;
+                stream.flush()?;
+                return Ok(None);
+            },
+        };
+        if http2::is_preface(&raw_request) {
+            let mut received = raw_request;
+            received.extend(parser.take_incomplete());
+            self.serve_http2(stream, received, settings, peer_certificate)?;
+            return Ok(None);
+        }
+        let (response, _, upgrade) = self.respond(raw_request, false, peer_certificate, &settings.upgrades);
+        stream.write_all(&response)?;
+        stream.flush()?;
+        Ok(upgrade.map(|upgrade| (upgrade, parser.take_incomplete())))
+    }
+
+    /// Like [`Service::serve`] on a plain connection of the Threads backend,
+    /// the connection leaves the worker if a handler takes it over
+    fn serve_plain(self: &Arc<Self>, mut stream: TcpStream, settings: &ThreadsSettings) -> Result<(), ConnectionError> {
+        if let Some((upgrade, received)) = self.serve(&mut stream, settings, None)? {
+            let socket = stream.try_clone()?;
+            self.spawn_upgrade(upgrade, Box::new(stream), socket, received, Vec::new());
+        }
+        Ok(())
+    }
+
+    /// Answers the streams of an HTTP/2 connection until the client closes it,
+    /// `received` starts with the preface. The handlers of the streams run on
+    /// workers of the connection, the overload policy applies once they're all
+    /// busy and their queue is full. Another thread reads the socket, so that
+    /// the connection waits for the client and the handlers at once.
+    fn serve_http2(self: &Arc<Self>,
+                   stream: &mut impl Socket,
+                   received: Vec<u8>,
+                   settings: &ThreadsSettings,
+                   peer_certificate: Option<&PeerCertificate>) -> Result<(), ConnectionError> {
+        let mut connection = http2::Connection::new(settings.limits.max_body_size);
+        let (sender, events) = mpsc::channel();
+        // the client is told which of its streams are still answered
+        let shutting_down = sender.clone();
+        let _listener = settings.shutdown.on_shutdown(move || {
+            let _ = shutting_down.send(Http2Event::ShuttingDown);
+        });
+        let service = self.clone();
+        let responses = sender.clone();
+        let workers = ThreadPool::new(HTTP2_STREAM_WORKERS, HTTP2_STREAM_WORKERS, move |(stream_id, mut request): (u32, HttpRequest)| {
+            let response = service.handle(&mut request);
+            let _ = responses.send(Http2Event::Responded(stream_id, response));
+        });
+        let socket = stream.try_clone_socket()?;
+        let reader = socket.try_clone()?;
+        thread::scope(|scope| {
+            thread::Builder::new().name(String::from("http2-reader"))
+                                  .spawn_scoped(scope, move || read_socket(reader, sender))?;
+            let served = (|| {
+                let mut received = received;
+                loop {
+                    for (stream_id, mut request) in connection.receive(&received) {
+                        // the client retries with HTTP/1.1
+                        if self.takes_connection(&request) {
+                            connection.reset(stream_id, http2::HTTP_1_1_REQUIRED);
+                            continue;
+                        }
+                        request.peer_certificate = peer_certificate.cloned();
+                        match settings.overload_policy {
+                            OverloadPolicy::Block => workers.execute((stream_id, request)),
+                            OverloadPolicy::Reject => {
+                                if workers.try_execute((stream_id, request)).is_err() {
+                                    connection.respond(stream_id, self.unavailable());
+                                }
+                            },
+                            // the client can retry the stream
+                            OverloadPolicy::Drop => {
+                                if workers.try_execute((stream_id, request)).is_err() {
+                                    connection.reset(stream_id, http2::REFUSED_STREAM);
+                                }
+                            },
+                        }
+                    }
+                    stream.write_all(&connection.take_output())?;
+                    stream.flush()?;
+                    if connection.is_closed() {
+                        return Ok(());
                     }
+                    // whichever comes first, the client or a handler
+                    received = match events.recv() {
+                        Ok(Http2Event::Responded(stream_id, response)) => {
+                            connection.respond(stream_id, response);
+                            Vec::new()
+                        },
+                        Ok(Http2Event::ShuttingDown) => {
+                            connection.go_away();
+                            Vec::new()
+                        },
+                        Ok(Http2Event::Received(Ok(bytes))) if bytes.is_empty() => return Ok(()),
+                        Ok(Http2Event::Received(Ok(bytes))) => stream.decode(&bytes)?,
+                        // an idle connection is closed, one whose requests are handled isn't
+                        Ok(Http2Event::Received(Err(e))) if is_timeout(&e) && connection.has_streams() => Vec::new(),
+                        Ok(Http2Event::Received(Err(e))) if is_timeout(&e) => return Ok(()),
+                        Ok(Http2Event::Received(Err(e))) => return Err(e),
+                        Err(_) => return Ok(()),
+                    };
                 }
-            },
-            None => {
-                let bad_request = HttpResponse::new("400", None, None);
-                bad_request.send_response(stream).unwrap();
+            })();
+            // stops the reader, responses can still be written
+            let _ = socket.shutdown(Shutdown::Read);
+            served
+        })?;
+        // waits for the handlers that still run
+        drop(workers);
+        Ok(())
+    }
+
+    /// Like [`Service::serve_plain`] on a connection encrypted with the configuration
+    #[cfg(feature = "tls")]
+    fn serve_tls(self: &Arc<Self>,
+                 config: &Arc<ServerConfig>,
+                 stream: TcpStream,
+                 settings: &ThreadsSettings) -> Result<(), ConnectionError> {
+        let connection = rustls::ServerConnection::new(config.clone()).map_err(std::io::Error::other)?;
+        let mut stream = rustls::StreamOwned::new(connection, stream);
+        // the handshake is over before the request is read, so that the client certificate is known
+        while stream.conn.is_handshaking() {
+            stream.conn.complete_io(&mut stream.sock)?;
+        }
+        let peer_certificate = tls::peer_certificate(&stream.conn);
+        if let Some((upgrade, received)) = self.serve(&mut stream, settings, peer_certificate.as_ref())? {
+            let socket = stream.sock.try_clone()?;
+            self.spawn_upgrade(upgrade, Box::new(stream), socket, received, Vec::new());
+            return Ok(());
+        }
+        stream.conn.send_close_notify();
+        stream.flush()?;
+        Ok(())
+    }
+
+    /// Runs the handler that takes the connection over on a thread of its own,
+    /// so that long-lived connections don't hold the workers that answer the
+    /// requests. `response` is what is left to write of the response first,
+    /// `socket` is a clone of the one of the stream. The client is closed once
+    /// it stays quiet, or doesn't read, for the idle timeout. When the server
+    /// shuts down, the reads of the handler fail so that it returns.
+    fn spawn_upgrade(self: &Arc<Self>,
+                     upgrade: Upgrade,
+                     mut stream: Box<dyn Transport + Send>,
+                     socket: TcpStream,
+                     received: Vec<u8>,
+                     response: Vec<u8>) {
+        let idle_timeout = Some(upgrade.slot.upgrades.idle_timeout);
+        if let Err(e) = socket.set_read_timeout(idle_timeout).and_then(|_| socket.set_write_timeout(idle_timeout)) {
+            self.report(&e.into());
+            return;
+        }
+        let service = self.clone();
+        let spawned = thread::Builder::new().name(String::from("upgraded")).spawn(move || {
+            let _listener = upgrade.slot.upgrades.shutdown.on_shutdown(move || {
+                let _ = socket.shutdown(Shutdown::Read);
+            });
+            match stream.write_all(&response).and_then(|_| stream.flush()) {
+                Ok(()) => service.run_upgrade(upgrade, &mut *stream, received),
+                Err(e) => service.report(&e.into()),
             }
-        }        
+        });
+        if let Err(e) = spawned {
+            self.report(&e.into());
+        }
     }
+}
 
-    pub fn run(&self) {
+impl<S> Service<S> {
+    /// The response to a request that can't be framed, the connection is closed after it
+    fn framing_error(&self, error: FramingError) -> Vec<u8> {
+        let status = error.status();
+        let endpoint = self.error_endpoint(status, Arc::new(move |_: &HttpRequest| Problem::new(status).into_response()));
+        Self::serialize(Method::Uninitialized, endpoint(&HttpRequest::default()), false)
+    }
+
+    /// The response to a connection that no worker can take
+    fn rejection(&self) -> Vec<u8> {
+        Self::serialize(Method::Uninitialized, self.unavailable(), false)
+    }
+
+    /// The response to a request that no worker can take
+    fn unavailable(&self) -> HttpResponse {
+        let service_unavailable = self.error_endpoint(StatusCode::SERVICE_UNAVAILABLE,
+                                                      Arc::new(|_: &HttpRequest| Problem::new(StatusCode::SERVICE_UNAVAILABLE).into_response()));
+        service_unavailable(&HttpRequest::default())
+    }
+
+    fn report(&self, error: &ConnectionError) {
+        match &*self.connection_error_handler.read().unwrap() {
+            Some(connection_error_handler) => connection_error_handler(error),
+            None if error.is_disconnect() => (),
+            None => eprintln!("Connection failed: {}", error),
+        }
+    }
+
+    /// Passes the default response through the error handler of the status, if any
+    fn error_endpoint(&self, status: StatusCode, default: RouteHandler) -> RouteHandler {
+        match self.error_handlers.read().unwrap().get(&status) {
+            Some(error_handler) => {
+                let error_handler = error_handler.clone();
+                Arc::new(move |request: &HttpRequest| error_handler(request, default(request)))
+            },
+            None => default,
+        }
+    }
+}
+
+/// What an HTTP/2 connection of the Threads backend waits for
+enum Http2Event {
+    /// What the reader read from the socket, nothing once the client closed it
+    Received(io::Result<Vec<u8>>),
+    /// The response of a stream, from a worker of the connection
+    Responded(u32, HttpResponse),
+    /// The server shuts down
+    ShuttingDown,
+}
+
+/// Reads the socket of an HTTP/2 connection until the client closes it or the
+/// connection stops the reads. Timeouts are passed on, the connection decides
+/// whether it's idle.
+fn read_socket(mut socket: TcpStream, events: mpsc::Sender<Http2Event>) {
+    let mut temp_buff = [0u8; 4096];
+    loop {
+        let received = match socket.read(&mut temp_buff) {
+            Ok(n) => Ok(temp_buff[..n].to_vec()),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => Err(e),
+        };
+        let last = match &received {
+            Ok(bytes) => bytes.is_empty(),
+            Err(e) => !is_timeout(e),
+        };
+        if events.send(Http2Event::Received(received)).is_err() || last {
+            return;
+        }
+    }
+}
+
+fn is_timeout(error: &io::Error) -> bool {
+    matches!(error.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// The message that was passed to `panic!`, the payload is a `&str` or a `String` for all of them
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        String::from("unknown panic payload")
+    }
+}
+
+impl<'a> Server<'a> {
+    pub fn new(socket_addr: &'a str) -> Self {
+        let shutdown = ShutdownHandle::default();
+        Server {
+            socket_addr,            
+            service: Arc::new(Service::new(Router::default())),
+            backend: Backend::default(),
+            workers: thread::available_parallelism().map(|n| n.get() * 4).unwrap_or(16),
+            queue_size: DEFAULT_QUEUE_SIZE,
+            overload_policy: OverloadPolicy::default(),
+            upgrades: Upgrades::new(shutdown.clone()),
+            shutdown,
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+            io_timeout: DEFAULT_IO_TIMEOUT,
+            timeouts: Timeouts::default(),
+            limits: Limits::default(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }   
+
+    /// A server that only accepts HTTPS connections, with the certificate
+    /// chain and the private key of the PEM files. It negotiates `h2` or
+    /// `http/1.1` with ALPN.
+    #[cfg(feature = "tls")]
+    pub fn bind_tls(socket_addr: &'a str, cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Self, TlsError> {
+        Ok(Server::new(socket_addr).with_tls(tls::server_config(cert_path, key_path)?))
+    }
+}
+
+impl<'a, S: Send + Sync + 'static> Server<'a, S> {
+    /// Sets the state that is shared by the handlers, which receive it along with
+    /// the request as `&T` or `Arc<T>`, e.g. `fn handler(req: &HttpRequest, state: &T)`.
+    pub fn with_state<T>(self, state: T) -> Server<'a, T> {
+        let service = Arc::into_inner(self.service)
+                          .expect("The state must be set before the server runs");
+        let router = service.router.into_inner().unwrap();
+        Server {
+            socket_addr: self.socket_addr,
+            backend: self.backend,
+            workers: self.workers,
+            queue_size: self.queue_size,
+            overload_policy: self.overload_policy,
+            shutdown: self.shutdown,
+            drain_timeout: self.drain_timeout,
+            io_timeout: self.io_timeout,
+            timeouts: self.timeouts,
+            limits: self.limits,
+            upgrades: self.upgrades,
+            #[cfg(feature = "tls")]
+            tls: self.tls,
+            service: Arc::new(Service {
+                router: RwLock::new(router.with_state(state)),
+                middleware: service.middleware,
+                fallback: service.fallback,
+                error_handlers: service.error_handlers,
+                panic_handler: service.panic_handler,
+                connection_error_handler: service.connection_error_handler,
+            }),
+        }
+    }
+
+    /// Sets the handler of the requests that don't match any route, instead of
+    /// the default 404 response.
+    pub fn fallback<H, M>(&self, handler: H)
+    where
+        H: Handler<S, M>,
+    {
+        let state = self.service.router.read().unwrap().state().clone();
+        *self.service.fallback.write().unwrap() = Some(handler.into_route_handler(state));
+    }
+
+    /// Customizes the responses with the status that the server answers by
+    /// itself, see [`ErrorHandler`].
+    pub fn error_handler<E>(&self, status: StatusCode, error_handler: E)
+    where
+        E: Fn(&HttpRequest, HttpResponse) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.service.error_handlers.write().unwrap().insert(status, Arc::new(error_handler));
+    }
+
+    /// Adds middleware that runs around every request, including the ones that
+    /// don't match any route. See [`Middleware`] for the order they run in.
+    pub fn layer<M>(&self, middleware: M)
+    where
+        M: Fn(&HttpRequest, Next<'_>) -> HttpResponse + Send + Sync + 'static,
+    {
+        self.service.middleware.write().unwrap().push(Arc::new(middleware));
+    }
+
+    /// Customizes the response when a handler panics. The panic is logged either way.
+    pub fn on_panic<P>(&self, panic_handler: P)
+    where
+        P: Fn(&HttpRequest, &HandlerPanic) -> HttpResponse + Send + Sync + 'static,
+    {
+        *self.service.panic_handler.write().unwrap() = Some(Arc::new(panic_handler));
+    }
+
+    /// Sets the hook that receives the errors of the connections, see [`ConnectionErrorHandler`].
+    pub fn on_connection_error<E>(&self, connection_error_handler: E)
+    where
+        E: Fn(&ConnectionError) + Send + Sync + 'static,
+    {
+        *self.service.connection_error_handler.write().unwrap() = Some(Arc::new(connection_error_handler));
+    }
+
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    /// Sets the number of threads that handle the connections, by default four
+    /// per CPU.
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// Sets how many accepted connections can wait for a free worker before
+    /// the overload policy applies.
+    pub fn with_queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    pub fn with_overload_policy(mut self, overload_policy: OverloadPolicy) -> Self {
+        self.overload_policy = overload_policy;
+        self
+    }
+
+    /// Sets how long the in-flight requests can take once the server is shut
+    /// down, 30 seconds by default.
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// Sets how long the Threads backend waits for a client to send or to read
+    /// a part of the request or of the response, including the TLS handshake,
+    /// before it closes the connection, 30 seconds by default. Otherwise clients
+    /// that connect and send nothing would hold the workers.
+    pub fn with_io_timeout(mut self, io_timeout: Duration) -> Self {
+        self.io_timeout = io_timeout;
+        self
+    }
+
+    /// Sets how long the Events and Tokio backends keep an idle connection
+    /// open for the next request, 60 seconds by default
+    pub fn with_keep_alive_timeout(mut self, keep_alive_timeout: Duration) -> Self {
+        self.timeouts.keep_alive = keep_alive_timeout;
+        self
+    }
+
+    /// Sets how long the Events and Tokio backends wait for the whole request,
+    /// the head and the body, once its first byte arrived, 30 seconds by default.
+    /// Otherwise clients that send a byte at a time would keep the connection.
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.timeouts.request = request_timeout;
+        self
+    }
+
+    /// Sets how long the Events and Tokio backends wait for the client to take
+    /// the whole response, 30 seconds by default. Otherwise clients that don't
+    /// read would keep the connection.
+    pub fn with_response_timeout(mut self, response_timeout: Duration) -> Self {
+        self.timeouts.response = response_timeout;
+        self
+    }
+
+    /// Sets the size of the longest head, the request line and the headers,
+    /// that the server reads, 16 KiB by default. Longer ones are answered with
+    /// `431 Request Header Fields Too Large`.
+    pub fn with_max_head_size(mut self, max_head_size: usize) -> Self {
+        self.limits.max_head_size = max_head_size;
+        self
+    }
+
+    /// Sets the size of the longest body that the server reads, 16 MiB by
+    /// default. Requests with a longer `Content-Length` are answered with
+    /// `413 Payload Too Large` before their body is read.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.limits.max_body_size = max_body_size;
+        self
+    }
+
+    /// Sets how many connections the handlers of WebSockets and event streams
+    /// can hold at once, 1024 by default. They run on threads of their own,
+    /// more are answered with `503 Service Unavailable`.
+    pub fn with_max_upgrades(mut self, max_upgrades: usize) -> Self {
+        self.upgrades.max = max_upgrades;
+        self
+    }
+
+    /// Sets how long the client of a WebSocket or of an event stream can stay
+    /// quiet, or not read what is sent, before the reads or the writes of the
+    /// handler fail, 5 minutes by default
+    pub fn with_upgrade_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.upgrades.idle_timeout = idle_timeout;
+        self
+    }
+
+    /// Serves HTTPS with the configuration, e.g. one built with
+    /// [`tls::server_config`] and customized. See [`Server::bind_tls`].
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, config: Arc<ServerConfig>) -> Self {
+        self.tls = Some(config);
+        self
+    }
+
+    /// A handle that stops the server, [`Server::run`] returns once the
+    /// in-flight requests are done or the drain deadline is over.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// None if the server was shut down before it listened: the shutdown
+    /// couldn't wake up the first accept since the address wasn't known yet.
+    fn bind(&self) -> Option<TcpListener> {
         let listener = TcpListener::bind(self.socket_addr)
-                                        .expect(format!("Couldn't bind to address {}", self.socket_addr).as_str());
+                                        .unwrap_or_else(|_| panic!("Couldn't bind to address {}", self.socket_addr));
+        if let Ok(local_addr) = listener.local_addr() {
+            self.shutdown.set_local_addr(local_addr);
+        }
+        match self.shutdown.is_shutdown() {
+            true => None,
+            false => Some(listener),
+        }
+    }
+
+    pub fn run(&self) {
+        let Some(listener) = self.bind() else { return };
+        match self.backend {
+            Backend::Threads => self.run_threads(listener),
+            Backend::Events => reactor::run(self, listener),
+            #[cfg(feature = "tokio")]
+            Backend::Tokio => {
+                tokio::runtime::Builder::new_multi_thread().worker_threads(self.workers)
+                                                           .enable_all()
+                                                           .build()
+                                                           .expect("Couldn't start the tokio runtime")
+                                                           .block_on(self.run_tokio(listener))
+            },
+        }
+    }
+
+    fn run_threads(&self, listener: TcpListener) {
+        let service = self.service.clone();
+        #[cfg(feature = "tls")]
+        let tls = self.tls.clone();
+        let settings = ThreadsSettings {
+            limits: self.limits,
+            overload_policy: self.overload_policy,
+            shutdown: self.shutdown.clone(),
+            upgrades: self.upgrades.clone(),
+        };
+        let pool = ThreadPool::new(self.workers, self.queue_size, move |stream: TcpStream| {
+            #[cfg(feature = "tls")]
+            let served = match &tls {
+                Some(config) => service.serve_tls(config, stream, &settings),
+                None => service.serve_plain(stream, &settings),
+            };
+            #[cfg(not(feature = "tls"))]
+            let served = service.serve_plain(stream, &settings);
+            if let Err(e) = served {
+                service.report(&e);
+            }
+        });
+
         for new_connection in listener.incoming() {
-            match new_connection {
-                Ok(mut stream) => {
-                    let router = self.router.clone();
-                    thread::spawn(move || Server::handle_connection(&mut stream, router));
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            let stream = new_connection.and_then(|stream| {
+                stream.set_read_timeout(Some(self.io_timeout))?;
+                stream.set_write_timeout(Some(self.io_timeout))?;
+                Ok(stream)
+            });
+            match stream {
+                Ok(stream) => match self.overload_policy {
+                    OverloadPolicy::Block => pool.execute(stream),
+                    OverloadPolicy::Reject => {
+                        if let Err(mut stream) = pool.try_execute(stream) {
+                            // a client that doesn't read can't hold the accept loop back,
+                            // the response fits in the buffer of the socket anyway
+                            let rejection = self.service.rejection();
+                            if let Err(e) = stream.set_nonblocking(true).and_then(|_| stream.write_all(&rejection)) {
+                                self.service.report(&e.into());
+                            }
+                        }
+                    },
+                    OverloadPolicy::Drop => {
+                        // the connection is closed when it's dropped
+                        let _ = pool.try_execute(stream);
+                    },
                 },
-                Err(e) => {
-                    eprintln!("Connection failed: {}", e);
-                }
-            };            
+                Err(e) => self.service.report(&e.into()),
+            };
+        }
+
+        // no new connections while the in-flight requests finish
+        drop(listener);
+        let drain_deadline = Instant::now() + self.drain_timeout;
+        let busy = pool.shutdown(self.drain_timeout);
+        if busy > 0 {
+            eprintln!("{} requests were still in flight after the drain deadline", busy);
+        }
+        self.wait_for_upgrades(drain_deadline);
+    }
+
+    /// Lets the handlers that took connections over close them, until the
+    /// drain deadline
+    fn wait_for_upgrades(&self, drain_deadline: Instant) {
+        let running = self.upgrades.wait(drain_deadline.saturating_duration_since(Instant::now()));
+        if running > 0 {
+            eprintln!("{} connections were still taken over after the drain deadline", running);
         }
     }
 
-    pub fn get(&self, path: &str, handler: RouteHandler) {
-        let mut router = self.router.write().unwrap();
+    #[track_caller]
+    pub fn get<H, M>(&self, path: &str, handler: H)
+    where
+        H: Handler<S, M>,
+    {
+        let mut router = self.service.router.write().unwrap();
         router.get(path, handler);
     }
 
-    pub fn post(&self, path: &str, handler: RouteHandler) {
-        let mut router = self.router.write().unwrap();
+    #[track_caller]
+    pub fn post<H, M>(&self, path: &str, handler: H)
+    where
+        H: Handler<S, M>,
+    {
+        let mut router = self.service.router.write().unwrap();
         router.post(path, handler);
     }
 
-}
\ No newline at end of file
+    /// Registers a WebSocket endpoint, see [`Router::websocket`]
+    #[track_caller]
+    pub fn websocket<F>(&self, path: &str, handler: F)
+    where
+        F: Fn(&HttpRequest, WebSocket<'_>) + Send + Sync + 'static,
+    {
+        let mut router = self.service.router.write().unwrap();
+        router.websocket(path, handler);
+    }
+
+    /// Registers a Server-Sent Events endpoint, see [`Router::sse`]
+    #[track_caller]
+    pub fn sse<F>(&self, path: &str, handler: F)
+    where
+        F: Fn(&HttpRequest, EventStream<'_>) + Send + Sync + 'static,
+    {
+        let mut router = self.service.router.write().unwrap();
+        router.sse(path, handler);
+    }
+
+    /// The route table in a printable form, see the `Display` implementation of [`Router`].
+    pub fn routes(&self) -> String {
+        self.service.router.read().unwrap().to_string()
+    }
+
+    /// Builds the path of a named route, see [`Router::url_for`].
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String, UrlError> {
+        self.service.router.read().unwrap().url_for(name, params)
+    }
+
+    /// An empty router with the state of the server, to build a new set of
+    /// routes for [`Server::replace_router`].
+    pub fn new_router(&self) -> Router<S> {
+        let state = self.service.router.read().unwrap().state().clone();
+        Router::default().with_shared_state(state)
+    }
+
+    /// Swaps all the routes of the server while it runs, e.g. to reload them
+    /// from a configuration file. Requests in flight finish with the old routes.
+    pub fn replace_router(&self, router: Router<S>) {
+        *self.service.router.write().unwrap() = router;
+    }
+
+    /// Mounts a separately built router under `prefix`, see [`Router::nest`].
+    #[track_caller]
+    pub fn nest<T>(&self, prefix: &str, router: Router<T>) {
+        let mut server_router = self.service.router.write().unwrap();
+        server_router.nest(prefix, router);
+    }
+
+    #[track_caller]
+    pub fn head<H, M>(&self, path: &str, handler: H)
+    where
+        H: Handler<S, M>,
+    {
+        let mut router = self.service.router.write().unwrap();
+        router.head(path, handler);
+    }
+
+    #[track_caller]
+    pub fn options<H, M>(&self, path: &str, handler: H)
+    where
+        H: Handler<S, M>,
+    {
+        let mut router = self.service.router.write().unwrap();
+        router.options(path, handler);
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::sync::Mutex;
+    use http::httpresponse::IntoResponse;
+    use crate::testing::{self, BACKENDS};
+
+    fn handle<S>(server: &Server<S>, raw_request: &str) -> HttpResponse {
+        let mut request = HttpRequest::parse(raw_request.as_bytes().to_vec()).unwrap();
+        server.service.handle(&mut request)
+    }
+
+    #[test]
+    fn test_middleware_chain() {
+        let trace = Arc::new(Mutex::new(Vec::new()));
+        let tracing = |name: &'static str| {
+            let trace = trace.clone();
+            move |request: &HttpRequest, next: Next| {
+                trace.lock().unwrap().push(name);
+                next.run(request)
+            }
+        };
+
+        let mut api = Router::default();
+        api.layer(tracing("api"));
+        api.get("/users/{id}", |req: &HttpRequest| req.path_params["id"].clone())
+           .layer(tracing("route"));
+
+        let server = Server::new("localhost:3000");
+        server.layer(tracing("server 1"));
+        server.nest("/api", api);
+        server.layer(tracing("server 2"));
+
+        let response = handle(&server, "GET /api/users/7 HTTP/1.1\r\n\r\n");
+        assert_eq!(response.body, Some(b"7".to_vec()));
+        assert_eq!(*trace.lock().unwrap(), vec!["server 1", "server 2", "api", "route"]);
+
+        // only the server middleware runs when no route matches
+        trace.lock().unwrap().clear();
+        assert_eq!(handle(&server, "GET /missing HTTP/1.1\r\n\r\n").status_code, "404");
+        assert_eq!(handle(&server, "POST /api/users/7 HTTP/1.1\r\n\r\n").status_code, "405");
+        assert_eq!(*trace.lock().unwrap(), vec!["server 1", "server 2", "server 1", "server 2"]);
+    }
+
+    #[test]
+    fn test_allowed_methods() {
+        let server = Server::new("localhost:3000");
+        server.get("/users/{id:u32}", |_: &HttpRequest| "user");
+        server.post("/users/{id:u32}", |_: &HttpRequest| "updated");
+
+        // just the methods, there's no body to describe
+        let response = handle(&server, "OPTIONS /users/7 HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code, "204");
+        assert_eq!(response.headers, Some(HashMap::from([(String::from("Allow"), String::from("GET, HEAD, POST, OPTIONS"))])));
+        assert_eq!(response.body, None);
+
+        let response = handle(&server, "PUT /users/7 HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code, "405");
+        assert_eq!(response.headers.as_ref().unwrap().get("Allow").unwrap(), "GET, HEAD, POST, OPTIONS");
+        assert_eq!(response.headers.as_ref().unwrap().get("Content-Type").unwrap(), "application/problem+json");
+    }
+
+    #[test]
+    fn test_default_error_responses() {
+        let server = Server::new("localhost:3000");
+        server.get("/panic", |_: &HttpRequest| -> &'static str { panic!("boom") });
+
+        // the errors the server answers by itself describe themselves
+        for (raw_request, status, title) in [("GET /missing HTTP/1.1\r\n\r\n", 404, "Not Found"),
+                                             ("GET /panic HTTP/1.1\r\n\r\n", 500, "Internal Server Error")] {
+            let response = handle(&server, raw_request);
+            assert_eq!(response.headers.as_ref().unwrap().get("Content-Type").unwrap(), "application/problem+json");
+            let problem: serde_json::Value = serde_json::from_slice(&response.body.unwrap()).unwrap();
+            assert_eq!(problem, serde_json::json!({ "type": "about:blank", "title": title, "status": status }));
+        }
+        let unavailable = String::from_utf8(server.service.rejection()).unwrap();
+        assert!(unavailable.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(unavailable.ends_with(r#"{"type":"about:blank","title":"Service Unavailable","status":503}"#));
+    }
+
+    #[test]
+    fn test_fallback() {
+        let server = Server::new("localhost:3000");
+        server.get("/hello", |_: &HttpRequest| "hello");
+        assert_eq!(handle(&server, "GET /missing HTTP/1.1\r\n\r\n").status_code, "404");
+
+        server.fallback(|req: &HttpRequest| (StatusCode::NOT_FOUND, format!("nothing at {}", req.path())));
+        let response = handle(&server, "GET /missing?page=2 HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code, "404");
+        assert_eq!(response.body, Some(b"nothing at /missing".to_vec()));
+        assert_eq!(handle(&server, "GET /hello HTTP/1.1\r\n\r\n").body, Some(b"hello".to_vec()));
+    }
+
+    #[test]
+    fn test_error_handlers() {
+        use crate::problem::Problem;
+
+        let server = Server::new("localhost:3000");
+        server.get("/hello", |_: &HttpRequest| "hello");
+        server.error_handler(StatusCode::NOT_FOUND, |req: &HttpRequest, response: HttpResponse| {
+            match req.header_value("Accept") {
+                Some(accept) if accept.contains("json") => Problem::new(StatusCode::NOT_FOUND)
+                                                               .with_instance(req.path())
+                                                               .into_response(),
+                _ => HttpResponse { body: Some(b"<h1>Page not found</h1>".to_vec()), ..response },
+            }
+        });
+        server.error_handler(StatusCode::METHOD_NOT_ALLOWED, |_: &HttpRequest, response: HttpResponse| {
+            HttpResponse { body: Some(b"not allowed".to_vec()), ..response }
+        });
+
+        let response = handle(&server, "GET /missing HTTP/1.1\r\n\r\n");
+        assert_eq!(response.body, Some(b"<h1>Page not found</h1>".to_vec()));
+
+        let response = handle(&server, "GET /missing HTTP/1.1\r\nAccept: application/json\r\n\r\n");
+        assert_eq!(response.status_code, "404");
+        assert_eq!(response.headers.as_ref().unwrap().get("Content-Type").unwrap(), "application/problem+json");
+        assert_eq!(response.body, Some(br#"{"type":"about:blank","title":"Not Found","status":404,"instance":"/missing"}"#.to_vec()));
+
+        // the default response is passed along, so the Allow header can be kept
+        let response = handle(&server, "POST /hello HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code, "405");
+        assert_eq!(response.headers.as_ref().unwrap().get("Allow").unwrap(), "GET, HEAD, OPTIONS");
+        assert_eq!(response.body, Some(b"not allowed".to_vec()));
+
+        // responses of the handlers are left alone
+        server.get("/gone", |_: &HttpRequest| (StatusCode::NOT_FOUND, "gone"));
+        assert_eq!(handle(&server, "GET /gone HTTP/1.1\r\n\r\n").body, Some(b"gone".to_vec()));
+    }
+
+    #[test]
+    fn test_handler_panic() {
+        let server = Server::new("localhost:3000");
+        server.get("/users/{id}", |req: &HttpRequest| -> String {
+            let id: u64 = req.path_params["id"].parse().unwrap();
+            format!("user {}", id)
+        });
+
+        let response = handle(&server, "GET /users/1 HTTP/1.1\r\n\r\n");
+        assert_eq!(response.body, Some(b"user 1".to_vec()));
+        let response = handle(&server, "GET /users/bob HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code, "500");
+        assert_eq!(response.status_text, "Internal Server Error");
+
+        server.on_panic(|req: &HttpRequest, handler_panic: &HandlerPanic| {
+            let body = format!("{} {} of {} failed: {}", handler_panic.method, handler_panic.route.as_deref().unwrap(),
+                               req.path(), handler_panic.message);
+            (StatusCode::INTERNAL_SERVER_ERROR, body).into_response()
+        });
+        let response = handle(&server, "GET /users/bob HTTP/1.1\r\n\r\n");
+        assert_eq!(response.status_code, "500");
+        assert_eq!(response.body, Some(b"GET /users/{id} of /users/bob failed: called `Result::unwrap()` on an `Err` value: ParseIntError { kind: InvalidDigit }".to_vec()));
+
+        // the server keeps working after a panic
+        assert_eq!(handle(&server, "GET /users/2 HTTP/1.1\r\n\r\n").body, Some(b"user 2".to_vec()));
+    }
+
+    /// A client that sends the request and then goes away
+    struct GoneClient {
+        request: &'static [u8],
+    }
+
+    impl Read for GoneClient {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let n = self.request.len().min(buf.len());
+            buf[..n].copy_from_slice(&self.request[..n]);
+            self.request = &self.request[n..];
+            Ok(n)
+        }
+    }
+
+    impl Socket for GoneClient {}
+
+    impl Write for GoneClient {
+        fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::BrokenPipe.into())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// A client that is still there, the response is written to the vector
+    struct ReadWrite<'a>(&'a mut dyn Read, &'a mut Vec<u8>);
+
+    impl Socket for ReadWrite<'_> {}
+
+    impl Read for ReadWrite<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl Write for ReadWrite<'_> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.1.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_connection_errors() {
+        let server = Server::new("localhost:3000");
+        server.get("/hello", |_: &HttpRequest| "hello");
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let reported = errors.clone();
+        server.on_connection_error(move |error: &ConnectionError| reported.lock().unwrap().push(error.is_disconnect()));
+
+        let mut client = GoneClient { request: b"GET /hello HTTP/1.1\r\n\r\n" };
+        let error = server.service.serve(&mut client, &ThreadsSettings::default(), None).err().unwrap();
+        assert!(error.is_disconnect());
+        server.service.report(&error);
+        server.service.report(&std::io::Error::from(std::io::ErrorKind::PermissionDenied).into());
+        assert_eq!(*errors.lock().unwrap(), vec![true, false]);
+
+        let mut response = Vec::new();
+        let mut client = std::io::Cursor::new(b"GET /hello HTTP/1.1\r\n\r\n".to_vec());
+        server.service.serve(&mut ReadWrite(&mut client, &mut response), &ThreadsSettings::default(), None).unwrap();
+        assert!(response.ends_with(b"\r\n\r\nhello"));
+    }
+
+    #[test]
+    fn test_graceful_shutdown() {
+        use std::net::Shutdown;
+
+        let server = Server::new("127.0.0.1:0").with_workers(2);
+        server.get("/slow", |_: &HttpRequest| {
+            thread::sleep(Duration::from_millis(200));
+            "done"
+        });
+        let (handle, addr, running) = testing::start(server);
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /slow HTTP/1.1\r\n\r\n").unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        handle.shutdown();
+
+        // the request in flight still gets its response
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(response.ends_with("\r\n\r\ndone"));
+
+        running.join().unwrap();
+        assert!(TcpStream::connect(addr).is_err());
+    }
+
+    #[test]
+    fn test_shutdown_before_run() {
+        for &backend in BACKENDS {
+            let server = Server::new("127.0.0.1:0").with_backend(backend);
+            server.shutdown_handle().shutdown();
+            let (done, finished) = std::sync::mpsc::channel();
+            thread::spawn(move || {
+                server.run();
+                let _ = done.send(());
+            });
+            assert!(finished.recv_timeout(Duration::from_secs(5)).is_ok(), "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn test_idle_connections() {
+        let server = Server::new("127.0.0.1:0").with_workers(1)
+                                               .with_io_timeout(Duration::from_millis(100))
+                                               .with_drain_timeout(Duration::from_secs(5));
+        server.get("/hello", |_: &HttpRequest| "hello");
+        let (handle, addr, running) = testing::start(server);
+
+        // a client that never sends its request only holds the worker until the timeout
+        let mut idle = testing::connect(addr);
+        thread::sleep(Duration::from_millis(20));
+        assert!(testing::fetch(addr, b"GET /hello HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nhello"));
+        let mut rest = Vec::new();
+        assert_eq!(idle.read_to_end(&mut rest).unwrap(), 0);
+
+        // nor does it hold the shutdown back until the drain deadline
+        let _idle = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(20));
+        let started = std::time::Instant::now();
+        handle.shutdown();
+        running.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn test_client_deadlines() {
+        // the Threads backend has a timeout per read and write instead
+        for &backend in BACKENDS.iter().filter(|&&backend| backend != Backend::Threads) {
+            let server = Server::new("127.0.0.1:0").with_backend(backend)
+                                                   .with_workers(2)
+                                                   .with_keep_alive_timeout(Duration::from_millis(100))
+                                                   .with_request_timeout(Duration::from_millis(300))
+                                                   .with_response_timeout(Duration::from_millis(300));
+            server.get("/large", |_: &HttpRequest| "x".repeat(32 << 20));
+            let (handle, addr, running) = testing::start(server);
+            let closed = |stream: &mut TcpStream| {
+                let mut rest = Vec::new();
+                match stream.read_to_end(&mut rest) {
+                    Ok(_) => true,
+                    Err(e) => e.kind() == io::ErrorKind::ConnectionReset,
+                }
+            };
+
+            // an idle connection is closed after the keep-alive timeout
+            let started = std::time::Instant::now();
+            let mut idle = testing::connect(addr);
+            assert!(closed(&mut idle), "{:?}", backend);
+            assert!(started.elapsed() < Duration::from_secs(2), "{:?}", backend);
+
+            // sending a byte at a time doesn't push the request deadline back
+            let started = std::time::Instant::now();
+            let mut slow = testing::connect(addr);
+            let mut dripping = slow.try_clone().unwrap();
+            thread::spawn(move || {
+                for byte in b"GET / HTTP/1.1\r\nX-Padding: ".iter().chain([b'a'; 100].iter()) {
+                    if dripping.write_all(&[*byte]).is_err() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(20));
+                }
+            });
+            assert!(closed(&mut slow), "{:?}", backend);
+            assert!(started.elapsed() < Duration::from_secs(1), "{:?}", backend);
+
+            // a client that doesn't read its response is closed after the response timeout
+            let mut stalled = testing::connect(addr);
+            stalled.write_all(b"GET /large HTTP/1.1\r\n\r\n").unwrap();
+            thread::sleep(Duration::from_secs(1));
+            let mut received = Vec::new();
+            let _ = stalled.read_to_end(&mut received);
+            assert!(received.len() < 32 << 20, "{:?}", backend);
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_framing_errors() {
+        for &backend in BACKENDS {
+            let server = Server::new("127.0.0.1:0").with_backend(backend)
+                                                   .with_workers(2)
+                                                   .with_max_head_size(1024)
+                                                   .with_max_body_size(16);
+            server.post("/echo", |req: &HttpRequest| req.body.clone());
+            server.get("/secret", |_: &HttpRequest| "secret");
+            let (handle, addr, running) = testing::start(server);
+
+            // the connection is closed after the error, nothing that followed is answered
+            let exchange = |request: &[u8]| testing::fetch(addr, request);
+            let smuggled = exchange(b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+                                      0\r\n\r\nGET /secret HTTP/1.1\r\n\r\n");
+            assert!(smuggled.starts_with("HTTP/1.1 501 Not Implemented\r\n"), "{:?}: {}", backend, smuggled);
+            assert!(smuggled.contains("Connection: close\r\n"));
+            assert!(!smuggled.contains("secret"));
+            let invalid = exchange(b"POST /echo HTTP/1.1\r\nContent-Length: 2\r\nContent-Length: 5\r\n\r\nhi");
+            assert!(invalid.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+            let too_large = exchange(b"POST /echo HTTP/1.1\r\nContent-Length: 17\r\n\r\n");
+            assert!(too_large.starts_with("HTTP/1.1 413 Payload Too Large\r\n"));
+            // the head never ends
+            let endless = exchange(&[b'a'; 2048]);
+            assert!(endless.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+            assert!(exchange(b"POST /echo HTTP/1.1\r\nContent-Length: 16\r\nConnection: close\r\n\r\n0123456789abcdef")
+                        .ends_with("\r\n\r\n0123456789abcdef"));
+
+            // more pipelined requests than the parser holds are all answered
+            if backend != Backend::Threads {
+                let mut requests = b"POST /echo HTTP/1.1\r\nContent-Length: 1\r\n\r\na".repeat(500);
+                requests.extend(b"GET /secret HTTP/1.1\r\nConnection: close\r\n\r\n");
+                let responses = exchange(&requests);
+                assert_eq!(responses.matches("HTTP/1.1 200 OK\r\n").count(), 501);
+                assert!(responses.ends_with("\r\n\r\nsecret"));
+            }
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_replace_router() {
+        let server = Server::new("localhost:3000").with_state(String::from("v1"));
+        server.get("/version", |_: &HttpRequest, version: &String| version.clone());
+        server.get("/old", |_: &HttpRequest| "old");
+
+        let mut router = server.new_router();
+        router.get("/version", |_: &HttpRequest, version: &String| format!("still {}", version));
+        server.replace_router(router);
+
+        assert_eq!(handle(&server, "GET /version HTTP/1.1\r\n\r\n").body, Some(b"still v1".to_vec()));
+        assert_eq!(handle(&server, "GET /old HTTP/1.1\r\n\r\n").status_code, "404");
+    }
+}
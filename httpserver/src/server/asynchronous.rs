@@ -0,0 +1,525 @@
+//! The [`Backend::Tokio`] backend: a task per connection on the tokio runtime.
+//! Async handlers are awaited on the runtime, synchronous handlers and the
+//! middleware chain run on its blocking threads. The streams of an HTTP/2
+//! connection are handled in tasks of their own. A connection taken over by
+//! its handler, for a WebSocket or an event stream, becomes a blocking socket
+//! for the handler, on a thread of its own outside the runtime.
+
+use std::io;
+#[cfg(feature = "tls")]
+use std::io::{Read, Write};
+use std::net;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::{self, JoinSet};
+use tokio::time;
+
+use http::{httprequest::{HttpRequest, PeerCertificate}, httpresponse::{HttpResponse, IntoResponse, StatusCode}};
+use super::{Server, Service, Timeouts, Upgrade, Upgrades};
+use crate::connection::{self, ConnectionError, Limits, RequestParser};
+use crate::http2;
+use crate::problem::Problem;
+use crate::router::{AsyncRouteHandler, RouteMatch};
+
+impl<S: Send + Sync + 'static> Server<'_, S> {
+    /// Runs the server on the tokio runtime the caller is in. It returns once
+    /// the server is shut down and the in-flight requests are done or the drain
+    /// deadline is over, see [`Server::shutdown_handle`]. The overload policy
+    /// doesn't apply, connections only cost a task.
+    pub async fn run_async(&self) {
+        if let Some(listener) = self.bind() {
+            self.run_tokio(listener).await
+        }
+    }
+
+    pub(super) async fn run_tokio(&self, listener: net::TcpListener) {
+        listener.set_nonblocking(true).expect("Couldn't make the listener non-blocking");
+        let listener = TcpListener::from_std(listener).expect("Couldn't register the listener");
+
+        // tells the connections to stop once they're idle
+        let (stop_sender, stop) = watch::channel(false);
+        let mut connections = JoinSet::new();
+        loop {
+            let accepted = listener.accept().await;
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            match accepted {
+                Ok((stream, _)) => {
+                    let stream = match self.transport(stream) {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            self.service.report(&e.into());
+                            continue;
+                        },
+                    };
+                    let service = self.service.clone();
+                    let stop = stop.clone();
+                    let (limits, timeouts) = (self.limits, self.timeouts);
+                    let upgrades = self.upgrades.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = serve(&service, stream, limits, timeouts, &upgrades, stop).await {
+                            service.report(&e);
+                        }
+                    });
+                },
+                Err(e) => self.service.report(&e.into()),
+            }
+        }
+
+        // no new connections while the in-flight requests finish
+        drop(listener);
+        let _ = stop_sender.send(true);
+        let drain_deadline = Instant::now() + self.drain_timeout;
+        let drained = time::timeout(self.drain_timeout, async {
+            while connections.join_next().await.is_some() {}
+        }).await;
+        if drained.is_err() {
+            eprintln!("{} requests were still in flight after the drain deadline", connections.len());
+            connections.abort_all();
+        }
+        // the handlers that took connections over run on threads of their own
+        let upgrades = self.upgrades.clone();
+        let running = task::spawn_blocking(move || upgrades.wait(drain_deadline.saturating_duration_since(Instant::now())));
+        if let Ok(running @ 1..) = running.await {
+            eprintln!("{} connections were still taken over after the drain deadline", running);
+        }
+    }
+
+    fn transport(&self, stream: TcpStream) -> io::Result<Transport> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = &self.tls {
+            let mut tls = rustls::ServerConnection::new(config.clone()).map_err(io::Error::other)?;
+            // the responses are in memory already, no need to hold them back
+            tls.set_buffer_limit(None);
+            return Ok(Transport::Tls(stream, Box::new(tls)));
+        }
+        Ok(Transport::Plain(stream))
+    }
+}
+
+/// A connection, decrypted if the server serves HTTPS
+enum Transport {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(TcpStream, Box<rustls::ServerConnection>),
+}
+
+impl Transport {
+    /// Cancel-safe once the TLS handshake is over, the encrypted bytes that
+    /// rustls wants to send afterwards go out with the next write
+    async fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Transport::Plain(stream) => stream.read(buf).await,
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream, tls) => {
+                loop {
+                    match tls.reader().read(buf) {
+                        Ok(n) => return Ok(n),
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                        Err(e) => return Err(e),
+                    }
+                    // the TLS handshake answers the client
+                    if tls.is_handshaking() {
+                        flush_tls(stream, tls).await?;
+                    }
+                    let mut received = [0u8; 4096];
+                    let n = stream.read(&mut received).await?;
+                    if n == 0 {
+                        return Ok(0);
+                    }
+                    let mut received = &received[..n];
+                    while !received.is_empty() {
+                        tls.read_tls(&mut received)?;
+                        if let Err(e) = tls.process_new_packets() {
+                            // tells the client why, e.g. that its certificate is unknown
+                            let _ = flush_tls(stream, tls).await;
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, e));
+                        }
+                    }
+                }
+            },
+        }
+    }
+
+    /// Like [`Transport::write_all`], false if the client didn't take all the
+    /// bytes before the timeout
+    async fn write_within(&mut self, bytes: &[u8], timeout: Duration) -> io::Result<bool> {
+        match time::timeout(timeout, self.write_all(bytes)).await {
+            Ok(written) => written.map(|_| true),
+            Err(_) => Ok(false),
+        }
+    }
+
+    async fn write_all(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            Transport::Plain(stream) => stream.write_all(bytes).await,
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream, tls) => {
+                tls.writer().write_all(bytes)?;
+                flush_tls(stream, tls).await
+            },
+        }
+    }
+
+    /// The certificate the client authenticated with, once the handshake is over
+    fn peer_certificate(&self) -> Option<PeerCertificate> {
+        match self {
+            Transport::Plain(_) => None,
+            #[cfg(feature = "tls")]
+            Transport::Tls(_, tls) => crate::tls::peer_certificate(tls),
+        }
+    }
+
+    /// The connection as a blocking socket, for a handler that takes it over,
+    /// with a clone of the socket
+    fn into_blocking(self) -> io::Result<(Box<dyn connection::Transport + Send>, net::TcpStream)> {
+        match self {
+            Transport::Plain(stream) => {
+                let stream = stream.into_std()?;
+                stream.set_nonblocking(false)?;
+                let socket = stream.try_clone()?;
+                Ok((Box::new(stream), socket))
+            },
+            #[cfg(feature = "tls")]
+            Transport::Tls(stream, tls) => {
+                let stream = stream.into_std()?;
+                stream.set_nonblocking(false)?;
+                let socket = stream.try_clone()?;
+                Ok((Box::new(rustls::StreamOwned::new(*tls, stream)), socket))
+            },
+        }
+    }
+
+    /// Tells a TLS client that the connection ends on purpose
+    async fn close(&mut self) {
+        #[cfg(feature = "tls")]
+        if let Transport::Tls(stream, tls) = self {
+            tls.send_close_notify();
+            let _ = flush_tls(stream, tls).await;
+        }
+    }
+}
+
+/// Writes the encrypted bytes that rustls buffered
+#[cfg(feature = "tls")]
+async fn flush_tls(stream: &mut TcpStream, tls: &mut rustls::ServerConnection) -> io::Result<()> {
+    let mut pending = Vec::new();
+    while tls.wants_write() {
+        tls.write_tls(&mut pending)?;
+    }
+    stream.write_all(&pending).await
+}
+
+/// Answers the requests of a connection until the client closes it, doesn't
+/// want it kept alive, misses a deadline or the server stops
+async fn serve<S: Send + Sync + 'static>(service: &Arc<Service<S>>,
+                                         mut stream: Transport,
+                                         limits: Limits,
+                                         timeouts: Timeouts,
+                                         upgrades: &Upgrades,
+                                         mut stop: watch::Receiver<bool>) -> Result<(), ConnectionError> {
+    let mut parser = RequestParser::new(limits);
+    let mut temp_buff = [0u8; 4096];
+    let mut peer_certificate = None;
+    // by when the next request, or the rest of the current one, must be received
+    let mut read_deadline = time::Instant::now() + timeouts.keep_alive;
+    loop {
+        let raw_request = match parser.next_request() {
+            Ok(Some(raw_request)) => raw_request,
+            Err(e) => {
+                if stream.write_within(&service.framing_error(e), timeouts.response).await? {
+                    stream.close().await;
+                }
+                return Ok(());
+            },
+            Ok(None) => {
+                let idle = parser.is_empty();
+                let n = tokio::select! {
+                    read = time::timeout_at(read_deadline, stream.read(&mut temp_buff)) => match read {
+                        Ok(read) => read?,
+                        Err(_) => return Ok(()),
+                    },
+                    _ = stop.wait_for(|stop| *stop), if idle => return Ok(()),
+                };
+                if n == 0 {
+                    // what was received of a request cut short is answered with a 400
+                    if parser.is_empty() {
+                        return Ok(());
+                    }
+                    let (response, _, _) = service.respond(parser.take_incomplete(), false, None, upgrades);
+                    if stream.write_within(&response, timeouts.response).await? {
+                        stream.close().await;
+                    }
+                    return Ok(());
+                }
+                // the whole request must arrive in time, however slowly it's sent
+                if idle {
+                    read_deadline = time::Instant::now() + timeouts.request;
+                }
+                parser.feed(&temp_buff[..n]);
+                continue;
+            },
+        };
+
+        let keep_alive = !*stop.borrow();
+        if peer_certificate.is_none() {
+            peer_certificate = stream.peer_certificate();
+        }
+        if http2::is_preface(&raw_request) {
+            let mut received = raw_request;
+            received.extend(parser.take_incomplete());
+            return serve_http2(service, stream, received, limits, timeouts, stop, peer_certificate).await;
+        }
+        let (response, keep_alive, upgrade) = respond(service, raw_request, keep_alive, peer_certificate.as_ref(), upgrades).await;
+        if !stream.write_within(&response, timeouts.response).await? {
+            return Ok(());
+        }
+        if let Some(upgrade) = upgrade {
+            // the handler blocks on the socket, on a thread of its own rather
+            // than one of the runtime, which it would hold for as long as it runs
+            let (stream, socket) = stream.into_blocking()?;
+            service.spawn_upgrade(upgrade, stream, socket, parser.take_incomplete(), Vec::new());
+            return Ok(());
+        }
+        if !keep_alive {
+            stream.close().await;
+            return Ok(());
+        }
+        // the next request may have started already
+        let timeout = match parser.is_empty() {
+            true => timeouts.keep_alive,
+            false => timeouts.request,
+        };
+        read_deadline = time::Instant::now() + timeout;
+    }
+}
+
+/// Answers the streams of an HTTP/2 connection as their handlers finish, until
+/// the client closes it, misses a deadline or the server stops. `received`
+/// starts with the preface.
+async fn serve_http2<S: Send + Sync + 'static>(service: &Arc<Service<S>>,
+                                               mut stream: Transport,
+                                               mut received: Vec<u8>,
+                                               limits: Limits,
+                                               timeouts: Timeouts,
+                                               mut stop: watch::Receiver<bool>,
+                                               peer_certificate: Option<PeerCertificate>) -> Result<(), ConnectionError> {
+    let mut connection = http2::Connection::new(limits.max_body_size);
+    let (response_sender, mut responses) = mpsc::unbounded_channel();
+    let mut temp_buff = [0u8; 4096];
+    let mut going_away = false;
+    // since the last stream ended, whatever other frames the client sends
+    let mut idle_deadline = None;
+    loop {
+        for (stream_id, mut request) in connection.receive(&received) {
+            // the client retries with HTTP/1.1
+            if service.takes_connection(&request) {
+                connection.reset(stream_id, http2::HTTP_1_1_REQUIRED);
+                continue;
+            }
+            request.peer_certificate = peer_certificate.clone();
+            let service = service.clone();
+            let response_sender = response_sender.clone();
+            tokio::spawn(async move {
+                let response = handle(&service, request).await;
+                let _ = response_sender.send((stream_id, response));
+            });
+        }
+        received.clear();
+        if !stream.write_within(&connection.take_output(), timeouts.response).await? {
+            return Ok(());
+        }
+        if connection.is_closed() {
+            stream.close().await;
+            return Ok(());
+        }
+
+        idle_deadline = match connection.has_streams() {
+            true => None,
+            false => idle_deadline.or_else(|| Some(time::Instant::now() + timeouts.keep_alive)),
+        };
+        tokio::select! {
+            read = stream.read(&mut temp_buff) => match read? {
+                0 => return Ok(()),
+                n => received.extend_from_slice(&temp_buff[..n]),
+            },
+            _ = time::sleep_until(idle_deadline.unwrap_or_else(time::Instant::now)), if idle_deadline.is_some() => return Ok(()),
+            Some((stream_id, response)) = responses.recv() => connection.respond(stream_id, response),
+            _ = stop.wait_for(|stop| *stop), if !going_away => {
+                going_away = true;
+                connection.go_away();
+            },
+        }
+    }
+}
+
+async fn respond<S: Send + Sync + 'static>(service: &Arc<Service<S>>,
+                                           raw_request: Vec<u8>,
+                                           keep_alive: bool,
+                                           peer_certificate: Option<&PeerCertificate>,
+                                           upgrades: &Upgrades) -> (Vec<u8>, bool, Option<Upgrade>) {
+    let Some(mut request) = HttpRequest::parse(raw_request.clone()) else {
+        return service.respond(raw_request, false, None, upgrades);
+    };
+    request.peer_certificate = peer_certificate.cloned();
+    let keep_alive = keep_alive && request.keep_alive();
+    let method = request.method;
+    // the handler gets its own copy, the one that takes the connection over needs the request too
+    let upgrade_request = service.takes_connection(&request).then(|| request.clone());
+    let response = handle(service, request).await;
+    let (response, upgrade) = match upgrade_request {
+        Some(request) => service.upgrade(request, response, upgrades),
+        None => (response, None),
+    };
+    // the connection is taken over rather than closed
+    let response = Service::<S>::serialize(method, response, keep_alive || upgrade.is_some());
+    (response, keep_alive, upgrade)
+}
+
+/// Awaits the async handler of the request, or runs the synchronous chain on a
+/// blocking thread
+async fn handle<S: Send + Sync + 'static>(service: &Arc<Service<S>>, mut request: HttpRequest) -> HttpResponse {
+    let Some((async_handler, route)) = service.async_endpoint(&mut request) else {
+        // synchronous handlers may block, so they get a thread of their own
+        let service = service.clone();
+        // panics are caught by the service, the task can only fail if the runtime shuts down
+        return tokio::task::spawn_blocking(move || service.handle(&mut request))
+                   .await
+                   .unwrap_or_else(|_| Problem::new(StatusCode::INTERNAL_SERVER_ERROR).into_response());
+    };
+
+    // the handler runs in a task of its own so that a panic only ends the task
+    let handler_request = request.clone();
+    match tokio::spawn(async move { async_handler(handler_request).await }).await {
+        Ok(response) => response,
+        Err(e) if e.is_panic() => service.panic_response(&request, Some(route), e.into_panic().as_ref()),
+        Err(_) => Problem::new(StatusCode::INTERNAL_SERVER_ERROR).into_response(),
+    }
+}
+
+impl<S> Service<S> {
+    /// The async handler of the route of the request when it can be awaited
+    /// directly, i.e. no middleware has to run around it. Also returns the
+    /// template of the route and sets the path parameters of the request.
+    fn async_endpoint(&self, request: &mut HttpRequest) -> Option<(AsyncRouteHandler, String)> {
+        if !self.middleware.read().unwrap().is_empty() {
+            return None;
+        }
+        let router = self.router.read().unwrap();
+        if !router.middleware().is_empty() {
+            return None;
+        }
+        let RouteMatch::Found(route_info) = router.route(request.method, request.path()) else {
+            return None;
+        };
+        if !route_info.middleware.is_empty() {
+            return None;
+        }
+        let async_handler = route_info.async_handler.clone()?;
+        let path_params = route_info.extract_path_params(request.path());
+        request.with_path_params(&path_params);
+        Some((async_handler, route_info.template.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::thread;
+    use serde::Deserialize;
+    use crate::extract::{Path, State};
+    use crate::middleware::Next;
+    use crate::server::Backend;
+    use crate::testing::{self, exchange};
+
+    #[derive(Deserialize)]
+    struct User {
+        id: u64,
+    }
+
+    async fn user(Path(user): Path<User>, State(greeting): State<String>) -> String {
+        time::sleep(Duration::from_millis(10)).await;
+        format!("{} {}", greeting, user.id)
+    }
+
+    #[test]
+    fn test_tokio_backend() {
+        let server = Server::new("127.0.0.1:0").with_state(String::from("user"))
+                                               .with_backend(Backend::Tokio)
+                                               .with_workers(2);
+        server.get("/users/{id}", user);
+        server.get("/sync", |_: &HttpRequest| "sync");
+        server.get("/double/{n}", |req: HttpRequest| async move {
+            let n: u64 = req.path_params["n"].parse().unwrap();
+            (n * 2).to_string()
+        });
+        let mut admin = server.new_router();
+        admin.layer(|req: &HttpRequest, next: Next| next.run(req).with_header("X-Admin", "1"));
+        admin.get("/{id}", user);
+        server.nest("/admin", admin);
+
+        let (handle, addr, running) = testing::start(server);
+
+        let mut stream = testing::connect(addr);
+        let mut parser = RequestParser::default();
+        let responses: Vec<String> = ["GET /users/7 HTTP/1.1\r\n\r\n",
+                                      "GET /sync HTTP/1.1\r\n\r\n",
+                                      "GET /admin/8 HTTP/1.1\r\n\r\n",
+                                      "GET /double/x HTTP/1.1\r\n\r\n",
+                                      "GET /users/bob HTTP/1.1\r\n\r\n"].iter()
+                                          .map(|request| exchange(&mut stream, &mut parser, request.as_bytes()))
+                                          .collect();
+        assert!(responses[0].ends_with("\r\n\r\nuser 7"));
+        assert!(responses[1].ends_with("\r\n\r\nsync"));
+        // the middleware runs around the async handler
+        assert!(responses[2].contains("X-Admin: 1\r\n"));
+        assert!(responses[2].ends_with("\r\n\r\nuser 8"));
+        assert!(responses[3].starts_with("HTTP/1.1 500 Internal Server Error\r\n"));
+        assert!(responses[4].starts_with("HTTP/1.1 400 Bad Request\r\n"));
+
+        // an idle connection is closed by the shutdown
+        let mut idle = testing::connect(addr);
+        thread::sleep(Duration::from_millis(50));
+        handle.shutdown();
+        running.join().unwrap();
+        assert_eq!(idle.read(&mut [0u8; 16]).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_async_handler_on_blocking_backend() {
+        let server = Server::new("127.0.0.1:0").with_state(String::from("user"));
+        server.get("/users/{id}", user);
+        let mut request = HttpRequest::parse(b"GET /users/3 HTTP/1.1\r\n\r\n".to_vec()).unwrap();
+        assert_eq!(server.service.handle(&mut request).body, Some(b"user 3".to_vec()));
+    }
+
+    #[test]
+    fn test_async_handler_in_runtime() {
+        let server = Server::new("127.0.0.1:0").with_state(String::from("user"));
+        server.layer(|req: &HttpRequest, next: Next| next.run(req).with_header("X-Layer", "1"));
+        server.get("/users/{id}", user);
+        let service = server.service.clone();
+
+        // the middleware chain blocks on the handler from within a task, on
+        // either flavor of runtime
+        let runtimes = [
+            tokio::runtime::Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap(),
+            tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap(),
+        ];
+        for runtime in runtimes {
+            let service = service.clone();
+            let response = runtime.block_on(async move {
+                let mut request = HttpRequest::parse(b"GET /users/4 HTTP/1.1\r\n\r\n".to_vec()).unwrap();
+                tokio::spawn(async move { service.handle(&mut request) }).await.unwrap()
+            });
+            assert_eq!(response.headers.as_ref().unwrap().get("X-Layer").unwrap(), "1");
+            assert_eq!(response.body, Some(b"user 4".to_vec()));
+        }
+    }
+}
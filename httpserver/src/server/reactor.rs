@@ -0,0 +1,708 @@
+//! The [`Backend::Events`] backend: a single thread polls non-blocking sockets
+//! and feeds the bytes it reads to the parser of each connection. Complete
+//! requests go to the worker pool, which sends the responses back to the event
+//! loop to be written. The streams of an HTTP/2 connection go to the workers
+//! as they complete, each one is answered as soon as its response is ready.
+//! A connection taken over by its handler, for a WebSocket or an event stream,
+//! leaves the event loop for a thread of its own, as a blocking socket. The
+//! event loop never blocks on the pool: with [`OverloadPolicy::Block`], the
+//! requests wait in the loop for room in the queue.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use mio::net::{TcpListener, TcpStream};
+use mio::{Events, Interest, Poll, Token, Waker};
+
+use http::httprequest::{HttpRequest, PeerCertificate};
+use http::httpresponse::HttpResponse;
+use super::{Server, Upgrade};
+use crate::connection::{ConnectionError, RequestParser, Transport};
+use crate::http2;
+use crate::pool::{OverloadPolicy, ThreadPool};
+
+const LISTENER: Token = Token(0);
+const WAKER: Token = Token(1);
+const FIRST_CONNECTION: usize = 2;
+
+const POLL_TIMEOUT: Duration = Duration::from_millis(500);
+/// How soon the requests that wait for room in the queue are tried again
+const RETRY_TIMEOUT: Duration = Duration::from_millis(10);
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Reading,
+    Processing, // a worker handles the request, or HTTP/2 streams are in progress
+    Writing,
+}
+
+struct Connection {
+    stream: TcpStream,
+    #[cfg(feature = "tls")]
+    tls: Option<Box<rustls::ServerConnection>>,
+    parser: RequestParser,
+    http2: Option<Box<http2::Connection>>, // once the client sent the preface
+    state: State,
+    response: Vec<u8>, // with HTTP/2, the frames that are still to be written
+    written: usize,
+    keep_alive: bool,
+    peer_closed: bool, // the client won't send anything more
+    // by when the next request, or the rest of the current one, must be received
+    read_deadline: Option<Instant>,
+    write_deadline: Option<Instant>, // by when what is left to write must be written
+    peer_certificate: Option<PeerCertificate>, // of a client authenticated with mutual TLS
+}
+
+impl Connection {
+    /// Feeds the parser with what the client sent until the socket would block,
+    /// or until the parser holds more than a request
+    fn receive(&mut self) -> io::Result<()> {
+        let mut temp_buff = [0u8; 4096];
+        // the rest waits in rustls and in the socket, see `Reactor::write`
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            loop {
+                // what was decrypted already first
+                while !self.parser.is_full() && !self.peer_closed {
+                    match tls.reader().read(&mut temp_buff) {
+                        // the client sent close_notify
+                        Ok(0) => self.peer_closed = true,
+                        Ok(n) => self.parser.feed(&temp_buff[..n]),
+                        Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                        Err(e) => return Err(e),
+                    }
+                }
+                if self.parser.is_full() || self.peer_closed {
+                    return Ok(());
+                }
+                match tls.read_tls(&mut self.stream) {
+                    Ok(0) => {
+                        self.peer_closed = true;
+                        return Ok(());
+                    },
+                    Ok(_) => {
+                        if let Err(e) = tls.process_new_packets() {
+                            // tells the client why, e.g. that its certificate is unknown
+                            let _ = tls.write_tls(&mut self.stream);
+                            return Err(io::Error::new(ErrorKind::InvalidData, e));
+                        }
+                        if self.peer_certificate.is_none() && !tls.is_handshaking() {
+                            self.peer_certificate = crate::tls::peer_certificate(tls);
+                        }
+                    },
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        while !self.parser.is_full() {
+            match self.stream.read(&mut temp_buff) {
+                Ok(0) => {
+                    self.peer_closed = true;
+                    return Ok(());
+                },
+                Ok(n) => self.parser.feed(&temp_buff[..n]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes as much of the response as the socket takes, returns whether all
+    /// of it was written
+    fn send(&mut self) -> io::Result<bool> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            // rustls buffers the whole response, see `Reactor::accept`
+            tls.writer().write_all(&self.response[self.written..])?;
+            self.written = self.response.len();
+            return self.flush();
+        }
+        while self.written < self.response.len() {
+            match self.stream.write(&self.response[self.written..]) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => self.written += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Writes the encrypted bytes that are still buffered, e.g. the rest of a
+    /// handshake. Returns whether all of them were written.
+    fn flush(&mut self) -> io::Result<bool> {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &mut self.tls {
+            while tls.wants_write() {
+                match tls.write_tls(&mut self.stream) {
+                    Ok(_) => (),
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(false),
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    fn wants_write(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if let Some(tls) = &self.tls {
+            return tls.wants_write();
+        }
+        false
+    }
+}
+
+/// A request for the workers
+enum Job {
+    Http1(Token, Vec<u8>, Option<PeerCertificate>),
+    Http2(Token, u32, HttpRequest),
+}
+
+/// A response computed by a worker
+enum Done {
+    /// With whether the connection stays open
+    Http1 {
+        token: Token,
+        response: Vec<u8>,
+        keep_alive: bool,
+    },
+    Http2 {
+        token: Token,
+        stream_id: u32,
+        response: HttpResponse,
+    },
+    /// The handler takes the connection over once the response is written,
+    /// e.g. for a WebSocket
+    Upgrade {
+        token: Token,
+        response: Vec<u8>,
+        upgrade: Upgrade,
+    },
+}
+
+struct Reactor<'s, 'a, S> {
+    server: &'s Server<'a, S>,
+    poll: Poll,
+    listener: Option<TcpListener>,
+    connections: HashMap<Token, Connection>,
+    // tokens are never reused, so a late response can't reach another connection
+    next_token: usize,
+    pool: ThreadPool<Job>,
+    pending: VecDeque<Job>, // with the Block policy, until the queue has room
+    accept_paused: bool,    // while requests are pending
+    done: Receiver<Done>,
+}
+
+pub(super) fn run<S: Send + Sync + 'static>(server: &Server<'_, S>, listener: net::TcpListener) {
+    listener.set_nonblocking(true).expect("Couldn't make the listener non-blocking");
+    let mut listener = TcpListener::from_std(listener);
+    let poll = Poll::new().expect("Couldn't create the poller");
+    poll.registry()
+        .register(&mut listener, LISTENER, Interest::READABLE)
+        .expect("Couldn't register the listener");
+    let waker = Arc::new(Waker::new(poll.registry(), WAKER).expect("Couldn't create the waker"));
+
+    let (done_sender, done) = mpsc::channel();
+    let service = server.service.clone();
+    let upgrades = server.upgrades.clone();
+    let pool = ThreadPool::new(server.workers, server.queue_size, move |job: Job| {
+        let send = |done: Done| {
+            let _ = done_sender.send(done);
+            let _ = waker.wake();
+        };
+        match job {
+            Job::Http1(token, raw_request, peer_certificate) => {
+                let (response, keep_alive, upgrade) = service.respond(raw_request, true, peer_certificate.as_ref(), &upgrades);
+                match upgrade {
+                    Some(upgrade) => send(Done::Upgrade { token, response, upgrade }),
+                    None => send(Done::Http1 { token, response, keep_alive }),
+                }
+            },
+            Job::Http2(token, stream_id, mut request) => {
+                let response = service.handle(&mut request);
+                send(Done::Http2 { token, stream_id, response });
+            },
+        }
+    });
+
+    let mut reactor = Reactor {
+        server,
+        poll,
+        listener: Some(listener),
+        connections: HashMap::new(),
+        next_token: FIRST_CONNECTION,
+        pool,
+        pending: VecDeque::new(),
+        accept_paused: false,
+        done,
+    };
+    let drain_deadline = reactor.event_loop();
+
+    let busy = reactor.pool.shutdown(drain_deadline.saturating_duration_since(Instant::now()));
+    if busy > 0 {
+        eprintln!("{} requests were still in flight after the drain deadline", busy);
+    }
+    server.wait_for_upgrades(drain_deadline);
+}
+
+impl<S: Send + Sync + 'static> Reactor<'_, '_, S> {
+    /// Runs until the server is shut down and the in-flight requests are done,
+    /// returns the drain deadline
+    fn event_loop(&mut self) -> Instant {
+        let mut events = Events::with_capacity(1024);
+        let mut drain_deadline = None;
+        loop {
+            let timeout = if self.pending.is_empty() { POLL_TIMEOUT } else { RETRY_TIMEOUT };
+            if let Err(e) = self.poll.poll(&mut events, Some(timeout)) {
+                if e.kind() == ErrorKind::Interrupted {
+                    continue;
+                }
+                self.server.service.report(&e.into());
+                return Instant::now();
+            }
+
+            for event in events.iter() {
+                match event.token() {
+                    LISTENER => self.accept(),
+                    WAKER => (),
+                    token => {
+                        if event.is_readable() || event.is_read_closed() {
+                            self.read(token);
+                        }
+                        if event.is_writable() {
+                            self.write(token);
+                        }
+                    },
+                }
+            }
+            self.receive_responses();
+            self.submit_pending();
+            self.close_expired();
+
+            if self.server.shutdown.is_shutdown() && drain_deadline.is_none() {
+                // no new connections while the in-flight requests finish
+                if let Some(mut listener) = self.listener.take() {
+                    let _ = self.poll.registry().deregister(&mut listener);
+                }
+                drain_deadline = Some(Instant::now() + self.server.drain_timeout);
+                // HTTP/2 clients are told which of their streams will still be answered
+                let tokens: Vec<Token> = self.connections.iter()
+                                             .filter(|(_, connection)| connection.http2.is_some())
+                                             .map(|(token, _)| *token)
+                                             .collect();
+                for token in tokens {
+                    if let Some(http2) = self.connections.get_mut(&token).and_then(|connection| connection.http2.as_mut()) {
+                        http2.go_away();
+                    }
+                    self.write_http2(token);
+                }
+            }
+            if let Some(deadline) = drain_deadline {
+                self.connections.retain(|_, connection| connection.state != State::Reading);
+                if self.connections.is_empty() || Instant::now() >= deadline {
+                    return deadline;
+                }
+            }
+        }
+    }
+
+    fn accept(&mut self) {
+        // like the accept loop of the Threads backend, which blocks on a full queue
+        if !self.pending.is_empty() {
+            self.accept_paused = true;
+            return;
+        }
+        let Some(listener) = &self.listener else { return };
+        loop {
+            match listener.accept() {
+                Ok((mut stream, _)) => {
+                    #[cfg(feature = "tls")]
+                    let tls = match &self.server.tls {
+                        Some(config) => match rustls::ServerConnection::new(config.clone()) {
+                            Ok(mut tls) => {
+                                // the responses are in memory already, no need to hold them back
+                                tls.set_buffer_limit(None);
+                                Some(Box::new(tls))
+                            },
+                            Err(e) => {
+                                self.server.service.report(&io::Error::other(e).into());
+                                continue;
+                            },
+                        },
+                        None => None,
+                    };
+                    let token = Token(self.next_token);
+                    self.next_token += 1;
+                    if let Err(e) = self.poll.registry().register(&mut stream, token, Interest::READABLE) {
+                        self.server.service.report(&e.into());
+                        continue;
+                    }
+                    self.connections.insert(token, Connection {
+                        stream,
+                        #[cfg(feature = "tls")]
+                        tls,
+                        parser: RequestParser::new(self.server.limits),
+                        http2: None,
+                        state: State::Reading,
+                        response: Vec::new(),
+                        written: 0,
+                        keep_alive: false,
+                        peer_closed: false,
+                        read_deadline: Some(Instant::now() + self.server.timeouts.keep_alive),
+                        write_deadline: None,
+                        peer_certificate: None,
+                    });
+                },
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => {
+                    self.server.service.report(&e.into());
+                    break;
+                },
+            }
+        }
+    }
+
+    fn read(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else { return };
+        let was_idle = connection.parser.is_empty();
+        if let Err(e) = connection.receive() {
+            self.close(token, Some(e));
+            return;
+        }
+        // the whole request must arrive in time, however slowly it's sent
+        if was_idle && !connection.parser.is_empty() && connection.state == State::Reading && connection.http2.is_none() {
+            connection.read_deadline = Some(Instant::now() + self.server.timeouts.request);
+        }
+        // e.g. the TLS handshake answers the client
+        if connection.state == State::Reading && connection.wants_write() {
+            self.write(token);
+        }
+        self.dispatch(token);
+    }
+
+    /// Hands the next complete request of the connection to the workers
+    fn dispatch(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else { return };
+        if connection.http2.is_some() {
+            self.dispatch_http2(token);
+            return;
+        }
+        if connection.state != State::Reading {
+            return;
+        }
+        let raw_request = match connection.parser.next_request() {
+            Ok(Some(raw_request)) => raw_request,
+            // what was received of a request cut short is answered with a 400
+            Ok(None) if connection.peer_closed && !connection.parser.is_empty() => connection.parser.take_incomplete(),
+            Ok(None) if connection.peer_closed => {
+                self.close(token, None);
+                return;
+            },
+            Ok(None) => return,
+            Err(e) => {
+                let response = self.server.service.framing_error(e);
+                self.respond(token, response, false);
+                return;
+            },
+        };
+        if http2::is_preface(&raw_request) {
+            connection.http2 = Some(Box::new(http2::Connection::new(self.server.limits.max_body_size)));
+            connection.read_deadline = None;
+            // the rest of the preface and the first frames are still in the parser
+            let mut received = raw_request;
+            received.extend(connection.parser.take_incomplete());
+            connection.parser.feed(&received);
+            self.dispatch_http2(token);
+            return;
+        }
+
+        connection.state = State::Processing;
+        connection.read_deadline = None;
+        let job = Job::Http1(token, raw_request, connection.peer_certificate.clone());
+        match self.server.overload_policy {
+            OverloadPolicy::Block => self.pending.push_back(job),
+            OverloadPolicy::Reject => {
+                if self.pool.try_execute(job).is_err() {
+                    let rejection = self.server.service.rejection();
+                    self.respond(token, rejection, false);
+                }
+            },
+            OverloadPolicy::Drop => {
+                if self.pool.try_execute(job).is_err() {
+                    self.close(token, None);
+                }
+            },
+        }
+    }
+
+    /// Hands the streams of an HTTP/2 connection to the workers as their
+    /// requests complete
+    fn dispatch_http2(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else { return };
+        let Some(http2) = &mut connection.http2 else { return };
+        for (stream_id, mut request) in http2.receive(&connection.parser.take_incomplete()) {
+            // the client retries with HTTP/1.1
+            if self.server.service.takes_connection(&request) {
+                http2.reset(stream_id, http2::HTTP_1_1_REQUIRED);
+                continue;
+            }
+            request.peer_certificate = connection.peer_certificate.clone();
+            let job = Job::Http2(token, stream_id, request);
+            match self.server.overload_policy {
+                OverloadPolicy::Block => self.pending.push_back(job),
+                OverloadPolicy::Reject => {
+                    if self.pool.try_execute(job).is_err() {
+                        http2.respond(stream_id, self.server.service.unavailable());
+                    }
+                },
+                OverloadPolicy::Drop => {
+                    // the client can retry the stream
+                    if self.pool.try_execute(job).is_err() {
+                        http2.reset(stream_id, http2::REFUSED_STREAM);
+                    }
+                },
+            }
+        }
+        self.write_http2(token);
+    }
+
+    /// Hands the requests that wait to the workers, as long as the queue has
+    /// room, then accepts the connections that waited too
+    fn submit_pending(&mut self) {
+        while let Some(job) = self.pending.pop_front() {
+            if let Err(job) = self.pool.try_execute(job) {
+                self.pending.push_front(job);
+                return;
+            }
+        }
+        if self.accept_paused {
+            self.accept_paused = false;
+            self.accept();
+        }
+    }
+
+    fn receive_responses(&mut self) {
+        while let Ok(done) = self.done.try_recv() {
+            match done {
+                Done::Http1 { token, response, keep_alive } => {
+                    // the connection may have failed in the meantime
+                    if self.connections.contains_key(&token) {
+                        self.respond(token, response, keep_alive);
+                    }
+                },
+                Done::Http2 { token, stream_id, response } => {
+                    let Some(connection) = self.connections.get_mut(&token) else { continue };
+                    if let Some(http2) = &mut connection.http2 {
+                        http2.respond(stream_id, response);
+                    }
+                    self.write_http2(token);
+                },
+                Done::Upgrade { token, response, upgrade } => self.upgrade(token, response, upgrade),
+            }
+        }
+    }
+
+    /// Hands the connection over to the handler that takes it over, on a thread
+    /// of its own, which writes the response first
+    fn upgrade(&mut self, token: Token, response: Vec<u8>, upgrade: Upgrade) {
+        let Some(mut connection) = self.connections.remove(&token) else { return };
+        let _ = self.poll.registry().deregister(&mut connection.stream);
+        let stream = net::TcpStream::from(connection.stream);
+        let socket = match stream.set_nonblocking(false).and_then(|_| stream.try_clone()) {
+            Ok(socket) => socket,
+            Err(e) => {
+                self.server.service.report(&e.into());
+                return;
+            },
+        };
+        #[cfg(feature = "tls")]
+        let stream: Box<dyn Transport + Send> = match connection.tls {
+            Some(tls) => Box::new(rustls::StreamOwned::new(*tls, stream)),
+            None => Box::new(stream),
+        };
+        #[cfg(not(feature = "tls"))]
+        let stream: Box<dyn Transport + Send> = Box::new(stream);
+        self.server.service.spawn_upgrade(upgrade, stream, socket, connection.parser.take_incomplete(), response);
+    }
+
+    /// Writes the frames of an HTTP/2 connection, and closes it once it's done
+    fn write_http2(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else { return };
+        let Some(http2) = &mut connection.http2 else { return };
+        connection.response.drain(..connection.written);
+        connection.written = 0;
+        connection.response.extend(http2.take_output());
+        let closed = http2.is_closed() || (connection.peer_closed && !http2.has_streams());
+        connection.state = match http2.has_streams() {
+            true => State::Processing,
+            false => State::Reading,
+        };
+        // an idle connection is closed once the keep-alive timeout is over
+        // since its last stream, whatever other frames the client sends
+        match connection.state {
+            State::Reading => {
+                connection.read_deadline.get_or_insert(Instant::now() + self.server.timeouts.keep_alive);
+            },
+            _ => connection.read_deadline = None,
+        }
+        let interest = match connection.send() {
+            Ok(true) => {
+                connection.write_deadline = None;
+                Interest::READABLE
+            },
+            Ok(false) => {
+                connection.write_deadline.get_or_insert(Instant::now() + self.server.timeouts.response);
+                Interest::READABLE | Interest::WRITABLE
+            },
+            Err(e) => {
+                self.close(token, Some(e));
+                return;
+            },
+        };
+        if closed && connection.written == connection.response.len() {
+            self.close(token, None);
+            return;
+        }
+        if let Err(e) = self.poll.registry().reregister(&mut connection.stream, token, interest) {
+            self.close(token, Some(e));
+        }
+    }
+
+    fn respond(&mut self, token: Token, response: Vec<u8>, keep_alive: bool) {
+        let Some(connection) = self.connections.get_mut(&token) else { return };
+        connection.state = State::Writing;
+        connection.response = response;
+        connection.written = 0;
+        connection.keep_alive = keep_alive;
+        connection.read_deadline = None;
+        connection.write_deadline = Some(Instant::now() + self.server.timeouts.response);
+        self.write(token);
+    }
+
+    fn write(&mut self, token: Token) {
+        let Some(connection) = self.connections.get_mut(&token) else { return };
+        if connection.http2.is_some() {
+            self.write_http2(token);
+            return;
+        }
+        let sent = match connection.state {
+            State::Writing => connection.send(),
+            State::Reading | State::Processing => connection.flush(),
+        };
+        match sent {
+            Ok(true) => connection.write_deadline = None,
+            Ok(false) => {
+                // the rest is written once the socket is writable again
+                connection.write_deadline.get_or_insert(Instant::now() + self.server.timeouts.response);
+                let interest = Interest::READABLE | Interest::WRITABLE;
+                if let Err(e) = self.poll.registry().reregister(&mut connection.stream, token, interest) {
+                    self.close(token, Some(e));
+                }
+                return;
+            },
+            Err(e) => {
+                self.close(token, Some(e));
+                return;
+            },
+        }
+        if connection.state != State::Writing {
+            if let Err(e) = self.poll.registry().reregister(&mut connection.stream, token, Interest::READABLE) {
+                self.close(token, Some(e));
+            }
+            return;
+        }
+
+        let shutting_down = self.server.shutdown.is_shutdown();
+        if !connection.keep_alive || connection.peer_closed || shutting_down {
+            self.close(token, None);
+            return;
+        }
+        connection.state = State::Reading;
+        connection.response = Vec::new();
+        // the next request may have started already
+        let timeout = match connection.parser.is_empty() {
+            true => self.server.timeouts.keep_alive,
+            false => self.server.timeouts.request,
+        };
+        connection.read_deadline = Some(Instant::now() + timeout);
+        if let Err(e) = self.poll.registry().reregister(&mut connection.stream, token, Interest::READABLE) {
+            self.close(token, Some(e));
+            return;
+        }
+        // the client may have sent the next request already, and more that
+        // wasn't read while the parser was full
+        self.read(token);
+    }
+
+    /// Closes the connections that are idle for too long, whose client sends
+    /// its request too slowly or doesn't read the response
+    fn close_expired(&mut self) {
+        let now = Instant::now();
+        self.connections.retain(|_, connection| {
+            [connection.read_deadline, connection.write_deadline].iter().flatten().all(|deadline| now < *deadline)
+        });
+    }
+
+    fn close(&mut self, token: Token, error: Option<io::Error>) {
+        if let Some(mut connection) = self.connections.remove(&token) {
+            #[cfg(feature = "tls")]
+            if let Some(tls) = &mut connection.tls {
+                if error.is_none() {
+                    tls.send_close_notify();
+                    let _ = connection.flush();
+                }
+            }
+            let _ = self.poll.registry().deregister(&mut connection.stream);
+        }
+        if let Some(e) = error {
+            self.server.service.report(&ConnectionError::from(e));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::httprequest::HttpRequest;
+    use crate::server::Backend;
+    use crate::testing::{self, exchange, read_response};
+
+    #[test]
+    fn test_keep_alive_and_pipelining() {
+        let server = Server::new("127.0.0.1:0").with_backend(Backend::Events).with_workers(2);
+        server.get("/hello/{name}", |req: &HttpRequest| format!("Hello {}!", req.path_params["name"]));
+        let (handle, addr, running) = testing::start(server);
+
+        let mut stream = testing::connect(addr);
+        let mut parser = RequestParser::default();
+        assert!(exchange(&mut stream, &mut parser, b"GET /hello/ann HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nHello ann!"));
+
+        // two requests in one write are answered in order on the same connection
+        let pipelined = b"GET /hello/bob HTTP/1.1\r\n\r\nGET /hello/eve HTTP/1.1\r\nConnection: close\r\n\r\n";
+        assert!(exchange(&mut stream, &mut parser, pipelined).ends_with("\r\n\r\nHello bob!"));
+        let last = read_response(&mut stream, &mut parser);
+        assert!(last.contains("Connection: close\r\n"));
+        assert!(last.ends_with("\r\n\r\nHello eve!"));
+        let mut rest = Vec::new();
+        stream.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty() && parser.is_empty());
+
+        // an idle kept-alive connection doesn't hold the shutdown back
+        let mut idle = testing::connect(addr);
+        let response = exchange(&mut idle, &mut RequestParser::default(), b"GET /missing HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        handle.shutdown();
+        running.join().unwrap();
+    }
+}
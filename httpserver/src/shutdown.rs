@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Stops a running server from another thread, see [`crate::server::Server::shutdown_handle`].
+///
+/// ```text
+/// let handle = server.shutdown_handle();
+/// thread::spawn(move || server.run());
+/// ...
+/// handle.shutdown();
+/// ```
+///
+/// The server stops accepting connections, lets the in-flight requests finish
+/// until the drain deadline and then `run` returns.
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Shutdown>,
+}
+
+#[derive(Default)]
+struct Shutdown {
+    requested: AtomicBool,
+    local_addr: Mutex<Option<SocketAddr>>,
+    listeners: Mutex<Listeners>,
+}
+
+/// What is told about the shutdown, by id
+#[derive(Default)]
+struct Listeners {
+    next_id: u64,
+    notify: HashMap<u64, Box<dyn Fn() + Send>>,
+}
+
+/// Keeps a function registered with [`ShutdownHandle::on_shutdown`], until
+/// it's dropped
+pub(crate) struct ShutdownListener {
+    shutdown: Arc<Shutdown>,
+    id: u64,
+}
+
+impl Drop for ShutdownListener {
+    fn drop(&mut self) {
+        self.shutdown.listeners.lock().unwrap().notify.remove(&self.id);
+    }
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.inner.requested.store(true, Ordering::SeqCst);
+        for notify in self.inner.listeners.lock().unwrap().notify.values() {
+            notify();
+        }
+
+        // wakes up the server that is waiting for a connection
+        if let Some(local_addr) = self.local_addr() {
+            let _ = TcpStream::connect_timeout(&wake_up_addr(local_addr), Duration::from_secs(1));
+        }
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.inner.requested.load(Ordering::SeqCst)
+    }
+
+    /// The address the server listens on once it runs, e.g. to find the port
+    /// picked by the OS for `127.0.0.1:0`.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        *self.inner.local_addr.lock().unwrap()
+    }
+
+    /// Calls the function once the server shuts down, right away if it did
+    /// already, e.g. to close the connections that wait for their clients
+    pub(crate) fn on_shutdown(&self, notify: impl Fn() + Send + 'static) -> ShutdownListener {
+        let mut listeners = self.inner.listeners.lock().unwrap();
+        // under the lock, so that a shutdown in the meantime can't be missed
+        if self.is_shutdown() {
+            notify();
+        }
+        let id = listeners.next_id;
+        listeners.next_id += 1;
+        listeners.notify.insert(id, Box::new(notify));
+        ShutdownListener { shutdown: self.inner.clone(), id }
+    }
+
+    pub(crate) fn set_local_addr(&self, local_addr: SocketAddr) {
+        *self.inner.local_addr.lock().unwrap() = Some(local_addr);
+    }
+}
+
+/// A server listening on all the interfaces can be reached on the loopback one
+fn wake_up_addr(mut local_addr: SocketAddr) -> SocketAddr {
+    if local_addr.ip().is_unspecified() {
+        match local_addr {
+            SocketAddr::V4(_) => local_addr.set_ip(Ipv4Addr::LOCALHOST.into()),
+            SocketAddr::V6(_) => local_addr.set_ip(Ipv6Addr::LOCALHOST.into()),
+        }
+    }
+    local_addr
+}
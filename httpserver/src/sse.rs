@@ -0,0 +1,398 @@
+//! Server-Sent Events. A route registered with [`crate::server::Server::sse`]
+//! answers a `GET` with a `text/event-stream` response that stays open, the
+//! handler then writes the events to it until it returns, on a worker thread,
+//! or on a blocking thread of the tokio backend:
+//!
+//! ```text
+//! server.sse("/prices", move |_req: &HttpRequest, mut events: EventStream| {
+//!     let resume_after = events.last_event_id().map(str::to_string);
+//!     let prices = feed.subscribe(resume_after);
+//!     // ends once the feed closes the channel or the client goes away
+//!     let _ = events.forward(&prices);
+//! });
+//! ```
+//!
+//! The client only finds out that the connection is gone by writing to it,
+//! so a comment is sent whenever the stream was idle for the keep-alive
+//! interval: it keeps proxies from closing the connection, and the write
+//! fails once the client went away. The writes also fail once the server
+//! shuts down, the response is ended so that the client reconnects.
+
+use std::io::{self, ErrorKind};
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use http::httprequest::HttpRequest;
+use http::httpresponse::{HttpResponse, StatusCode};
+use crate::connection::Transport;
+use crate::shutdown::ShutdownHandle;
+
+/// Runs on the connection once the response headers were sent
+pub type EventStreamHandler = Arc<dyn Fn(&HttpRequest, EventStream<'_>) + Send + Sync>;
+
+/// How long the stream can stay idle before a keep-alive comment is sent
+pub const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+/// The shortest keep-alive interval, shorter ones would have an idle stream
+/// send nothing but comments
+pub const MIN_KEEP_ALIVE: Duration = Duration::from_millis(10);
+
+/// An event, e.g. `Event::new("42.5").with_event("price").with_id("17")`.
+/// Every field is optional, an event without data only updates the last
+/// event id or the retry delay of the client.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Event {
+    event: Option<String>,
+    id: Option<String>,
+    data: Option<String>,
+    retry: Option<Duration>,
+}
+
+impl Event {
+    /// An event with the data, which can span several lines
+    pub fn new(data: impl Into<String>) -> Self {
+        Event {
+            data: Some(data.into()),
+            ..Event::default()
+        }
+    }
+
+    /// Sets the type of the event, `message` for the client otherwise
+    pub fn with_event(mut self, event: &str) -> Self {
+        self.event = Some(event.to_string());
+        self
+    }
+
+    /// Sets the id that the client sends back in `Last-Event-ID` when it
+    /// reconnects
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = Some(id.to_string());
+        self
+    }
+
+    /// Sets how long the client waits before it reconnects
+    pub fn with_retry(mut self, retry: Duration) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// The event in the format of the stream. Every line of the data gets its
+    /// own `data:` field, the line breaks of a single-line field would end it
+    /// early so they are removed, as well as the NUL characters that make
+    /// the clients ignore an id.
+    fn serialize(&self) -> String {
+        let mut serialized = String::new();
+        if let Some(event) = &self.event {
+            serialized.push_str(&format!("event: {}\n", single_line(event)));
+        }
+        if let Some(id) = &self.id {
+            serialized.push_str(&format!("id: {}\n", single_line(id).replace('\0', "")));
+        }
+        if let Some(retry) = self.retry {
+            serialized.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        if let Some(data) = &self.data {
+            // the clients split the lines on any of CRLF, CR and LF
+            for line in data.split("\r\n").flat_map(|line| line.split(['\r', '\n'])) {
+                serialized.push_str(&format!("data: {}\n", line));
+            }
+        }
+        serialized.push('\n');
+        serialized
+    }
+}
+
+fn single_line(value: &str) -> String {
+    value.replace(['\r', '\n'], "")
+}
+
+/// The open response of an event stream. The events are sent in chunks of
+/// the response, which is ended when the stream is dropped.
+pub struct EventStream<'a> {
+    stream: &'a mut dyn Transport,
+    last_event_id: Option<String>,
+    keep_alive: Duration,
+    last_sent: Instant,
+    shutdown: ShutdownHandle,
+}
+
+impl<'a> EventStream<'a> {
+    /// The stream of the request, whose headers were sent already
+    pub(crate) fn new(stream: &'a mut dyn Transport, request: &HttpRequest) -> Self {
+        EventStream {
+            stream,
+            last_event_id: request.header_value("Last-Event-ID").map(str::to_string),
+            keep_alive: DEFAULT_KEEP_ALIVE,
+            last_sent: Instant::now(),
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// The writes fail once the server shuts down
+    pub(crate) fn with_shutdown(mut self, shutdown: ShutdownHandle) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Sets how long the stream can stay idle before a keep-alive comment is
+    /// sent, 15 seconds by default and at least [`MIN_KEEP_ALIVE`]
+    pub fn with_keep_alive(mut self, keep_alive: Duration) -> Self {
+        self.keep_alive = keep_alive.max(MIN_KEEP_ALIVE);
+        self
+    }
+
+    /// The id of the last event a reconnecting client received, so that the
+    /// stream can resume after it
+    pub fn last_event_id(&self) -> Option<&str> {
+        self.last_event_id.as_deref()
+    }
+
+    /// Sends the event, fails once the client went away
+    pub fn send(&mut self, event: Event) -> io::Result<()> {
+        self.write_chunk(event.serialize().as_bytes())
+    }
+
+    /// Sends a comment, which the clients ignore. Every line of the text is a
+    /// comment of its own.
+    pub fn comment(&mut self, text: &str) -> io::Result<()> {
+        let mut comment: String = text.split("\r\n")
+                                      .flat_map(|line| line.split(['\r', '\n']))
+                                      .map(|line| format!(": {}\n", line))
+                                      .collect();
+        comment.push('\n');
+        self.write_chunk(comment.as_bytes())
+    }
+
+    /// Sends a keep-alive comment if nothing was sent for the keep-alive
+    /// interval, for handlers that wait for their events in a loop of their
+    /// own. Fails once the client went away.
+    pub fn keep_alive(&mut self) -> io::Result<()> {
+        if self.last_sent.elapsed() >= self.keep_alive {
+            self.comment("keep-alive")?;
+        }
+        Ok(())
+    }
+
+    /// Sends the events of the channel as they come, and keep-alive comments
+    /// while it's idle. Returns once all the senders are dropped, or fails
+    /// once the client went away or the server shuts down, which is noticed
+    /// at the next event or keep-alive comment.
+    pub fn forward(&mut self, events: &Receiver<Event>) -> io::Result<()> {
+        loop {
+            let idle_for = self.keep_alive.saturating_sub(self.last_sent.elapsed());
+            match events.recv_timeout(idle_for) {
+                Ok(event) => self.send(event)?,
+                Err(RecvTimeoutError::Timeout) => self.keep_alive()?,
+                Err(RecvTimeoutError::Disconnected) => return Ok(()),
+            }
+        }
+    }
+
+    /// Writes the bytes as a chunk of the response
+    fn write_chunk(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.shutdown.is_shutdown() {
+            return Err(io::Error::new(ErrorKind::ConnectionAborted, "the server shuts down"));
+        }
+        let mut chunk = format!("{:x}\r\n", bytes.len()).into_bytes();
+        chunk.extend_from_slice(bytes);
+        chunk.extend_from_slice(b"\r\n");
+        self.stream.write_all(&chunk)?;
+        self.stream.flush()?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+}
+
+impl Drop for EventStream<'_> {
+    fn drop(&mut self) {
+        // the last chunk, the client reconnects unless it's told otherwise
+        let _ = self.stream.write_all(b"0\r\n\r\n").and_then(|_| self.stream.flush());
+    }
+}
+
+/// Answers the request of an event stream with the headers of the stream, the
+/// events follow in chunks
+pub(crate) fn open(_request: &HttpRequest) -> HttpResponse {
+    HttpResponse::with_status(StatusCode::OK, None).with_header("Content-Type", "text/event-stream")
+                                                   .with_header("Cache-Control", "no-cache")
+                                                   .with_header("Transfer-Encoding", "chunked")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{ErrorKind, Read, Write};
+    use std::sync::mpsc;
+
+    /// Keeps what the server wrote, the client never sends anything more
+    #[derive(Default)]
+    struct Client {
+        received: Vec<u8>,
+        gone: bool, // writes fail, as once the client closed the connection
+    }
+
+    impl Read for Client {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for Client {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.gone {
+                true => Err(ErrorKind::BrokenPipe.into()),
+                false => self.received.write(buf),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Puts the chunks of the body back together, checks that the last one is there
+    fn dechunk(mut body: &[u8]) -> String {
+        let mut decoded = Vec::new();
+        loop {
+            let end_of_size = body.windows(2).position(|window| window == b"\r\n").expect("chunk size");
+            let size = usize::from_str_radix(std::str::from_utf8(&body[..end_of_size]).unwrap(), 16).unwrap();
+            if size == 0 {
+                assert_eq!(&body[end_of_size..], b"\r\n\r\n");
+                return String::from_utf8(decoded).unwrap();
+            }
+            let chunk = &body[end_of_size + 2..];
+            decoded.extend_from_slice(&chunk[..size]);
+            assert_eq!(&chunk[size..size + 2], b"\r\n");
+            body = &chunk[size + 2..];
+        }
+    }
+
+    #[test]
+    fn test_serialize() {
+        assert_eq!(Event::new("hello").serialize(), "data: hello\n\n");
+        assert_eq!(Event::new("").serialize(), "data: \n\n");
+        let event = Event::new("first\nsecond\r\nthird\rfourth\n").with_event("update")
+                                                                    .with_id("7")
+                                                                    .with_retry(Duration::from_secs(3));
+        assert_eq!(event.serialize(),
+                   "event: update\nid: 7\nretry: 3000\ndata: first\ndata: second\ndata: third\ndata: fourth\ndata: \n\n");
+        // the single-line fields can't end early
+        let event = Event::default().with_event("up\ndate").with_id("1\r\n2\0");
+        assert_eq!(event.serialize(), "event: update\nid: 12\n\n");
+        assert_eq!(Event::default().with_retry(Duration::from_millis(1500)).serialize(), "retry: 1500\n\n");
+    }
+
+    #[test]
+    fn test_event_stream() {
+        let request = HttpRequest {
+            header: HashMap::from([(String::from("last-event-id"), String::from("41"))]),
+            ..HttpRequest::default()
+        };
+        let mut client = Client::default();
+        let mut events = EventStream::new(&mut client, &request).with_keep_alive(Duration::from_millis(20));
+        assert_eq!(events.last_event_id(), Some("41"));
+        events.send(Event::new("a").with_id("42")).unwrap();
+        // nothing to keep alive yet
+        events.keep_alive().unwrap();
+        events.comment("two\nlines").unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let sending = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            sender.send(Event::new("b")).unwrap();
+        });
+        events.forward(&receiver).unwrap();
+        sending.join().unwrap();
+        drop(events);
+
+        let body = dechunk(&client.received);
+        let (before, after) = body.split_once(": keep-alive\n\n").expect("a keep-alive comment");
+        assert_eq!(before, "id: 42\ndata: a\n\n: two\n: lines\n\n");
+        assert!(after.ends_with("data: b\n\n"));
+        assert_eq!(after.replace(": keep-alive\n\n", ""), "data: b\n\n");
+
+        // an interval of zero doesn't have the stream spin
+        let mut client = Client::default();
+        let mut events = EventStream::new(&mut client, &HttpRequest::default()).with_keep_alive(Duration::ZERO);
+        let (sender, receiver) = mpsc::channel::<Event>();
+        let sending = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            drop(sender);
+        });
+        events.forward(&receiver).unwrap();
+        sending.join().unwrap();
+        drop(events);
+        let comments = dechunk(&client.received).matches(": keep-alive").count();
+        assert!((1..=6).contains(&comments), "{} keep-alive comments", comments);
+
+        // the keep-alive comments find out that the client went away
+        let mut client = Client { gone: true, ..Client::default() };
+        let mut events = EventStream::new(&mut client, &HttpRequest::default()).with_keep_alive(Duration::from_millis(10));
+        assert_eq!(events.last_event_id(), None);
+        let (_sender, receiver) = mpsc::channel();
+        assert_eq!(events.forward(&receiver).unwrap_err().kind(), ErrorKind::BrokenPipe);
+    }
+
+    #[test]
+    fn test_server() {
+        use std::net::TcpStream;
+        use std::sync::Mutex;
+        use crate::server::Server;
+        use crate::testing::{self, BACKENDS};
+
+        for &backend in BACKENDS {
+            // a single worker, which the event streams must not hold
+            let server = Server::new("127.0.0.1:0").with_backend(backend).with_workers(1).with_queue_size(0);
+            server.sse("/counter/{name}", |req: &HttpRequest, mut events: EventStream| {
+                let next = events.last_event_id().and_then(|id| id.parse::<u32>().ok()).map_or(1, |id| id + 1);
+                for id in next..next + 2 {
+                    let data = format!("{} {}", req.path_params["name"], id);
+                    events.send(Event::new(data).with_id(&id.to_string())).unwrap();
+                }
+            });
+            // tells the test how the stream ended
+            let (ended, stream_end) = mpsc::channel();
+            let ended = Mutex::new(ended);
+            server.sse("/idle", move |_: &HttpRequest, events: EventStream| {
+                let (_sender, receiver) = mpsc::channel();
+                let forwarded = events.with_keep_alive(Duration::from_millis(10)).forward(&receiver);
+                ended.lock().unwrap().send(forwarded.is_err()).unwrap();
+            });
+            let (handle, addr, running) = testing::start(server);
+
+            // the connection is closed once the handler returns
+            let (mut stream, head, mut body) = testing::open(addr, b"GET /counter/ann HTTP/1.1\r\nAccept: text/event-stream\r\n\
+                                                                     Last-Event-ID: 5\r\n\r\n");
+            stream.read_to_end(&mut body).unwrap();
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}: {}", backend, head);
+            assert!(head.contains("Content-Type: text/event-stream\r\n"));
+            assert!(head.contains("Cache-Control: no-cache\r\n"));
+            assert!(head.contains("Transfer-Encoding: chunked\r\n"));
+            assert!(!head.contains("Content-Length"));
+            assert_eq!(dechunk(&body), "id: 6\ndata: ann 6\n\nid: 7\ndata: ann 7\n\n");
+
+            // the handler finds out that the client went away
+            drop(testing::open(addr, b"GET /idle HTTP/1.1\r\n\r\n"));
+            assert!(stream_end.recv_timeout(Duration::from_secs(5)).unwrap());
+
+            // open event streams leave the workers to the requests
+            let open: Vec<TcpStream> = (0..3).map(|_| testing::open(addr, b"GET /idle HTTP/1.1\r\n\r\n").0).collect();
+            let response = testing::fetch(addr, b"GET /counter/bob HTTP/1.1\r\n\r\n");
+            assert!(response.ends_with("data: bob 2\n\n\r\n0\r\n\r\n"), "{:?}", backend);
+            drop(open);
+            for _ in 0..3 {
+                assert!(stream_end.recv_timeout(Duration::from_secs(5)).unwrap());
+            }
+
+            // the open ones are ended as the server shuts down
+            let (mut stream, _, _) = testing::open(addr, b"GET /idle HTTP/1.1\r\n\r\n");
+            handle.shutdown();
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).unwrap();
+            assert!(response.ends_with(b"0\r\n\r\n"), "{:?}", backend);
+            assert!(stream_end.recv_timeout(Duration::from_secs(5)).unwrap());
+            running.join().unwrap();
+        }
+    }
+}
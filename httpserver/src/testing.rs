@@ -0,0 +1,80 @@
+//! What the tests that run a server and talk to it over a socket share
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::connection::RequestParser;
+use crate::server::{Backend, Server};
+use crate::shutdown::ShutdownHandle;
+
+/// The backends the crate is built with, to run the same test on each of them
+pub(crate) const BACKENDS: &[Backend] = &[
+    Backend::Threads,
+    Backend::Events,
+    #[cfg(feature = "tokio")]
+    Backend::Tokio,
+];
+
+/// How long the clients wait for the server, so that a test fails rather than hangs
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs the server on a thread of its own and returns once it listens
+pub(crate) fn start<S: Send + Sync + 'static>(server: Server<'static, S>) -> (ShutdownHandle, SocketAddr, JoinHandle<()>) {
+    let handle = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+    while handle.local_addr().is_none() {
+        thread::sleep(Duration::from_millis(10));
+    }
+    let addr = handle.local_addr().unwrap();
+    (handle, addr, running)
+}
+
+/// Connects to the server, with reads that time out
+pub(crate) fn connect(addr: SocketAddr) -> TcpStream {
+    let stream = TcpStream::connect(addr).unwrap();
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT)).unwrap();
+    stream
+}
+
+/// Sends the bytes on a new connection and reads until the server closes it
+pub(crate) fn fetch(addr: SocketAddr, bytes: &[u8]) -> String {
+    let mut stream = connect(addr);
+    stream.write_all(bytes).unwrap();
+    let mut received = Vec::new();
+    stream.read_to_end(&mut received).unwrap();
+    String::from_utf8(received).unwrap()
+}
+
+/// Sends the bytes on a new connection and reads the head of the response.
+/// Returns the connection, the head and what was received past it.
+pub(crate) fn open(addr: SocketAddr, bytes: &[u8]) -> (TcpStream, String, Vec<u8>) {
+    let mut stream = connect(addr);
+    stream.write_all(bytes).unwrap();
+    let mut received = Vec::new();
+    let mut temp_buff = [0u8; 1024];
+    let end_of_head = loop {
+        if let Some(position) = received.windows(4).position(|window| window == b"\r\n\r\n") {
+            break position + 4;
+        }
+        let n = stream.read(&mut temp_buff).unwrap();
+        assert!(n > 0, "closed before the end of the head");
+        received.extend_from_slice(&temp_buff[..n]);
+    };
+    let rest = received.split_off(end_of_head);
+    (stream, String::from_utf8(received).unwrap(), rest)
+}
+
+/// Sends a request on the connection and reads one response, whose head is
+/// framed like a request's. The parser keeps what the server sent past it,
+/// e.g. the next pipelined response.
+pub(crate) fn exchange(stream: &mut TcpStream, parser: &mut RequestParser, request: &[u8]) -> String {
+    stream.write_all(request).unwrap();
+    read_response(stream, parser)
+}
+
+/// Reads one response, see [`exchange`]
+pub(crate) fn read_response(stream: &mut TcpStream, parser: &mut RequestParser) -> String {
+    String::from_utf8(parser.read_request(stream).unwrap().unwrap()).unwrap()
+}
@@ -0,0 +1,782 @@
+//! HTTPS with rustls, behind the `tls` feature. The connections are decrypted
+//! by the backend, the routes, the middleware and the handlers see the same
+//! requests as with plain TCP. The clients that offer `h2` with ALPN are
+//! served HTTP/2, the others HTTP/1.1.
+//!
+//! A server with a single certificate is built with
+//! [`crate::server::Server::bind_tls`], one that hosts several domains picks
+//! the certificate of each connection from a [`CertificateStore`]:
+//!
+//! ```text
+//! let certificates = Arc::new(CertificateStore::from_dir("/etc/httpserver/certs")?);
+//! certificates.watch(Duration::from_secs(10));
+//! let server = Server::new("0.0.0.0:443").with_tls(tls::sni_server_config(certificates));
+//! ```
+//!
+//! [`TlsConfig`] builds the configurations that also authenticate the clients.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::Display;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use std::{fs, io, thread};
+
+use http::httprequest::{PeerCertificate, SubjectAltName};
+use ring::digest;
+use rustls::crypto::{ring as ring_provider, CryptoProvider};
+use rustls::pki_types::pem::{self, PemObject};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::danger::ClientCertVerifier;
+use rustls::server::{ClientHello, ResolvesServerCert, VerifierBuilderError, WebPkiClientVerifier};
+use rustls::sign::{CertifiedKey, SingleCertAndKey};
+use rustls::{RootCertStore, ServerConnection};
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+pub use rustls::ServerConfig;
+
+/// The ALPN protocols the server negotiates, HTTP/2 first
+pub const ALPN_H2: &[u8] = b"h2";
+pub const ALPN_HTTP_1_1: &[u8] = b"http/1.1";
+
+/// Error while loading the certificates and the key of the server
+#[derive(Debug)]
+pub enum TlsError {
+    /// The directory of the certificates can't be read
+    Io(PathBuf, io::Error),
+    /// The PEM file can't be read or parsed
+    Pem(PathBuf, pem::Error),
+    /// The file doesn't contain any certificate
+    NoCertificate(PathBuf),
+    /// rustls refused the configuration, e.g. the key doesn't match the certificate
+    Rustls(rustls::Error),
+    /// The CA bundle of the client certificates can't be used
+    ClientAuth(VerifierBuilderError),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Io(path, e) => write!(f, "couldn't read {}: {}", path.display(), e),
+            TlsError::Pem(path, e) => write!(f, "couldn't load {}: {}", path.display(), e),
+            TlsError::NoCertificate(path) => write!(f, "no certificate in {}", path.display()),
+            TlsError::Rustls(e) => write!(f, "invalid TLS configuration: {}", e),
+            TlsError::ClientAuth(e) => write!(f, "invalid CA bundle: {}", e),
+        }
+    }
+}
+
+impl Error for TlsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TlsError::Io(_, e) => Some(e),
+            TlsError::Pem(_, e) => Some(e),
+            TlsError::NoCertificate(_) => None,
+            TlsError::Rustls(e) => Some(e),
+            TlsError::ClientAuth(e) => Some(e),
+        }
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(e: rustls::Error) -> Self {
+        TlsError::Rustls(e)
+    }
+}
+
+/// Loads a certificate chain, the certificate of the server first and then
+/// the intermediate ones, from a PEM file.
+pub fn load_certs(path: impl AsRef<Path>) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let path = path.as_ref();
+    let certs = CertificateDer::pem_file_iter(path).and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                                                   .map_err(|e| TlsError::Pem(path.to_path_buf(), e))?;
+    if certs.is_empty() {
+        return Err(TlsError::NoCertificate(path.to_path_buf()));
+    }
+    Ok(certs)
+}
+
+/// Loads the first private key, PKCS#8, PKCS#1 or SEC1, of a PEM file
+pub fn load_key(path: impl AsRef<Path>) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let path = path.as_ref();
+    PrivateKeyDer::from_pem_file(path).map_err(|e| TlsError::Pem(path.to_path_buf(), e))
+}
+
+/// Loads a certificate chain and its private key, checking that they belong together
+pub fn load_certified_key(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Arc<CertifiedKey>, TlsError> {
+    let certified_key = CertifiedKey::from_der(load_certs(cert_path)?, load_key(key_path)?, &provider())?;
+    Ok(Arc::new(certified_key))
+}
+
+/// The configuration of a server with the certificate chain and the key of
+/// the PEM files, for [`crate::server::Server::with_tls`].
+pub fn server_config(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Arc<ServerConfig>, TlsError> {
+    Ok(TlsConfig::new(cert_path, key_path)?.build())
+}
+
+/// The configuration of a server that picks the certificate of each connection
+/// from the store, for [`crate::server::Server::with_tls`].
+pub fn sni_server_config(certificates: Arc<CertificateStore>) -> Arc<ServerConfig> {
+    TlsConfig::sni(certificates).build()
+}
+
+/// Builds the configuration of a server that needs more than a certificate,
+/// e.g. one that authenticates its clients:
+///
+/// ```text
+/// let config = TlsConfig::new("cert.pem", "key.pem")?.with_client_auth(ClientAuth::required("ca.pem")?)
+///                                                     .build();
+/// let server = Server::new("0.0.0.0:443").with_tls(config);
+/// ```
+pub struct TlsConfig {
+    certificates: Arc<dyn ResolvesServerCert>,
+    client_auth: Option<ClientAuth>,
+}
+
+impl TlsConfig {
+    /// With the certificate chain and the key of the PEM files
+    pub fn new(cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<Self, TlsError> {
+        let certified_key = load_certified_key(cert_path, key_path)?;
+        Ok(TlsConfig {
+            certificates: Arc::new(SingleCertAndKey::from(certified_key)),
+            client_auth: None,
+        })
+    }
+
+    /// With the certificates of the store, chosen by SNI
+    pub fn sni(certificates: Arc<CertificateStore>) -> Self {
+        TlsConfig {
+            certificates,
+            client_auth: None,
+        }
+    }
+
+    pub fn with_client_auth(mut self, client_auth: ClientAuth) -> Self {
+        self.client_auth = Some(client_auth);
+        self
+    }
+
+    /// Negotiates `h2` or `http/1.1` with ALPN
+    pub fn build(self) -> Arc<ServerConfig> {
+        let builder = ServerConfig::builder_with_provider(provider())
+                          .with_safe_default_protocol_versions()
+                          .expect("The default protocol versions are supported by ring");
+        let builder = match self.client_auth {
+            Some(client_auth) => builder.with_client_cert_verifier(client_auth.verifier),
+            None => builder.with_no_client_auth(),
+        };
+        let mut config = builder.with_cert_resolver(self.certificates);
+        config.alpn_protocols = vec![ALPN_H2.to_vec(), ALPN_HTTP_1_1.to_vec()];
+        Arc::new(config)
+    }
+}
+
+/// Mutual TLS: the server asks the clients for a certificate signed by one of
+/// the CAs of a bundle. The verified certificate is available to the handlers
+/// and the middleware as [`http::httprequest::HttpRequest::peer_certificate`].
+pub struct ClientAuth {
+    verifier: Arc<dyn ClientCertVerifier>,
+}
+
+impl ClientAuth {
+    /// The handshake fails without a valid client certificate
+    pub fn required(ca_path: impl AsRef<Path>) -> Result<Self, TlsError> {
+        Self::build(ca_path.as_ref(), true)
+    }
+
+    /// A client without a certificate can connect, its requests have no peer
+    /// certificate. A client with an invalid certificate can't.
+    pub fn optional(ca_path: impl AsRef<Path>) -> Result<Self, TlsError> {
+        Self::build(ca_path.as_ref(), false)
+    }
+
+    fn build(ca_path: &Path, required: bool) -> Result<Self, TlsError> {
+        let mut roots = RootCertStore::empty();
+        for cert in load_certs(ca_path)? {
+            roots.add(cert)?;
+        }
+        let mut builder = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider());
+        if !required {
+            builder = builder.allow_unauthenticated();
+        }
+        let verifier = builder.build().map_err(TlsError::ClientAuth)?;
+        Ok(ClientAuth { verifier })
+    }
+}
+
+/// The certificate that the client of the connection sent and that was
+/// verified, once the handshake is over
+pub(crate) fn peer_certificate(connection: &ServerConnection) -> Option<PeerCertificate> {
+    let der = connection.peer_certificates()?.first()?;
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let subject_alt_names = match cert.subject_alternative_name() {
+        Ok(Some(extension)) => extension.value.general_names.iter().filter_map(|name| match name {
+            GeneralName::DNSName(name) => Some(SubjectAltName::Dns(name.to_string())),
+            GeneralName::URI(uri) => Some(SubjectAltName::Uri(uri.to_string())),
+            GeneralName::RFC822Name(email) => Some(SubjectAltName::Email(email.to_string())),
+            GeneralName::IPAddress(ip) => match ip.len() {
+                4 => <[u8; 4]>::try_from(*ip).ok().map(|ip| SubjectAltName::Ip(IpAddr::from(ip))),
+                16 => <[u8; 16]>::try_from(*ip).ok().map(|ip| SubjectAltName::Ip(IpAddr::from(ip))),
+                _ => None,
+            },
+            _ => None,
+        }).collect(),
+        _ => Vec::new(),
+    };
+    let fingerprint = digest::digest(&digest::SHA256, der).as_ref()
+                                                          .iter()
+                                                          .map(|byte| format!("{:02x}", byte))
+                                                          .collect();
+    Some(PeerCertificate {
+        subject: cert.subject().to_string(),
+        subject_alt_names,
+        fingerprint,
+        der: der.to_vec(),
+    })
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring_provider::default_provider())
+}
+
+/// Certificates chosen by the hostname that the client sends with SNI, for a
+/// server that hosts several domains. A wildcard certificate is added under
+/// its name, e.g. `*.example.com`.
+///
+/// The certificates are reloaded when their files change, see
+/// [`CertificateStore::reload`]. New handshakes use the new certificate while
+/// the connections that are already established go on with the old one.
+#[derive(Debug, Default)]
+pub struct CertificateStore {
+    dir: Option<PathBuf>,
+    certs: RwLock<HashMap<String, StoredCert>>, // by lowercase hostname
+    default_hostname: RwLock<Option<String>>,
+}
+
+#[derive(Debug)]
+struct StoredCert {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    modified: (Option<SystemTime>, Option<SystemTime>), // of the certificate and the key when they were loaded
+    in_dir: bool, // dropped when its directory goes away
+    certified_key: Arc<CertifiedKey>,
+}
+
+impl StoredCert {
+    fn load(cert_path: PathBuf, key_path: PathBuf, in_dir: bool) -> Result<Self, TlsError> {
+        // read before the files, so that a change while they're loaded is seen by the next reload
+        let modified = (modified(&cert_path), modified(&key_path));
+        let certified_key = load_certified_key(&cert_path, &key_path)?;
+        Ok(StoredCert { cert_path, key_path, modified, in_dir, certified_key })
+    }
+
+    fn is_outdated(&self) -> bool {
+        (modified(&self.cert_path), modified(&self.key_path)) != self.modified
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+impl CertificateStore {
+    pub fn new() -> Self {
+        CertificateStore::default()
+    }
+
+    /// Loads a certificate per subdirectory of `dir`, the name of the
+    /// subdirectory is the hostname and it holds the `cert.pem` chain and the
+    /// `key.pem` key. Subdirectories that are added or removed later are picked
+    /// up by [`CertificateStore::reload`].
+    pub fn from_dir(dir: impl AsRef<Path>) -> Result<Self, TlsError> {
+        let certificates = CertificateStore {
+            dir: Some(dir.as_ref().to_path_buf()),
+            ..CertificateStore::default()
+        };
+        certificates.reload().map_err(|mut errors| errors.remove(0))?;
+        Ok(certificates)
+    }
+
+    /// Adds, or replaces, the certificate of a hostname
+    pub fn add(&self, hostname: &str, cert_path: impl AsRef<Path>, key_path: impl AsRef<Path>) -> Result<(), TlsError> {
+        let cert = StoredCert::load(cert_path.as_ref().to_path_buf(), key_path.as_ref().to_path_buf(), false)?;
+        self.certs.write().unwrap().insert(hostname.to_lowercase(), cert);
+        Ok(())
+    }
+
+    /// Sets the hostname whose certificate is sent to the clients that don't
+    /// use SNI or ask for an unknown hostname. Without it, their handshake
+    /// fails unless the store has a single certificate.
+    pub fn set_default(&self, hostname: &str) {
+        *self.default_hostname.write().unwrap() = Some(hostname.to_lowercase());
+    }
+
+    pub fn hostnames(&self) -> Vec<String> {
+        let mut hostnames: Vec<String> = self.certs.read().unwrap().keys().cloned().collect();
+        hostnames.sort();
+        hostnames
+    }
+
+    /// Loads again the certificates whose files changed, and the ones that
+    /// appeared in the directory. Each certificate is swapped at once, a
+    /// certificate that fails to load, e.g. because its key isn't written yet,
+    /// is kept as it was and tried again by the next reload.
+    pub fn reload(&self) -> Result<(), Vec<TlsError>> {
+        let mut errors = Vec::new();
+        let mut reloaded = Vec::new();
+        let mut removed = Vec::new();
+        {
+            let certs = self.certs.read().unwrap();
+            for (hostname, cert) in certs.iter() {
+                if cert.in_dir && !cert.cert_path.parent().is_some_and(Path::exists) {
+                    removed.push(hostname.clone());
+                } else if cert.is_outdated() {
+                    match StoredCert::load(cert.cert_path.clone(), cert.key_path.clone(), cert.in_dir) {
+                        Ok(cert) => reloaded.push((hostname.clone(), cert)),
+                        Err(e) => errors.push(e),
+                    }
+                }
+            }
+
+            if let Some(dir) = &self.dir {
+                match fs::read_dir(dir) {
+                    Ok(entries) => {
+                        for entry in entries.flatten() {
+                            let path = entry.path();
+                            let hostname = entry.file_name().to_string_lossy().to_lowercase();
+                            if !path.is_dir() || certs.contains_key(&hostname) {
+                                continue;
+                            }
+                            match StoredCert::load(path.join("cert.pem"), path.join("key.pem"), true) {
+                                Ok(cert) => reloaded.push((hostname, cert)),
+                                Err(e) => errors.push(e),
+                            }
+                        }
+                    },
+                    Err(e) => errors.push(TlsError::Io(dir.clone(), e)),
+                }
+            }
+        }
+
+        // the handshakes only wait for the swap, not for the files
+        let mut certs = self.certs.write().unwrap();
+        for hostname in removed {
+            certs.remove(&hostname);
+        }
+        certs.extend(reloaded);
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(errors),
+        }
+    }
+
+    /// Reloads the certificates every `interval` on a thread of its own, until
+    /// the store is dropped. The errors are printed.
+    pub fn watch(self: &Arc<Self>, interval: Duration) {
+        let certificates = Arc::downgrade(self);
+        thread::Builder::new().name(String::from("certificate-watcher"))
+                              .spawn(move || Self::watch_until_dropped(&certificates, interval))
+                              .expect("Couldn't spawn the certificate watcher");
+    }
+
+    fn watch_until_dropped(certificates: &Weak<Self>, interval: Duration) {
+        loop {
+            thread::sleep(interval);
+            let Some(certificates) = certificates.upgrade() else { break };
+            if let Err(errors) = certificates.reload() {
+                for e in errors {
+                    eprintln!("Couldn't reload a certificate: {}", e);
+                }
+            }
+        }
+    }
+
+    /// The certificate for the hostname: the one of the exact name, else
+    /// the wildcard one of its parent domain, else the default one
+    fn lookup(&self, hostname: Option<&str>) -> Option<Arc<CertifiedKey>> {
+        let certs = self.certs.read().unwrap();
+        if let Some(hostname) = hostname.map(str::to_lowercase) {
+            if let Some(cert) = certs.get(&hostname) {
+                return Some(cert.certified_key.clone());
+            }
+            if let Some((_, parent)) = hostname.split_once('.') {
+                if let Some(cert) = certs.get(&format!("*.{}", parent)) {
+                    return Some(cert.certified_key.clone());
+                }
+            }
+        }
+        match &*self.default_hostname.read().unwrap() {
+            Some(default_hostname) => certs.get(default_hostname).map(|cert| cert.certified_key.clone()),
+            None if certs.len() == 1 => certs.values().next().map(|cert| cert.certified_key.clone()),
+            None => None,
+        }
+    }
+}
+
+impl ResolvesServerCert for CertificateStore {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        self.lookup(client_hello.server_name())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpStream};
+    use http::httprequest::HttpRequest;
+    use rustls::pki_types::ServerName;
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, StreamOwned};
+    use crate::connection::read_request;
+    use crate::server::{Backend, Server};
+    use crate::shutdown::ShutdownHandle;
+    use crate::testing::{self, BACKENDS};
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DnType, ExtendedKeyUsagePurpose, IsCa, KeyPair, SanType};
+
+    /// A new empty directory for the files of a test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("httpserver-tls-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Writes a new self-signed certificate for the hostname and its key to
+    /// `cert.pem` and `key.pem` in the directory
+    fn self_signed(dir: &Path, hostname: &str) -> (PathBuf, PathBuf) {
+        fs::create_dir_all(dir).unwrap();
+        let certified = rcgen::generate_simple_self_signed(vec![hostname.to_string()]).unwrap();
+        let (cert_path, key_path) = (dir.join("cert.pem"), dir.join("key.pem"));
+        fs::write(&cert_path, certified.cert.pem()).unwrap();
+        fs::write(&key_path, certified.key_pair.serialize_pem()).unwrap();
+        (cert_path, key_path)
+    }
+
+    /// A client that trusts the certificates
+    fn connect(addr: SocketAddr, hostname: &str, cert_paths: &[&Path]) -> StreamOwned<ClientConnection, TcpStream> {
+        connect_as(addr, hostname, cert_paths, None)
+    }
+
+    /// A client that authenticates with the certificate, if any
+    fn connect_as(addr: SocketAddr,
+                  hostname: &str,
+                  cert_paths: &[&Path],
+                  identity: Option<&(Certificate, KeyPair)>) -> StreamOwned<ClientConnection, TcpStream> {
+        let mut config = client_config(cert_paths, identity);
+        config.alpn_protocols = vec![ALPN_HTTP_1_1.to_vec()];
+        let server_name = ServerName::try_from(hostname.to_string()).unwrap();
+        let connection = ClientConnection::new(Arc::new(config), server_name).unwrap();
+        StreamOwned::new(connection, TcpStream::connect(addr).unwrap())
+    }
+
+    fn client_config(cert_paths: &[&Path], identity: Option<&(Certificate, KeyPair)>) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        for cert_path in cert_paths {
+            roots.add_parsable_certificates(load_certs(cert_path).unwrap());
+        }
+        let builder = ClientConfig::builder_with_provider(provider())
+                          .with_safe_default_protocol_versions()
+                          .unwrap()
+                          .with_root_certificates(roots);
+        match identity {
+            Some((cert, key)) => {
+                let key = PrivateKeyDer::Pkcs8(key.serialize_der().into());
+                builder.with_client_auth_cert(vec![cert.der().clone()], key).unwrap()
+            },
+            None => builder.with_no_client_auth(),
+        }
+    }
+
+    fn exchange(stream: &mut StreamOwned<ClientConnection, TcpStream>, request: &str) -> String {
+        stream.write_all(request.as_bytes()).unwrap();
+        String::from_utf8(read_request(stream).unwrap().unwrap()).unwrap()
+    }
+
+    /// Whether the server ended the handshake, which the client may only see
+    /// after it sent the request with TLS 1.3
+    fn refused(stream: &mut StreamOwned<ClientConnection, TcpStream>) -> bool {
+        stream.write_all(b"GET /whoami HTTP/1.1\r\n\r\n").is_err() || read_request(stream).is_err()
+    }
+
+    /// A new CA and a client certificate it signed, for `CN=billing`
+    fn client_ca(dir: &Path) -> (PathBuf, (Certificate, KeyPair)) {
+        let ca_key = KeyPair::generate().unwrap();
+        let mut ca_params = CertificateParams::new(Vec::new()).unwrap();
+        ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        ca_params.distinguished_name.push(DnType::CommonName, "Test CA");
+        let ca = ca_params.self_signed(&ca_key).unwrap();
+        let ca_path = dir.join("ca.pem");
+        fs::create_dir_all(dir).unwrap();
+        fs::write(&ca_path, ca.pem()).unwrap();
+
+        let client_key = KeyPair::generate().unwrap();
+        let mut client_params = CertificateParams::new(vec![String::from("billing.internal")]).unwrap();
+        client_params.subject_alt_names.push(SanType::URI("spiffe://example.org/billing".try_into().unwrap()));
+        client_params.subject_alt_names.push(SanType::IpAddress([10, 0, 0, 7].into()));
+        client_params.distinguished_name.push(DnType::CommonName, "billing");
+        client_params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        let client = client_params.signed_by(&client_key, &ca, &ca_key).unwrap();
+        (ca_path, (client, client_key))
+    }
+
+    /// Runs the server with a route that greets, see [`testing::start`]
+    fn start<S: Send + Sync + 'static>(server: Server<'static, S>) -> (ShutdownHandle, SocketAddr, thread::JoinHandle<()>) {
+        server.get("/hello/{name}", |req: &HttpRequest| format!("Hello {}!", req.path_params["name"]));
+        testing::start(server)
+    }
+
+    /// The certificate the server sent
+    fn peer_cert(stream: &StreamOwned<ClientConnection, TcpStream>) -> CertificateDer<'static> {
+        stream.conn.peer_certificates().unwrap()[0].clone().into_owned()
+    }
+
+    #[test]
+    fn test_https() {
+        let (cert_path, key_path) = self_signed(&temp_dir("https"), "localhost");
+        for &backend in BACKENDS {
+            let server = Server::bind_tls("127.0.0.1:0", &cert_path, &key_path).unwrap()
+                                                                               .with_backend(backend)
+                                                                               .with_workers(2);
+            let (handle, addr, running) = start(server);
+
+            let mut stream = connect(addr, "localhost", &[&cert_path]);
+            let response = exchange(&mut stream, "GET /hello/ann HTTP/1.1\r\n\r\n");
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}: {}", backend, response);
+            assert!(response.ends_with("\r\n\r\nHello ann!"));
+            assert_eq!(stream.conn.alpn_protocol(), Some(ALPN_HTTP_1_1));
+            if backend != Backend::Threads {
+                // the connection is kept alive
+                let response = exchange(&mut stream, "GET /hello/bob HTTP/1.1\r\nConnection: close\r\n\r\n");
+                assert!(response.ends_with("\r\n\r\nHello bob!"));
+            }
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_h2() {
+        let (cert_path, key_path) = self_signed(&temp_dir("h2"), "localhost");
+        for &backend in BACKENDS {
+            let server = Server::bind_tls("127.0.0.1:0", &cert_path, &key_path).unwrap()
+                                                                               .with_backend(backend)
+                                                                               .with_workers(2);
+            let (handle, addr, running) = start(server);
+
+            let mut config = client_config(&[&cert_path], None);
+            config.alpn_protocols = vec![ALPN_H2.to_vec(), ALPN_HTTP_1_1.to_vec()];
+            let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+            let (protocol, status, body) = runtime.block_on(async move {
+                let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+                let server_name = ServerName::try_from("localhost").unwrap();
+                let tls = tokio_rustls::TlsConnector::from(Arc::new(config)).connect(server_name, tcp).await.unwrap();
+                let protocol = tls.get_ref().1.alpn_protocol().map(<[u8]>::to_vec);
+                let (mut client, connection) = h2::client::handshake(tls).await.unwrap();
+                tokio::spawn(connection);
+
+                let request = http1::Request::get("https://localhost/hello/ann").body(()).unwrap();
+                let (response, _) = client.send_request(request, true).unwrap();
+                let (parts, mut response_body) = response.await.unwrap().into_parts();
+                let mut body = Vec::new();
+                while let Some(data) = response_body.data().await {
+                    body.extend_from_slice(&data.unwrap());
+                }
+                (protocol, parts.status.as_u16(), body)
+            });
+            assert_eq!(protocol.as_deref(), Some(ALPN_H2), "{:?}", backend);
+            assert_eq!(status, 200);
+            assert_eq!(body, b"Hello ann!");
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_websocket() {
+        let (cert_path, key_path) = self_signed(&temp_dir("websocket"), "localhost");
+        for &backend in BACKENDS {
+            let server = Server::bind_tls("127.0.0.1:0", &cert_path, &key_path).unwrap()
+                                                                               .with_backend(backend)
+                                                                               .with_workers(2);
+            server.websocket("/echo", |_: &HttpRequest, mut ws: crate::websocket::WebSocket| {
+                while let Ok(message) = ws.read() {
+                    ws.send(message).unwrap();
+                }
+            });
+            let (handle, addr, running) = start(server);
+
+            let mut stream = connect(addr, "localhost", &[&cert_path]);
+            let handshake = "GET /echo HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                             Sec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+            // a text frame and a close frame, masked with zeros
+            let frames = [0x81, 0x82, 0, 0, 0, 0, b'h', b'i', 0x88, 0x82, 0, 0, 0, 0, 0x03, 0xe8];
+            stream.write_all(&[handshake.as_bytes(), &frames].concat()).unwrap();
+            let expected = [0x81, 0x02, b'h', b'i', 0x88, 0x02, 0x03, 0xe8];
+            let mut received = Vec::new();
+            let mut temp_buff = [0u8; 1024];
+            while !received.ends_with(&expected) {
+                let n = stream.read(&mut temp_buff).unwrap();
+                assert!(n > 0, "{:?}: {}", backend, String::from_utf8_lossy(&received));
+                received.extend_from_slice(&temp_buff[..n]);
+            }
+            assert!(received.starts_with(b"HTTP/1.1 101 Switching Protocols\r\n"));
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let (cert_path, key_path) = self_signed(&temp_dir("errors"), "localhost");
+        let (other_cert_path, _) = self_signed(&temp_dir("errors-other"), "localhost");
+        assert!(server_config(&cert_path, &key_path).is_ok());
+
+        let missing = cert_path.with_file_name("missing.pem");
+        assert!(matches!(server_config(&missing, &key_path), Err(TlsError::Pem(path, pem::Error::Io(_))) if path == missing));
+        assert!(matches!(server_config(&key_path, &key_path), Err(TlsError::NoCertificate(path)) if path == key_path));
+        assert!(matches!(server_config(&cert_path, &cert_path), Err(TlsError::Pem(_, pem::Error::NoItemsFound))));
+        // the key doesn't belong to the certificate
+        assert!(matches!(server_config(&other_cert_path, &key_path), Err(TlsError::Rustls(_))));
+        assert!(matches!(load_certified_key(&other_cert_path, &key_path), Err(TlsError::Rustls(_))));
+    }
+
+    #[test]
+    fn test_certificate_lookup() {
+        let dir = temp_dir("lookup");
+        let certificates = CertificateStore::new();
+        let (cert_path, key_path) = self_signed(&dir.join("one"), "one.test");
+        certificates.add("One.test", &cert_path, &key_path).unwrap();
+        // a single certificate is sent to everyone
+        assert!(certificates.lookup(None).is_some());
+        assert!(certificates.lookup(Some("other.test")).is_some());
+
+        let (cert_path, key_path) = self_signed(&dir.join("wildcard"), "*.two.test");
+        certificates.add("*.two.test", &cert_path, &key_path).unwrap();
+        assert_eq!(certificates.hostnames(), ["*.two.test", "one.test"]);
+        let one = certificates.lookup(Some("one.test")).unwrap();
+        let two = certificates.lookup(Some("a.Two.test")).unwrap();
+        assert_ne!(one.cert, two.cert);
+        assert!(certificates.lookup(Some("a.b.two.test")).is_none());
+        assert!(certificates.lookup(None).is_none());
+
+        certificates.set_default("one.test");
+        assert_eq!(certificates.lookup(Some("other.test")).unwrap().cert, one.cert);
+    }
+
+    #[test]
+    fn test_sni_and_reload() {
+        let dir = temp_dir("sni");
+        let (a_cert_path, _) = self_signed(&dir.join("a.localhost"), "a.localhost");
+        let (b_cert_path, _) = self_signed(&dir.join("b.localhost"), "b.localhost");
+        let certificates = Arc::new(CertificateStore::from_dir(&dir).unwrap());
+        assert_eq!(certificates.hostnames(), ["a.localhost", "b.localhost"]);
+
+        let server = Server::new("127.0.0.1:0").with_tls(sni_server_config(certificates.clone()))
+                                               .with_backend(Backend::Events)
+                                               .with_workers(2);
+        let (handle, addr, running) = start(server);
+
+        let roots = [a_cert_path.as_path(), b_cert_path.as_path()];
+        let mut a = connect(addr, "a.localhost", &roots);
+        assert!(exchange(&mut a, "GET /hello/a HTTP/1.1\r\n\r\n").ends_with("Hello a!"));
+        assert_eq!(peer_cert(&a), load_certs(&a_cert_path).unwrap()[0]);
+        let mut b = connect(addr, "b.localhost", &roots);
+        assert!(exchange(&mut b, "GET /hello/b HTTP/1.1\r\n\r\n").ends_with("Hello b!"));
+        assert_eq!(peer_cert(&b), load_certs(&b_cert_path).unwrap()[0]);
+        // no default certificate for an unknown hostname
+        let mut unknown = connect(addr, "c.localhost", &roots);
+        assert!(unknown.write_all(b"GET /hello/c HTTP/1.1\r\n\r\n").is_err());
+
+        // the watcher swaps the renewed certificate of b and picks up c
+        let old_b_cert = peer_cert(&b);
+        let (new_b_cert_path, _) = self_signed(&dir.join("b.localhost"), "b.localhost");
+        let (c_cert_path, _) = self_signed(&dir.join("c.localhost"), "c.localhost");
+        certificates.watch(Duration::from_millis(20));
+        let roots = [new_b_cert_path.as_path(), c_cert_path.as_path()];
+        let mut renewed = None;
+        for _ in 0..250 {
+            thread::sleep(Duration::from_millis(20));
+            if certificates.hostnames().len() == 3 && certificates.lookup(Some("b.localhost")).unwrap().cert[0] != old_b_cert {
+                renewed = Some(connect(addr, "b.localhost", &roots));
+                break;
+            }
+        }
+        let mut renewed = renewed.expect("the certificates weren't reloaded");
+        assert!(exchange(&mut renewed, "GET /hello/new HTTP/1.1\r\n\r\n").ends_with("Hello new!"));
+        assert_eq!(peer_cert(&renewed), load_certs(&new_b_cert_path).unwrap()[0]);
+        let mut c = connect(addr, "c.localhost", &roots);
+        assert!(exchange(&mut c, "GET /hello/c HTTP/1.1\r\n\r\n").ends_with("Hello c!"));
+
+        // the connection established before the reload goes on with the old certificate
+        assert!(exchange(&mut b, "GET /hello/old HTTP/1.1\r\n\r\n").ends_with("Hello old!"));
+        assert_eq!(peer_cert(&b), old_b_cert);
+
+        // a directory that goes away takes its certificate with it
+        fs::remove_dir_all(dir.join("a.localhost")).unwrap();
+        certificates.reload().unwrap();
+        assert_eq!(certificates.hostnames(), ["b.localhost", "c.localhost"]);
+
+        handle.shutdown();
+        running.join().unwrap();
+    }
+
+    #[test]
+    fn test_client_auth() {
+        let dir = temp_dir("mtls");
+        let (cert_path, key_path) = self_signed(&dir, "localhost");
+        let (ca_path, client) = client_ca(&dir);
+        let (_, stranger) = client_ca(&dir.join("other"));
+        let whoami = |req: &HttpRequest| match &req.peer_certificate {
+            Some(cert) => format!("{} {:?} {}", cert.subject, cert.subject_alt_names, cert.fingerprint),
+            None => String::from("anonymous"),
+        };
+        let fingerprint: String = digest::digest(&digest::SHA256, client.0.der()).as_ref()
+                                                                               .iter()
+                                                                               .map(|byte| format!("{:02x}", byte))
+                                                                               .collect();
+        let expected = format!("CN=billing [Dns(\"billing.internal\"), Uri(\"spiffe://example.org/billing\"), Ip(10.0.0.7)] {}",
+                               fingerprint);
+
+        for &backend in BACKENDS {
+            let config = TlsConfig::new(&cert_path, &key_path).unwrap()
+                                                             .with_client_auth(ClientAuth::required(&ca_path).unwrap())
+                                                             .build();
+            let server = Server::new("127.0.0.1:0").with_tls(config).with_backend(backend).with_workers(2);
+            server.get("/whoami", whoami);
+            let (handle, addr, running) = start(server);
+
+            let mut stream = connect_as(addr, "localhost", &[&cert_path], Some(&client));
+            let response = exchange(&mut stream, "GET /whoami HTTP/1.1\r\n\r\n");
+            assert!(response.ends_with(&format!("\r\n\r\n{}", expected)), "{:?}: {}", backend, response);
+            // without a certificate, or with one of another CA
+            assert!(refused(&mut connect(addr, "localhost", &[&cert_path])));
+            assert!(refused(&mut connect_as(addr, "localhost", &[&cert_path], Some(&stranger))));
+
+            handle.shutdown();
+            running.join().unwrap();
+        }
+
+        let config = TlsConfig::new(&cert_path, &key_path).unwrap()
+                                                         .with_client_auth(ClientAuth::optional(&ca_path).unwrap())
+                                                         .build();
+        let server = Server::new("127.0.0.1:0").with_tls(config);
+        server.get("/whoami", whoami);
+        let (handle, addr, running) = start(server);
+        let mut stream = connect(addr, "localhost", &[&cert_path]);
+        assert!(exchange(&mut stream, "GET /whoami HTTP/1.1\r\n\r\n").ends_with("\r\n\r\nanonymous"));
+        let mut stream = connect_as(addr, "localhost", &[&cert_path], Some(&client));
+        assert!(exchange(&mut stream, "GET /whoami HTTP/1.1\r\n\r\n").ends_with(&expected));
+        assert!(refused(&mut connect_as(addr, "localhost", &[&cert_path], Some(&stranger))));
+        handle.shutdown();
+        running.join().unwrap();
+
+        assert!(matches!(ClientAuth::required(&key_path), Err(TlsError::NoCertificate(_))));
+    }
+}
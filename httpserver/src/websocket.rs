@@ -0,0 +1,878 @@
+//! WebSockets (RFC 6455). A route registered with
+//! [`crate::server::Server::websocket`] answers the opening handshake of a
+//! `GET` with `Upgrade: websocket`, the middleware runs around it as for any
+//! other request, e.g. to authenticate the client. Once the connection is
+//! switched, the handler gets it as a [`WebSocket`] until it returns, on a
+//! worker thread, or on a blocking thread of the tokio backend:
+//!
+//! ```text
+//! server.websocket("/echo", |_req: &HttpRequest, mut ws: WebSocket| {
+//!     while let Ok(message) = ws.read() {
+//!         if ws.send(message).is_err() {
+//!             break;
+//!         }
+//!     }
+//! });
+//! ```
+//!
+//! Pings are answered as they arrive, fragmented messages are put back
+//! together and the clients that break the protocol are closed with the code
+//! that says why. When the server shuts down, the WebSocket is closed with
+//! [`CloseCode::GOING_AWAY`] and its reads and sends fail.
+
+use std::error::Error;
+use std::fmt::Display;
+use std::io::{self, ErrorKind};
+use std::sync::Arc;
+use std::thread;
+
+use http::httprequest::{HttpRequest, Method};
+use http::httpresponse::{HttpResponse, StatusCode};
+use crate::connection::Transport;
+use crate::shutdown::ShutdownHandle;
+
+/// Runs on the connection once the handshake switched it to WebSocket
+pub type WebSocketHandler = Arc<dyn Fn(&HttpRequest, WebSocket<'_>) + Send + Sync>;
+
+/// Larger frames are refused with [`CloseCode::MESSAGE_TOO_BIG`], larger
+/// messages are sent in several frames
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 << 20;
+/// Larger messages, once put back together, are refused with [`CloseCode::MESSAGE_TOO_BIG`]
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+const GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const VERSION: &str = "13";
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+const CONTINUATION: u8 = 0x0;
+const TEXT: u8 = 0x1;
+const BINARY: u8 = 0x2;
+const CLOSE: u8 = 0x8;
+const PING: u8 = 0x9;
+const PONG: u8 = 0xa;
+
+const FIN: u8 = 0x80;
+const RSV: u8 = 0x70;
+const MASK: u8 = 0x80;
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// A message of either kind, fragmented or not on the wire
+#[derive(Debug, PartialEq, Clone)]
+pub enum Message {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<String> for Message {
+    fn from(text: String) -> Self {
+        Message::Text(text)
+    }
+}
+
+impl From<&str> for Message {
+    fn from(text: &str) -> Self {
+        Message::Text(text.to_string())
+    }
+}
+
+impl From<Vec<u8>> for Message {
+    fn from(bytes: Vec<u8>) -> Self {
+        Message::Binary(bytes)
+    }
+}
+
+impl From<&[u8]> for Message {
+    fn from(bytes: &[u8]) -> Self {
+        Message::Binary(bytes.to_vec())
+    }
+}
+
+/// The status code of a close frame, e.g. `CloseCode::NORMAL`
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct CloseCode(pub u16);
+
+impl CloseCode {
+    pub const NORMAL: CloseCode = CloseCode(1000);
+    pub const GOING_AWAY: CloseCode = CloseCode(1001);
+    pub const PROTOCOL_ERROR: CloseCode = CloseCode(1002);
+    pub const UNSUPPORTED_DATA: CloseCode = CloseCode(1003);
+    /// Never sent, it stands for a close frame without a code
+    pub const NO_STATUS: CloseCode = CloseCode(1005);
+    pub const INVALID_PAYLOAD: CloseCode = CloseCode(1007);
+    pub const POLICY_VIOLATION: CloseCode = CloseCode(1008);
+    pub const MESSAGE_TOO_BIG: CloseCode = CloseCode(1009);
+    pub const INTERNAL_ERROR: CloseCode = CloseCode(1011);
+
+    /// Whether the code can be sent in a close frame: the ones of the
+    /// protocol, except those that stand for a missing frame, and the ones of
+    /// the libraries and the applications
+    pub fn is_valid(&self) -> bool {
+        matches!(self.0, 1000..=1003 | 1007..=1014 | 3000..=4999)
+    }
+}
+
+impl Display for CloseCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[derive(Debug)]
+pub enum WebSocketError {
+    /// The client closed the WebSocket with the code and the reason,
+    /// [`CloseCode::NO_STATUS`] if it didn't send any
+    Closed(CloseCode, String),
+    /// The client broke the protocol, the WebSocket was closed with the code
+    Protocol(CloseCode),
+    /// The WebSocket was closed before
+    AlreadyClosed,
+    /// The server shuts down, the WebSocket was closed with [`CloseCode::GOING_AWAY`]
+    GoingAway,
+    Io(io::Error),
+}
+
+impl From<io::Error> for WebSocketError {
+    fn from(e: io::Error) -> Self {
+        WebSocketError::Io(e)
+    }
+}
+
+impl Display for WebSocketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WebSocketError::Closed(code, reason) => write!(f, "closed by the client with {}: {}", code, reason),
+            WebSocketError::Protocol(code) => write!(f, "the client broke the protocol, closed with {}", code),
+            WebSocketError::AlreadyClosed => write!(f, "the WebSocket is closed"),
+            WebSocketError::GoingAway => write!(f, "the server shuts down, closed with {}", CloseCode::GOING_AWAY),
+            WebSocketError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for WebSocketError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WebSocketError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A connection that was switched to WebSocket, it's closed with
+/// [`CloseCode::NORMAL`] when dropped unless it was closed before, with
+/// [`CloseCode::INTERNAL_ERROR`] if the handler panicked or with
+/// [`CloseCode::GOING_AWAY`] if the server shuts down.
+pub struct WebSocket<'a> {
+    stream: &'a mut dyn Transport,
+    received: Vec<u8>, // read from the stream but not parsed yet
+    max_frame_size: usize,
+    max_message_size: usize,
+    closed: bool, // a close frame was sent
+    shutdown: ShutdownHandle,
+}
+
+/// A frame of the client, unmasked
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+impl<'a> WebSocket<'a> {
+    /// `received` is what the client sent right after the handshake
+    pub(crate) fn new(stream: &'a mut dyn Transport, received: Vec<u8>) -> Self {
+        WebSocket {
+            stream,
+            received,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            closed: false,
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+
+    /// The reads and the sends fail once the server shuts down, the server
+    /// stops the reads that are waiting
+    pub(crate) fn with_shutdown(mut self, shutdown: ShutdownHandle) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Sets the largest frame the client can send, and the size of the
+    /// frames the messages are split into, 16 MiB by default
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Sets the largest message the client can send, 64 MiB by default
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Waits for the next message of the client. Pings are answered and
+    /// pongs are skipped meanwhile. Once the client closes the WebSocket, the
+    /// close frame is answered and [`WebSocketError::Closed`] returned.
+    pub fn read(&mut self) -> Result<Message, WebSocketError> {
+        if self.closed {
+            return Err(WebSocketError::AlreadyClosed);
+        }
+        // the opcode and the payload of the fragments received so far
+        let mut message: Option<(u8, Vec<u8>)> = None;
+        loop {
+            let frame = self.read_frame()?;
+            match frame.opcode {
+                PING => {
+                    self.write_frame(FIN | PONG, &frame.payload)?;
+                    continue;
+                },
+                PONG => continue,
+                CLOSE => return Err(self.receive_close(&frame.payload)),
+                CONTINUATION => {
+                    let Some((_, payload)) = &mut message else {
+                        return Err(self.fail(CloseCode::PROTOCOL_ERROR));
+                    };
+                    if payload.len() + frame.payload.len() > self.max_message_size {
+                        return Err(self.fail(CloseCode::MESSAGE_TOO_BIG));
+                    }
+                    payload.extend_from_slice(&frame.payload);
+                },
+                // a new message while a fragmented one isn't over
+                _ if message.is_some() => return Err(self.fail(CloseCode::PROTOCOL_ERROR)),
+                _ => message = Some((frame.opcode, frame.payload)),
+            }
+            if !frame.fin {
+                continue;
+            }
+            match message.take() {
+                Some((TEXT, payload)) => match String::from_utf8(payload) {
+                    Ok(text) => return Ok(Message::Text(text)),
+                    Err(_) => return Err(self.fail(CloseCode::INVALID_PAYLOAD)),
+                },
+                Some((_, payload)) => return Ok(Message::Binary(payload)),
+                None => (),
+            }
+        }
+    }
+
+    /// Sends the message, in several frames if it's larger than the maximum
+    /// frame size
+    pub fn send(&mut self, message: impl Into<Message>) -> Result<(), WebSocketError> {
+        if self.closed {
+            return Err(WebSocketError::AlreadyClosed);
+        }
+        if self.shutdown.is_shutdown() {
+            return Err(self.going_away());
+        }
+        let (mut opcode, payload) = match message.into() {
+            Message::Text(text) => (TEXT, text.into_bytes()),
+            Message::Binary(bytes) => (BINARY, bytes),
+        };
+        let mut fragments = payload.chunks(self.max_frame_size.max(1)).peekable();
+        if fragments.peek().is_none() {
+            return self.write_frame(FIN | opcode, &[]);
+        }
+        while let Some(fragment) = fragments.next() {
+            let fin = if fragments.peek().is_none() { FIN } else { 0 };
+            self.write_frame(fin | opcode, fragment)?;
+            opcode = CONTINUATION;
+        }
+        Ok(())
+    }
+
+    /// Sends a ping, the client answers with a pong that [`WebSocket::read`]
+    /// skips. The payload can't be longer than 125 bytes.
+    pub fn ping(&mut self, payload: &[u8]) -> Result<(), WebSocketError> {
+        if self.closed {
+            return Err(WebSocketError::AlreadyClosed);
+        }
+        if payload.len() > MAX_CONTROL_PAYLOAD {
+            return Err(io::Error::new(ErrorKind::InvalidInput, "ping payload longer than 125 bytes").into());
+        }
+        if self.shutdown.is_shutdown() {
+            return Err(self.going_away());
+        }
+        self.write_frame(FIN | PING, payload)
+    }
+
+    /// Closes the WebSocket and waits for the client to answer with its own
+    /// close frame, the messages it sent in the meantime are dropped
+    pub fn close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        if self.closed {
+            return Err(WebSocketError::AlreadyClosed);
+        }
+        self.send_close(code, reason)?;
+        loop {
+            match self.read_frame() {
+                Ok(frame) if frame.opcode == CLOSE => return Ok(()),
+                Ok(_) => (),
+                Err(WebSocketError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(WebSocketError::GoingAway) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Reads the next frame and checks it against the protocol and the limits
+    fn read_frame(&mut self) -> Result<Frame, WebSocketError> {
+        self.fill(2)?;
+        let (first, second) = (self.received[0], self.received[1]);
+        let (fin, opcode) = (first & FIN != 0, first & 0x0f);
+        // no extension was negotiated, so the reserved bits must be clear
+        if first & RSV != 0 || !matches!(opcode, CONTINUATION | TEXT | BINARY | CLOSE | PING | PONG) {
+            return Err(self.fail(CloseCode::PROTOCOL_ERROR));
+        }
+        // the frames of the clients are always masked
+        if second & MASK == 0 {
+            return Err(self.fail(CloseCode::PROTOCOL_ERROR));
+        }
+        let (len, header_len) = match second & 0x7f {
+            126 => {
+                self.fill(4)?;
+                (u16::from_be_bytes([self.received[2], self.received[3]]) as u64, 4)
+            },
+            127 => {
+                self.fill(10)?;
+                (u64::from_be_bytes(self.received[2..10].try_into().unwrap()), 10)
+            },
+            len => (len as u64, 2),
+        };
+        let is_control = opcode & 0x08 != 0;
+        if is_control && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(self.fail(CloseCode::PROTOCOL_ERROR));
+        }
+        if len > self.max_frame_size as u64 {
+            return Err(self.fail(CloseCode::MESSAGE_TOO_BIG));
+        }
+
+        let frame_len = header_len + 4 + len as usize;
+        self.fill(frame_len)?;
+        let mask: [u8; 4] = self.received[header_len..header_len + 4].try_into().unwrap();
+        let mut payload: Vec<u8> = self.received.drain(..frame_len).skip(header_len + 4).collect();
+        for (i, byte) in payload.iter_mut().enumerate() {
+            *byte ^= mask[i % 4];
+        }
+        Ok(Frame { fin, opcode, payload })
+    }
+
+    /// Reads until at least `len` bytes were received. The reads that the
+    /// server stopped because it shuts down close the WebSocket.
+    fn fill(&mut self, len: usize) -> Result<(), WebSocketError> {
+        let mut temp_buff = [0u8; 8192];
+        while self.received.len() < len {
+            match self.stream.read(&mut temp_buff) {
+                Ok(n) if n > 0 => self.received.extend_from_slice(&temp_buff[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                _ if self.shutdown.is_shutdown() => return Err(self.going_away()),
+                Ok(_) => return Err(io::Error::new(ErrorKind::UnexpectedEof, "connection closed before the close frame").into()),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers the close frame of the client with the same code
+    fn receive_close(&mut self, payload: &[u8]) -> WebSocketError {
+        let (code, reason) = match payload {
+            [] => (CloseCode::NO_STATUS, ""),
+            [_] => return self.fail(CloseCode::PROTOCOL_ERROR),
+            [high, low, reason @ ..] => {
+                let code = CloseCode(u16::from_be_bytes([*high, *low]));
+                if !code.is_valid() {
+                    return self.fail(CloseCode::PROTOCOL_ERROR);
+                }
+                match std::str::from_utf8(reason) {
+                    Ok(reason) => (code, reason),
+                    Err(_) => return self.fail(CloseCode::INVALID_PAYLOAD),
+                }
+            },
+        };
+        let answered = match code {
+            CloseCode::NO_STATUS => {
+                self.closed = true;
+                self.write_frame(FIN | CLOSE, &[])
+            },
+            code => self.send_close(code, ""),
+        };
+        match answered {
+            Ok(()) => WebSocketError::Closed(code, reason.to_string()),
+            Err(e) => e,
+        }
+    }
+
+    /// Closes the WebSocket because the server shuts down, the error is for the caller
+    fn going_away(&mut self) -> WebSocketError {
+        if !self.closed {
+            let _ = self.send_close(CloseCode::GOING_AWAY, "");
+        }
+        WebSocketError::GoingAway
+    }
+
+    /// Closes the WebSocket because of the client, the error is for the caller
+    fn fail(&mut self, code: CloseCode) -> WebSocketError {
+        if !self.closed {
+            let _ = self.send_close(code, "");
+        }
+        WebSocketError::Protocol(code)
+    }
+
+    fn send_close(&mut self, code: CloseCode, reason: &str) -> Result<(), WebSocketError> {
+        self.closed = true;
+        let mut payload = code.0.to_be_bytes().to_vec();
+        // the reason is cut to fit in a control frame, on a character boundary
+        let mut reason_len = reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+        while !reason.is_char_boundary(reason_len) {
+            reason_len -= 1;
+        }
+        payload.extend_from_slice(&reason.as_bytes()[..reason_len]);
+        self.write_frame(FIN | CLOSE, &payload)
+    }
+
+    /// Writes an unmasked frame, `first` holds the FIN bit and the opcode
+    fn write_frame(&mut self, first: u8, payload: &[u8]) -> Result<(), WebSocketError> {
+        let mut frame = Vec::with_capacity(payload.len() + 10);
+        frame.push(first);
+        match payload.len() {
+            len @ 0..=125 => frame.push(len as u8),
+            len @ 126..=0xffff => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(payload);
+        self.stream.write_all(&frame)?;
+        self.stream.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for WebSocket<'_> {
+    fn drop(&mut self) {
+        if !self.closed {
+            let code = if thread::panicking() {
+                CloseCode::INTERNAL_ERROR
+            } else if self.shutdown.is_shutdown() {
+                CloseCode::GOING_AWAY
+            } else {
+                CloseCode::NORMAL
+            };
+            let _ = self.send_close(code, "");
+        }
+    }
+}
+
+/// Answers the opening handshake of the client: `101 Switching Protocols` if
+/// it's valid, `426 Upgrade Required` if the client didn't ask for WebSocket
+/// or for another version of it, `400 Bad Request` otherwise
+pub(crate) fn handshake(request: &HttpRequest) -> HttpResponse {
+    let has_token = |name: &str, token: &str| {
+        request.header_value(name)
+               .is_some_and(|value| value.split(',').any(|value| value.trim().eq_ignore_ascii_case(token)))
+    };
+    if !has_token("Upgrade", "websocket") || !has_token("Connection", "upgrade") {
+        return HttpResponse::new("426", None, None).with_header("Upgrade", "websocket")
+                                                   .with_header("Connection", "Upgrade");
+    }
+    if request.header_value("Sec-WebSocket-Version").map(str::trim) != Some(VERSION) {
+        return HttpResponse::new("426", None, None).with_header("Upgrade", "websocket")
+                                                   .with_header("Sec-WebSocket-Version", VERSION);
+    }
+    let key = request.header_value("Sec-WebSocket-Key").map(str::trim).unwrap_or_default();
+    if request.method != Method::Get || !is_valid_key(key) {
+        return HttpResponse::new("400", None, None);
+    }
+    HttpResponse::with_status(StatusCode::SWITCHING_PROTOCOLS, None).with_header("Upgrade", "websocket")
+                                                                    .with_header("Connection", "Upgrade")
+                                                                    .with_header("Sec-WebSocket-Accept", &accept_key(key))
+}
+
+/// The `Sec-WebSocket-Accept` that proves the server read the key of the client
+pub fn accept_key(key: &str) -> String {
+    base64(&sha1(format!("{}{}", key, GUID).as_bytes()))
+}
+
+/// Whether the key is the base64 of 16 bytes, as the clients must send it
+fn is_valid_key(key: &str) -> bool {
+    key.len() == 24 && key.ends_with("==") && key.bytes().take(22).all(|byte| BASE64.contains(&byte))
+}
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let bits = chunk.iter()
+                        .enumerate()
+                        .fold(0u32, |bits, (i, byte)| bits | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(bits >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// SHA-1, which the handshake requires even though it's broken for anything else
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    for block in padded.chunks_exact(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().unwrap());
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+        for (value, added) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(added);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, value) in digest.chunks_exact_mut(4).zip(state) {
+        bytes.copy_from_slice(&value.to_be_bytes());
+    }
+    digest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::io::{Cursor, Read, Write};
+
+    /// Reads what the client sent and keeps what the server wrote
+    struct Duplex {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Duplex {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Duplex {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn duplex(frames: &[Vec<u8>]) -> Duplex {
+        Duplex { input: Cursor::new(frames.concat()), output: Vec::new() }
+    }
+
+    /// A frame as a client sends it, masked
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x37, 0xfa, 0x21, 0x3d];
+        let mut frame = vec![first];
+        match payload.len() {
+            len @ 0..=125 => frame.push(MASK | len as u8),
+            len @ 126..=0xffff => {
+                frame.push(MASK | 126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            },
+            len => {
+                frame.push(MASK | 127);
+                frame.extend_from_slice(&(len as u64).to_be_bytes());
+            },
+        }
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, byte)| byte ^ mask[i % 4]));
+        frame
+    }
+
+    /// Splits what the server wrote into its frames, the first byte and the payload
+    fn server_frames(mut output: &[u8]) -> Vec<(u8, Vec<u8>)> {
+        let mut frames = Vec::new();
+        while !output.is_empty() {
+            assert_eq!(output[1] & MASK, 0, "the frames of the server aren't masked");
+            let (len, header_len) = match output[1] {
+                126 => (u16::from_be_bytes([output[2], output[3]]) as usize, 4),
+                127 => (u64::from_be_bytes(output[2..10].try_into().unwrap()) as usize, 10),
+                len => (len as usize, 2),
+            };
+            frames.push((output[0], output[header_len..header_len + len].to_vec()));
+            output = &output[header_len + len..];
+        }
+        frames
+    }
+
+    fn close_payload(code: CloseCode) -> Vec<u8> {
+        code.0.to_be_bytes().to_vec()
+    }
+
+    fn request(method: Method, headers: &[(&str, &str)]) -> HttpRequest {
+        let header: HashMap<String, String> = headers.iter()
+                                                     .map(|(name, value)| (name.to_string(), value.to_string()))
+                                                     .collect();
+        HttpRequest { method, header, ..HttpRequest::default() }
+    }
+
+    #[test]
+    fn test_accept_key() {
+        // the example of RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        let digest: String = sha1(b"abc").iter().map(|byte| format!("{:02x}", byte)).collect();
+        assert_eq!(digest, "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn test_handshake() {
+        let headers = [
+            ("Upgrade", "websocket"),
+            ("Connection", "keep-alive, Upgrade"),
+            ("Sec-WebSocket-Version", "13"),
+            ("Sec-WebSocket-Key", "dGhlIHNhbXBsZSBub25jZQ=="),
+        ];
+        let response = handshake(&request(Method::Get, &headers));
+        assert_eq!(response.status_code, "101");
+        let response_headers = response.headers.unwrap();
+        assert_eq!(response_headers["Upgrade"], "websocket");
+        assert_eq!(response_headers["Connection"], "Upgrade");
+        assert_eq!(response_headers["Sec-WebSocket-Accept"], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+
+        // a plain GET is told to upgrade
+        let response = handshake(&request(Method::Get, &[]));
+        assert_eq!(response.status_code, "426");
+        assert_eq!(response.headers.unwrap()["Upgrade"], "websocket");
+        let response = handshake(&request(Method::Get, &[headers[0], headers[1], ("Sec-WebSocket-Version", "8"), headers[3]]));
+        assert_eq!(response.status_code, "426");
+        assert_eq!(response.headers.unwrap()["Sec-WebSocket-Version"], "13");
+
+        let invalid_key = handshake(&request(Method::Get, &[headers[0], headers[1], headers[2], ("Sec-WebSocket-Key", "short")]));
+        assert_eq!(invalid_key.status_code, "400");
+        let missing_key = handshake(&request(Method::Get, &headers[..3]));
+        assert_eq!(missing_key.status_code, "400");
+        assert_eq!(handshake(&request(Method::Head, &headers)).status_code, "400");
+    }
+
+    #[test]
+    fn test_messages() {
+        let mut stream = duplex(&[
+            client_frame(FIN | TEXT, "héllo".as_bytes()),
+            // a fragmented message with a ping in the middle
+            client_frame(BINARY, &[1, 2]),
+            client_frame(FIN | PING, b"are you there"),
+            client_frame(CONTINUATION, &[3]),
+            client_frame(FIN | CONTINUATION, &[4, 5]),
+            client_frame(FIN | PONG, b""),
+            client_frame(FIN | TEXT, &[b'a'; 300]),
+            client_frame(FIN | CLOSE, &[&close_payload(CloseCode::GOING_AWAY)[..], b"bye"].concat()),
+        ]);
+        let mut websocket = WebSocket::new(&mut stream, Vec::new());
+        assert_eq!(websocket.read().unwrap(), Message::Text(String::from("héllo")));
+        assert_eq!(websocket.read().unwrap(), Message::Binary(vec![1, 2, 3, 4, 5]));
+        assert_eq!(websocket.read().unwrap(), Message::Text("a".repeat(300)));
+        assert!(matches!(websocket.read(), Err(WebSocketError::Closed(CloseCode::GOING_AWAY, reason)) if reason == "bye"));
+        assert!(matches!(websocket.read(), Err(WebSocketError::AlreadyClosed)));
+        assert!(matches!(websocket.send("late"), Err(WebSocketError::AlreadyClosed)));
+        drop(websocket);
+
+        assert_eq!(server_frames(&stream.output), [
+            (FIN | PONG, b"are you there".to_vec()),
+            (FIN | CLOSE, close_payload(CloseCode::GOING_AWAY)),
+        ]);
+
+        // what the client sent with the handshake comes first
+        let mut stream = duplex(&[client_frame(FIN | CLOSE, b"")]);
+        let mut websocket = WebSocket::new(&mut stream, client_frame(FIN | TEXT, b"early"));
+        assert_eq!(websocket.read().unwrap(), Message::Text(String::from("early")));
+        assert!(matches!(websocket.read(), Err(WebSocketError::Closed(CloseCode::NO_STATUS, _))));
+        drop(websocket);
+        assert_eq!(server_frames(&stream.output), [(FIN | CLOSE, Vec::new())]);
+    }
+
+    #[test]
+    fn test_send() {
+        let mut stream = duplex(&[client_frame(FIN | CLOSE, &close_payload(CloseCode::NORMAL))]);
+        let mut websocket = WebSocket::new(&mut stream, Vec::new()).with_max_frame_size(4);
+        websocket.send("hi").unwrap();
+        websocket.send(&[1u8, 2, 3, 4, 5, 6, 7, 8, 9][..]).unwrap();
+        websocket.send("").unwrap();
+        websocket.ping(b"ping").unwrap();
+        assert!(matches!(websocket.ping(&[0; 126]), Err(WebSocketError::Io(_))));
+        websocket.close(CloseCode::NORMAL, "done").unwrap();
+        drop(websocket);
+
+        assert_eq!(server_frames(&stream.output), [
+            (FIN | TEXT, b"hi".to_vec()),
+            (BINARY, vec![1, 2, 3, 4]),
+            (CONTINUATION, vec![5, 6, 7, 8]),
+            (FIN | CONTINUATION, vec![9]),
+            (FIN | TEXT, Vec::new()),
+            (FIN | PING, b"ping".to_vec()),
+            (FIN | CLOSE, [&close_payload(CloseCode::NORMAL)[..], b"done"].concat()),
+        ]);
+
+        // a large message, with a 64-bit length
+        let large = vec![7u8; 70_000];
+        let mut stream = duplex(&[]);
+        WebSocket::new(&mut stream, Vec::new()).send(large.clone()).unwrap();
+        assert_eq!(server_frames(&stream.output), [
+            (FIN | BINARY, large),
+            (FIN | CLOSE, close_payload(CloseCode::NORMAL)),
+        ]);
+    }
+
+    #[test]
+    fn test_protocol_errors() {
+        let unmasked = {
+            let mut frame = client_frame(FIN | TEXT, b"");
+            frame[1] &= !MASK;
+            frame.truncate(2);
+            frame
+        };
+        let cases = [
+            (vec![unmasked], CloseCode::PROTOCOL_ERROR),
+            (vec![client_frame(FIN | RSV | TEXT, b"")], CloseCode::PROTOCOL_ERROR),
+            (vec![client_frame(FIN | 0x3, b"")], CloseCode::PROTOCOL_ERROR),
+            (vec![client_frame(FIN | CONTINUATION, b"")], CloseCode::PROTOCOL_ERROR),
+            (vec![client_frame(TEXT, b"a"), client_frame(FIN | TEXT, b"b")], CloseCode::PROTOCOL_ERROR),
+            (vec![client_frame(PING, b"")], CloseCode::PROTOCOL_ERROR),
+            (vec![client_frame(FIN | PING, &[0; 126])], CloseCode::PROTOCOL_ERROR),
+            (vec![client_frame(FIN | CLOSE, &[3])], CloseCode::PROTOCOL_ERROR),
+            (vec![client_frame(FIN | CLOSE, &close_payload(CloseCode::NO_STATUS))], CloseCode::PROTOCOL_ERROR),
+            (vec![client_frame(FIN | TEXT, &[0xff, 0xfe])], CloseCode::INVALID_PAYLOAD),
+            (vec![client_frame(FIN | CLOSE, &[0x03, 0xe8, 0xff])], CloseCode::INVALID_PAYLOAD),
+            (vec![client_frame(FIN | BINARY, &[0; 17])], CloseCode::MESSAGE_TOO_BIG),
+            (vec![client_frame(BINARY, &[0; 16]), client_frame(FIN | CONTINUATION, &[0; 16])], CloseCode::MESSAGE_TOO_BIG),
+        ];
+        for (frames, code) in cases {
+            let mut stream = duplex(&frames);
+            let mut websocket = WebSocket::new(&mut stream, Vec::new()).with_max_frame_size(16)
+                                                                       .with_max_message_size(24);
+            match websocket.read() {
+                Err(WebSocketError::Protocol(closed_with)) => assert_eq!(closed_with, code),
+                other => panic!("{:?} for {:?}", other, frames),
+            }
+            drop(websocket);
+            assert_eq!(server_frames(&stream.output), [(FIN | CLOSE, close_payload(code))]);
+        }
+
+        // a client that goes away without closing
+        let mut stream = duplex(&[client_frame(FIN | TEXT, b"cut short")[..5].to_vec()]);
+        let mut websocket = WebSocket::new(&mut stream, Vec::new());
+        assert!(matches!(websocket.read(), Err(WebSocketError::Io(e)) if e.kind() == ErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn test_upgrade() {
+        use std::net::TcpStream;
+        use std::time::Duration;
+        use crate::middleware::Next;
+        use crate::server::Server;
+        use crate::testing::{self, BACKENDS};
+
+        for &backend in BACKENDS {
+            // a single worker, which the WebSockets must not hold
+            let server = Server::new("127.0.0.1:0").with_backend(backend)
+                                                   .with_workers(1)
+                                                   .with_queue_size(0)
+                                                   .with_max_upgrades(3);
+            server.get("/plain", |_: &HttpRequest| "plain");
+            server.websocket("/rooms/{room}", |req: &HttpRequest, mut ws: WebSocket| {
+                ws.send(format!("joined {}", req.path_params["room"])).unwrap();
+                while let Ok(message) = ws.read() {
+                    ws.send(message).unwrap();
+                }
+            });
+            server.layer(|req: &HttpRequest, next: Next<'_>| match req.header_value("Authorization") {
+                Some(_) => next.run(req),
+                None => HttpResponse::new("401", None, None),
+            });
+            let (handle, addr, running) = testing::start(server);
+            let handshake = "GET /rooms/lobby HTTP/1.1\r\nAuthorization: Bearer token\r\nUpgrade: websocket\r\n\
+                             Connection: Upgrade\r\nSec-WebSocket-Version: 13\r\n\
+                             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n";
+            let connect = |bytes: &[u8]| testing::open(addr, bytes);
+
+            // the middleware runs around the handshake
+            let (_, head, _) = connect(handshake.replace("Authorization: Bearer token\r\n", "").as_bytes());
+            assert!(head.starts_with("HTTP/1.1 401 Unauthorized\r\n"), "{:?}: {}", backend, head);
+            let (_, head, _) = connect(b"GET /rooms/lobby HTTP/1.1\r\nAuthorization: Bearer token\r\n\r\n");
+            assert!(head.starts_with("HTTP/1.1 426 Upgrade Required\r\n"));
+
+            // a frame sent along with the handshake isn't lost
+            let (mut stream, head, mut received) = connect(&[handshake.as_bytes(), &client_frame(FIN | TEXT, b"first")].concat());
+            assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{}", head);
+            assert!(head.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+            assert!(!head.contains("Content-Length") && !head.contains("close"));
+
+            stream.write_all(&client_frame(FIN | BINARY, &[1, 2, 3])).unwrap();
+            stream.write_all(&client_frame(FIN | CLOSE, &close_payload(CloseCode::NORMAL))).unwrap();
+            // the server closes the connection once the handler returns
+            stream.read_to_end(&mut received).unwrap();
+            assert_eq!(server_frames(&received), [
+                (FIN | TEXT, b"joined lobby".to_vec()),
+                (FIN | TEXT, b"first".to_vec()),
+                (FIN | BINARY, vec![1, 2, 3]),
+                (FIN | CLOSE, close_payload(CloseCode::NORMAL)),
+            ]);
+
+            // the handler of the first one may not have returned yet
+            let (_, head, _) = connect(handshake.as_bytes());
+            assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{:?}: {}", backend, head);
+            thread::sleep(Duration::from_millis(50));
+
+            // open WebSockets leave the workers to the requests
+            let open: Vec<(TcpStream, Vec<u8>)> = (0..3).map(|_| {
+                let (stream, head, received) = connect(handshake.as_bytes());
+                assert!(head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"), "{:?}: {}", backend, head);
+                (stream, received)
+            }).collect();
+            let (_, head, _) = connect(b"GET /plain HTTP/1.1\r\nAuthorization: Bearer token\r\n\r\n");
+            assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{:?}: {}", backend, head);
+            // up to the maximum
+            let (_, head, _) = connect(handshake.as_bytes());
+            assert!(head.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{:?}: {}", backend, head);
+
+            // the server closes the open ones as it shuts down
+            handle.shutdown();
+            for (mut stream, mut received) in open {
+                stream.read_to_end(&mut received).unwrap();
+                assert_eq!(server_frames(&received), [
+                    (FIN | TEXT, b"joined lobby".to_vec()),
+                    (FIN | CLOSE, close_payload(CloseCode::GOING_AWAY)),
+                ], "{:?}", backend);
+            }
+            running.join().unwrap();
+        }
+    }
+}